@@ -0,0 +1,123 @@
+//! Timed lookups over 12 normal and 2 import tables on disk, every table
+//! probed by the same workers.
+//!
+//! `cargo bench --bench probe -- [rows per table] [--cold]`, with `--cold`
+//! the page cache is dropped before each run (needs root), otherwise the
+//! tables stay cached after the first run.
+
+use std::{
+    env::{args, temp_dir},
+    fs::{create_dir_all, metadata, write, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+    thread::available_parallelism,
+    time::{Duration, Instant},
+};
+
+use hash_hack_dbms::{
+    catalog::{Catalog, QueryPlan},
+    data::TblMeta,
+    hash::hash_bili2,
+};
+use m6coll::ToLeBytes;
+
+const NORMAL_TBLS: u32 = 12;
+const IMPORT_TBLS: u32 = 2;
+const KEYS: u32 = 1000;
+const RUNS: usize = 5;
+
+/// (hash, uid) rows of uids `lo..lo + n` sorted by hash, as the normal tables
+fn write_tbl(path: &Path, lo: u32, n: u32) -> io::Result<()> {
+    let meta = TblMeta {
+        len: n as u64,
+        keysize: 4,
+        valsize: 4,
+        valcols: 0,
+    };
+
+    if metadata(path).map(|x| x.len()).ok() == Some(meta.tbl_bytes()) {
+        return Ok(());
+    }
+
+    let mut rows: Vec<(u32, u32)> = (lo..lo + n).map(|uid| (hash_bili2(uid), uid)).collect();
+    rows.sort_unstable();
+
+    create_dir_all(path.parent().unwrap())?;
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(&meta.to_le_bytes())?;
+    for (hash, uid) in rows {
+        w.write_all(&hash.to_le_bytes())?;
+        w.write_all(&uid.to_le_bytes())?;
+    }
+
+    // synced, so dropping the page cache drops the tables too
+    w.into_inner()?.sync_all()
+}
+
+fn main() -> io::Result<()> {
+    // `cargo bench` passes `--bench`
+    let args: Vec<String> = args().skip(1).filter(|x| x != "--bench").collect();
+    let cold = args.iter().any(|x| x == "--cold");
+    let rows: u32 = args
+        .iter()
+        .find_map(|x| x.parse().ok())
+        .unwrap_or(4_000_000);
+
+    let root = temp_dir().join(format!("hh_bench_probe_{}", rows));
+
+    for i in 0..NORMAL_TBLS {
+        write_tbl(&root.join(format!("data{}/db.bin", i)), i * rows + 1, rows)?;
+    }
+    for i in 0..IMPORT_TBLS {
+        let path = root.join(format!("data_import/db_import_{}.bin", i));
+        write_tbl(&path, (NORMAL_TBLS + i) * rows + 1, rows)?;
+    }
+
+    let catalog = Catalog::load_in(&root);
+    let total = (NORMAL_TBLS + IMPORT_TBLS) * rows;
+    let keys: Vec<u32> = (0..KEYS).map(|i| hash_bili2(1 + i * (total / KEYS))).collect();
+
+    println!(
+        "{} tables x {} rows ({} MiB), {} keys, {} cpus, {} caches",
+        NORMAL_TBLS + IMPORT_TBLS,
+        rows,
+        ((NORMAL_TBLS + IMPORT_TBLS) as u64 * rows as u64 * 8) >> 20,
+        KEYS,
+        available_parallelism().map(|n| n.get()).unwrap_or(1),
+        if cold { "cold" } else { "warm" },
+    );
+
+    for threads in [1, 2, 4, 8] {
+        let plan = QueryPlan {
+            threads,
+            ..QueryPlan::default()
+        };
+        let mut times: Vec<Duration> = vec![];
+
+        // a warm-up run, then `RUNS` timed ones
+        for run in 0..=RUNS {
+            if cold {
+                write("/proc/sys/vm/drop_caches", "3")?;
+            }
+
+            let start = Instant::now();
+            let cands = catalog.query(&plan, &keys)?;
+            let elapsed = start.elapsed();
+
+            assert!(cands.iter().all(|x| !x.is_empty()));
+            if run > 0 {
+                times.push(elapsed);
+            }
+        }
+
+        times.sort_unstable();
+        println!(
+            "threads {}: median {:.1}ms, min {:.1}ms",
+            threads,
+            times[RUNS / 2].as_secs_f64() * 1e3,
+            times[0].as_secs_f64() * 1e3,
+        );
+    }
+
+    Ok(())
+}
@@ -1,8 +1,15 @@
+use std::{io, path::PathBuf, process::exit};
+
 use clap::{IntoApp, Parser, Subcommand};
 use clap_complete::Shell;
 use hash_hack_dbms::{
-    gendata::{gen_collision_data_bili2, gen_data_bili2, gen_collision_resolve_data_bili2},
-    shell::gen_completions,
+    gendata::{
+        gen_collision_data_bili2, gen_collision_group_data_bili2,
+        clean_temporaries, gen_collision_resolve_data_bili2, gen_data_bili2,
+    },
+    compact::compact_bili2,
+    import::{import_bili2, Column, ImportSpec, Radix},
+    shell::{gen_completions, path2str, TblFamily},
 };
 
 
@@ -24,13 +31,50 @@ enum SubCommand {
     Bili2 {
         #[clap(validator=format_u32_str)]
         id: u32,
+
+        /// continue an interrupted generation from its checkpoint
+        #[clap(long)]
+        resume: bool,
     },
 
     /// generate dup db
     Dup {},
 
+    /// generate collision group db (every member of each group)
+    Group {},
+
     #[clap(subcommand)]
-    Resolve(Resolve)
+    Resolve(Resolve),
+
+    /// import hash/uid pairs of a csv (with header) as a new import table
+    Import {
+        csv: PathBuf,
+
+        /// hash column, header name or 0-based index
+        #[clap(long, default_value = "0")]
+        hash_col: Column,
+
+        /// uid column, header name or 0-based index
+        #[clap(long, default_value = "1")]
+        uid_col: Column,
+
+        /// radix of the hash column (`0x` is always hex), uids are decimal
+        #[clap(long, arg_enum, default_value_t = Radix::Hex)]
+        hash_radix: Radix,
+
+        #[clap(short, long, default_value_t = ',')]
+        delimiter: char,
+    },
+
+    /// merge tables of a family into the first of them, deleting the others
+    Compact {
+        #[clap(arg_enum)]
+        family: TblFamily,
+
+        /// tables to merge (default all), normal ones must be consecutive
+        #[clap(long, use_value_delimiter = true)]
+        ids: Vec<u32>,
+    },
 }
 
 #[derive(Subcommand)]
@@ -41,7 +85,14 @@ enum Resolve {
 
 fn format_u32_str(s: &str) -> Result<u32, String> {
     let s = s.replace("_", "");
-    u32::from_str_radix(&s, 10).or(Err(s))
+    s.parse::<u32>().or(Err(s))
+}
+
+fn exit_on_err(what: &str, res: Result<(), io::Error>) {
+    if let Err(err) = res {
+        eprintln!("{}: {}", what, err);
+        exit(1);
+    }
 }
 
 fn main() {
@@ -54,13 +105,66 @@ fn main() {
     }
 
     if let Some(command) = cli.command {
+        match clean_temporaries() {
+            Ok(removed) => {
+                for path in removed.iter() {
+                    eprintln!("removed leftover {}", path2str(path));
+                }
+            }
+            Err(err) => {
+                eprintln!("clean temporaries: {}", err);
+                exit(1);
+            }
+        }
+
         match command {
-            SubCommand::Bili2 { id } => gen_data_bili2(id),
-            SubCommand::Dup {} => gen_collision_data_bili2(),
+            SubCommand::Bili2 { id, resume } => {
+                exit_on_err("bili2", gen_data_bili2(id, resume))
+            }
+            SubCommand::Dup {} => exit_on_err("dup", gen_collision_data_bili2()),
+            SubCommand::Group {} => {
+                exit_on_err("group", gen_collision_group_data_bili2())
+            }
             SubCommand::Resolve(resolve) => match resolve {
                 Resolve::Rehash => {
-                    gen_collision_resolve_data_bili2()
+                    exit_on_err("resolve", gen_collision_resolve_data_bili2())
                 },
+            },
+            SubCommand::Import {
+                csv,
+                hash_col,
+                uid_col,
+                hash_radix,
+                delimiter,
+            } => {
+                let spec = ImportSpec {
+                    hash_col,
+                    uid_col,
+                    hash_radix,
+                    delimiter,
+                };
+
+                match import_bili2(&csv, &spec) {
+                    Ok(rep) => {
+                        for path in rep.tables.iter() {
+                            println!("wrote into {}", path2str(path));
+                        }
+                        println!("{}", rep);
+                    }
+                    Err(err) => {
+                        eprintln!("{}: {}", csv.display(), err);
+                        exit(1);
+                    }
+                }
+            }
+            SubCommand::Compact { family, ids } => {
+                match compact_bili2(family, &ids) {
+                    Ok(rep) => println!("{}", rep),
+                    Err(err) => {
+                        eprintln!("compact: {}", err);
+                        exit(1);
+                    }
+                }
             }
         }
     }
@@ -1,8 +1,38 @@
-use clap::{IntoApp, Parser, Subcommand};
+use std::{
+    fs::read_to_string,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+use clap::{ArgEnum, ArgGroup, Args, IntoApp, Parser, Subcommand};
 use clap_complete::Shell;
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter,
+    hint::Hinter, validate::Validator, Editor, Helper,
+};
+use shellexpand::tilde;
 use hash_hack_dbms::{
-    query::{print_dbmeta, query_bili2, query_collision_rehash_resolve},
-    shell::gen_completions,
+    eval::{eval_bili2, load_truth},
+    diff::diff_dbs,
+    export::{export, ExportFmt},
+    inspect::{dump, inspect, DumpRange, TblHeader},
+    hash::{check_pair, hash_bili2, hash_uids, parse_hash, HashSpec, RehashFmt},
+    search::{load_wordlist, search, SearchSpace},
+    stats::stats_bili2,
+    catalog::{Catalog, QueryPlan},
+    query::{
+        print_dbmeta, query_colliding_uids, KeyRange,
+        query_stream_bili2, write_dbmeta, Candidate, ResolveChain,
+    },
+    output::{
+        CandidateRecord, CheckRecord, CollideRecord, Format, HashRecord,
+        LocateRecord, StatsRecord, ProbeRecord,
+        OverrideRecord, ScoredRecord, SpecRecord,
+    },
+    overrides::{import_csv, Override, Overrides, Verdict},
+    rank::{load_uid_set, rank as rank_candidates, RankConfig, UidPrior, Weight},
+    shell::{gen_completions, TblFamily},
 };
 
 
@@ -14,6 +44,10 @@ struct Cli {
     #[clap(long = "generate", arg_enum)]
     generator: Option<Shell>,
 
+    /// output format (schemas in `hash_hack_dbms::output`)
+    #[clap(long, arg_enum, global = true, default_value_t = Format::Plain)]
+    format: Format,
+
     #[clap(subcommand)]
     command: Option<SubCommand>,
 }
@@ -21,18 +55,369 @@ struct Cli {
 #[derive(Subcommand)]
 enum SubCommand {
     /// bilibili query
-    Bili2 { id: String },
+    #[clap(
+        visible_alias = "q",
+        group(
+            ArgGroup::new("source")
+                .required(true)
+                .multiple(true)
+                .args(&["ids", "file", "stdin", "range"])
+        )
+    )]
+    Bili2 {
+        #[clap(validator=format_hex_str)]
+        ids: Vec<String>,
+
+        /// read hashes from file, one per line
+        #[clap(short, long)]
+        file: Option<PathBuf>,
+
+        /// read hashes from stdin, write results per hash as it goes
+        #[clap(
+            long,
+            conflicts_with_all = &[
+                "ids", "file", "rank", "priors", "allow", "deny", "active", "weights",
+                "range"
+            ]
+        )]
+        stdin: bool,
+
+        /// table families to consult (default all but dup), rejected
+        /// overrides are dropped whatever the families, `override` puts
+        /// confirmed ones first
+        #[clap(long, arg_enum, use_value_delimiter = true)]
+        tables: Vec<TblFamily>,
+
+        /// report the tables probed, seeks, bytes read and time to stderr
+        /// (not of `--prefix` queries, they scan the tables in the range)
+        #[clap(long, conflicts_with = "range")]
+        explain: bool,
+
+        /// every hash matching a hex prefix (`c4ff7a`), a mask with `?` for
+        /// unknown digits (`c4ff??c1`) or a range `[lo,hi]`, with `--rank`
+        /// the candidates of each hash are ranked
+        #[clap(long = "prefix", visible_alias = "range", conflicts_with = "ids")]
+        range: Option<KeyRange>,
+
+        /// stop a `--prefix` query after this many candidates
+        #[clap(long, default_value_t = 1000)]
+        limit: usize,
+
+        /// workers probing tables concurrently (default: cpus, at most 8)
+        #[clap(short = 'j', long)]
+        threads: Option<usize>,
+
+        #[clap(flatten)]
+        rank: RankArgs,
+    },
 
     /// check database meta
     Config {},
+
+    /// run commands interactively, the database is opened once
+    Shell {},
+
+    /// parsed header of table files (`data0/db.bin`), checked against the
+    /// file size and the stored digest, exit 1 on a mismatch
+    Inspect {
+        #[clap(required = true)]
+        tables: Vec<PathBuf>,
+    },
+
+    /// stream tables (or a hash range of them) to csv, json lines or sqlite
+    Export {
+        #[clap(long, arg_enum, default_value_t = ExportFmt::Csv)]
+        to: ExportFmt,
+
+        /// file written (required for sqlite), stdout otherwise
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// table families to export (default all)
+        #[clap(long, arg_enum, use_value_delimiter = true)]
+        tables: Vec<TblFamily>,
+
+        /// hashes matching a prefix, mask or range, as for bili2
+        #[clap(long = "prefix", visible_alias = "range")]
+        range: Option<KeyRange>,
+    },
+
+    /// keys differing between two databases (dirs), exits 1 if any
+    Diff { a: PathBuf, b: PathBuf },
+
+    /// rows of a table file, by row number or key (bounds inclusive)
+    Dump {
+        table: PathBuf,
+
+        #[clap(long, conflicts_with_all = &["from-key", "to-key"])]
+        from_row: Option<u64>,
+
+        #[clap(long, conflicts_with_all = &["from-key", "to-key"])]
+        to_row: Option<u64>,
+
+        /// hash (hex)
+        #[clap(long, validator=format_hex_str)]
+        from_key: Option<String>,
+
+        /// hash (hex)
+        #[clap(long, validator=format_hex_str)]
+        to_key: Option<String>,
+    },
+
+    /// uids colliding with the uid
+    Group { uid: u32 },
+
+    /// table and row of each uid, and the collision run around it (normal
+    /// tables only, imported pairs are not laid out by uid)
+    Locate {
+        #[clap(required = true)]
+        uids: Vec<u32>,
+    },
+
+    /// midHash of each uid
+    Hash {
+        #[clap(required = true)]
+        uids: Vec<u32>,
+
+        #[clap(flatten)]
+        spec: SpecArgs,
+    },
+
+    /// whether the hash comes from the uid, directly or through a resolver
+    Check {
+        #[clap(validator=format_hex_str)]
+        hash: String,
+        uid: u32,
+
+        #[clap(flatten)]
+        spec: SpecArgs,
+    },
+
+    /// curated hash -> uid pairs merged into bili2 results
+    Override {
+        #[clap(subcommand)]
+        action: OverrideAction,
+    },
+
+    /// collision statistics of the database
+    Stats {
+        /// number of most collided hashes to list
+        #[clap(long, default_value_t = 10)]
+        top: usize,
+
+        /// same as `--format json`, kept for older scripts
+        #[clap(long, hide = true)]
+        json: bool,
+    },
+
+    /// score tables and resolvers against a `hashid,uid` ground-truth csv
+    Eval { csv: PathBuf },
+
+    /// search hash parameters reproducing the known pairs of a csv
+    Search {
+        csv: PathBuf,
+
+        /// salts, one per line
+        #[clap(short, long)]
+        wordlist: Option<PathBuf>,
+
+        /// string templates with `{uid}` and `{salt}`
+        #[clap(short, long = "template")]
+        templates: Vec<String>,
+
+        /// extra crc polynomials (hex)
+        #[clap(short, long = "poly", validator=format_hex_str)]
+        polys: Vec<String>,
+
+        /// extra crc init values (hex), besides ffffffff and 0
+        #[clap(long = "init", validator=format_hex_str)]
+        inits: Vec<String>,
+
+        /// extra crc xorout values (hex), besides ffffffff and 0
+        #[clap(long = "xorout", validator=format_hex_str)]
+        xorouts: Vec<String>,
+
+        /// max re-hash depth
+        #[clap(short, long, default_value_t = 2)]
+        depth: u32,
+
+        #[clap(long, default_value_t = 10)]
+        top: usize,
+    },
+}
+
+/// Ranking of candidates, any of them turns it on
+#[derive(Args)]
+struct RankArgs {
+    /// order candidates by score
+    #[clap(long)]
+    rank: bool,
+
+    /// uid range prior `lo..=hi:weight`
+    #[clap(long = "prior")]
+    priors: Vec<UidPrior>,
+
+    /// uids to favour, one per line
+    #[clap(long)]
+    allow: Option<PathBuf>,
+
+    /// uids to disfavour, one per line
+    #[clap(long)]
+    deny: Option<PathBuf>,
+
+    /// known-active uids, one per line
+    #[clap(long)]
+    active: Option<PathBuf>,
+
+    /// signal weight `name:weight`, name is allow (default 10), deny (-10),
+    /// active (2), a table (override 10, resolve -1, others 0) or a resolver
+    /// (rehash, default its table's)
+    #[clap(long = "weight")]
+    weights: Vec<Weight>,
+}
+
+impl RankArgs {
+    fn into_config(self) -> Result<Option<RankConfig>, io::Error> {
+        if !self.rank
+            && self.priors.is_empty()
+            && self.allow.is_none()
+            && self.deny.is_none()
+            && self.active.is_none()
+            && self.weights.is_empty()
+        {
+            return Ok(None);
+        }
+
+        let load = |path: Option<PathBuf>| match path {
+            Some(path) => load_uid_set(&path),
+            None => Ok(Default::default()),
+        };
+
+        let mut cfg = RankConfig {
+            priors: self.priors,
+            allow: load(self.allow)?,
+            deny: load(self.deny)?,
+            active: load(self.active)?,
+            ..Default::default()
+        };
+        for weight in self.weights {
+            cfg.set_weight(weight);
+        }
+
+        Ok(Some(cfg))
+    }
+}
+
+/// Hash function, bilibili midHash by default
+#[derive(Args)]
+struct SpecArgs {
+    /// string hashed, with `{uid}` and `{salt}`
+    #[clap(long, default_value = "{uid}")]
+    template: String,
+
+    #[clap(long, default_value = "")]
+    salt: String,
+
+    /// crc polynomial (hex)
+    #[clap(long, validator=format_hex_str)]
+    poly: Option<String>,
+
+    /// rounds of crc
+    #[clap(long, default_value_t = 1)]
+    depth: u32,
+
+    /// how a hash is hashed again when depth > 1
+    #[clap(long, arg_enum, default_value_t = RehashFmt::Dec)]
+    rehash_fmt: RehashFmt,
+}
+
+impl SpecArgs {
+    fn into_spec(self) -> HashSpec {
+        let mut spec = HashSpec::bili2();
+
+        if let Some(poly) = self.poly {
+            spec.params.poly = format_hex_str(&poly).unwrap();
+        }
+        spec.template = self.template;
+        spec.salt = self.salt;
+        spec.depth = self.depth.max(1);
+        spec.fmt = self.rehash_fmt;
+
+        spec
+    }
+}
+
+#[derive(Subcommand)]
+enum OverrideAction {
+    /// confirm (or reject) that the hash comes from the uid
+    Add {
+        #[clap(validator=format_hex_str)]
+        hash: String,
+        uid: u32,
+
+        #[clap(long, arg_enum, default_value_t = Verdict::Confirmed)]
+        verdict: Verdict,
+
+        #[clap(short, long, default_value = "")]
+        note: String,
+    },
+
+    /// drop the overrides of the hash (only of the uid if given)
+    Remove {
+        #[clap(validator=format_hex_str)]
+        hash: String,
+        uid: Option<u32>,
+    },
+
+    /// overrides (only of the hash if given)
+    List {
+        #[clap(validator=format_hex_str)]
+        hash: Option<String>,
+    },
+
+    /// confirm the labeled pairs of a `hashid,uid` csv (`bullet.csv`)
+    Import { csv: PathBuf },
 }
 
 fn format_hex_str(s: &str) -> Result<u32, String> {
-    u32::from_str_radix(&s, 16).or(Err(s.to_string()))
+    parse_hash(s).ok_or_else(|| s.to_string())
+}
+
+/// `err` prefixed with the file it is about
+fn with_path(path: &Path) -> impl Fn(io::Error) -> io::Error + '_ {
+    move |err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+}
+
+/// `1 2 3`
+fn uids_str(uids: &[u32]) -> String {
+    let uids: Vec<String> = uids.iter().map(|uid| uid.to_string()).collect();
+
+    uids.join(" ")
 }
 
 
 
+/// State kept across the commands of `hhq shell`
+#[derive(Default)]
+struct Ctx {
+    catalog: Option<Catalog>,
+    /// running in `hhq shell`, stdin is the shell's
+    shell: bool,
+    /// `explain on`
+    explain: bool,
+    /// last command failed (`check` mismatch, `diff` differences,
+    /// unreadable table)
+    failed: bool,
+}
+
+impl Ctx {
+    /// Scanned on first use only
+    fn catalog(&mut self) -> &Catalog {
+        self.catalog.get_or_insert_with(Catalog::load)
+    }
+}
+
+
 fn main() {
     let cli = Cli::parse();
 
@@ -42,32 +427,834 @@ fn main() {
     }
 
     if let Some(command) = cli.command {
-        match command {
-            SubCommand::Bili2 { id } => {
-                // let ids: Vec<u32> = ids
-                // .into_iter()
-                // .map(|id| format_hex_str(&id).unwrap())
-                // .collect();
-                let id = format_hex_str(&id).unwrap();
-                let res = query_bili2(id).unwrap();
-
-                if res.is_empty() {
-                    // query collision resolve
-                    println!("Not Found in Normal.");
+        let mut ctx = Ctx::default();
+
+        if let SubCommand::Shell {} = command {
+            return run_shell(cli.format, &mut ctx);
+        }
+
+        if let Err(err) = run(command, cli.format, &mut ctx) {
+            eprintln!("{}", err);
+            exit(1);
+        }
+
+        if ctx.failed {
+            exit(1);
+        }
+    }
+}
+
+/// Errors are returned for the caller to report, a command that ran but
+/// found a mismatch sets `ctx.failed` instead
+fn run(command: SubCommand, fmt: Format, ctx: &mut Ctx) -> Result<(), io::Error> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    match command {
+        SubCommand::Bili2 {
+            stdin: true,
+            tables,
+            explain,
+            threads,
+            ..
+        } => {
+            if ctx.shell {
+                eprintln!("bili2 --stdin reads the shell's input, run it outside of hhq shell");
+                ctx.failed = true;
+                return Ok(());
+            }
+
+            let explain = explain || ctx.explain;
+            let stdin = io::stdin();
+            let stderr = io::stderr();
+            let mut err_out = stderr.lock();
+            let mut plan = QueryPlan::default().restrict(&tables);
+            if let Some(threads) = threads {
+                plan.threads = threads;
+            }
+
+            if fmt != Format::Plain {
+                fmt.write_header::<_, CandidateRecord>(&mut out)?;
+            }
+            if explain {
+                fmt.write_header::<_, ProbeRecord>(&mut err_out)?;
+            }
+
+            let explain_out: Option<&mut dyn Write> =
+                if explain { Some(&mut err_out) } else { None };
+
+            match query_stream_bili2(
+                ctx.catalog(),
+                stdin.lock(),
+                &mut out,
+                fmt,
+                &plan,
+                explain_out,
+            ) {
+                // downstream of the pipe closed
+                Err(err) if err.kind() == io::ErrorKind::BrokenPipe => (),
+                res => res?,
+            }
+        }
+        SubCommand::Bili2 {
+            ids,
+            file,
+            tables,
+            explain,
+            threads,
+            rank,
+            range,
+            limit,
+            ..
+        } => {
+            let mut ids: Vec<u32> = ids
+                .into_iter()
+                .map(|id| format_hex_str(&id).unwrap())
+                .collect();
+
+            if let Some(file) = file {
+                let content = read_to_string(&file).map_err(with_path(&file))?;
+
+                let mut bad = false;
+                for (i, line) in content.lines().enumerate() {
+                    let line = line.trim();
+
+                    if line.is_empty() {
+                        continue;
+                    }
+                    match parse_hash(line) {
+                        Some(id) => ids.push(id),
+                        None => {
+                            eprintln!("{}:{}: invalid hash {:?}", file.display(), i + 1, line);
+                            bad = true;
+                        }
+                    }
+                }
+
+                if bad {
+                    ctx.failed = true;
+                    return Ok(());
+                }
+            }
+
+            let mut plan = QueryPlan::default().restrict(&tables);
+            if let Some(threads) = threads {
+                plan.threads = threads;
+            }
+            let explain = explain || ctx.explain;
+            let catalog = ctx.catalog();
+            let rank_cfg = rank.into_config()?;
+
+            if let Some(range) = range {
+                if explain {
+                    eprintln!("explain: not reported for --prefix, it scans the tables in the range");
+                }
+
+                let coverage = catalog.coverage();
+                let mut iter = catalog.range(&plan, range)?;
+                let mut group: Vec<Candidate> = vec![];
+                let mut cnt = 0;
+
+                if rank_cfg.is_some() {
+                    fmt.write_header::<_, ScoredRecord>(&mut out)?;
                 } else {
-                    println!("Normal:");
-                    println!("{:#?}", res);
+                    fmt.write_header::<_, CandidateRecord>(&mut out)?;
                 }
 
-                let resolve_res = query_collision_rehash_resolve(id).unwrap();
-                if resolve_res.is_empty() {
-                    println!("Resolve Failed.")
+                // the candidates of a hash, ranked among themselves
+                let mut write_group = |group: Vec<Candidate>| match &rank_cfg {
+                    Some(cfg) => fmt.write_scored(
+                        &mut out,
+                        group[0].hash,
+                        &rank_candidates(group, cfg),
+                        &coverage,
+                    ),
+                    None => fmt.write_candidates(&mut out, group[0].hash, &group, &coverage),
+                };
+
+                for cand in iter.by_ref().take(limit) {
+                    let cand = cand?;
+
+                    if group.last().is_some_and(|x| x.hash != cand.hash) {
+                        write_group(std::mem::take(&mut group))?;
+                    }
+                    group.push(cand);
+                    cnt += 1;
+                }
+                if !group.is_empty() {
+                    write_group(group)?;
+                }
+
+                if iter.next().is_some() {
+                    eprintln!("stopped after {} candidates of {} (--limit)", cnt, range);
+                }
+                return Ok(());
+            }
+
+            let coverage = catalog.coverage();
+            let (batch_res, probes) =
+                catalog.query_explain(&plan, &ids)?;
+
+            if explain {
+                let stderr = io::stderr();
+                let mut err_out = stderr.lock();
+
+                fmt.write_header::<_, ProbeRecord>(&mut err_out)?;
+                fmt.write_explain(&mut err_out, &ids, &probes)?;
+            }
+
+            if let Some(cfg) = rank_cfg {
+                fmt.write_header::<_, ScoredRecord>(&mut out)?;
+                for (id, cands) in ids.iter().zip(batch_res) {
+                    fmt.write_scored(&mut out, *id, &rank_candidates(cands, &cfg), &coverage)?;
+                }
+                return Ok(());
+            }
+
+            if fmt != Format::Plain {
+                fmt.write_header::<_, CandidateRecord>(&mut out)?;
+                for (id, cands) in ids.iter().zip(batch_res) {
+                    fmt.write_candidates(&mut out, *id, &cands, &coverage)?;
+                }
+                return Ok(());
+            }
+
+            for (id, cands) in ids.iter().zip(batch_res) {
+                if ids.len() > 1 {
+                    println!("{:08x}:", id);
+                }
+
+                for family in plan.families.iter() {
+                    let family_cands: Vec<&Candidate> = cands
+                        .iter()
+                        .filter(|cand| cand.found_in(*family).is_some())
+                        .collect();
+
+                    match family {
+                        TblFamily::Resolve => {
+                            if family_cands.is_empty() {
+                                println!("Resolve Failed.")
+                            } else {
+                                println!("Resolved: ");
+                                for cand in family_cands {
+                                    let chain = ResolveChain {
+                                        orig: cand.found_in(*family).and_then(|x| x.orig),
+                                        ..ResolveChain::from(cand)
+                                    };
+                                    println!("{}", chain);
+                                }
+                            }
+                        }
+                        TblFamily::Override if !family_cands.is_empty() => {
+                            let uids: Vec<u32> =
+                                family_cands.iter().map(|cand| cand.uid).collect();
+
+                            println!("Confirmed: {}", uids_str(&uids));
+                        }
+                        TblFamily::Normal if family_cands.is_empty() => {
+                            // query collision resolve
+                            println!("Not Found in Normal.");
+                        }
+                        _ if family_cands.is_empty() => (),
+                        _ => {
+                            let uids: Vec<u32> =
+                                family_cands.iter().map(|cand| cand.uid).collect();
+
+                            println!("{:?}: {}", family, uids_str(&uids));
+                        }
+                    }
+                }
+
+                if cands.is_empty() {
+                    println!("{}", coverage);
+                }
+            }
+        }
+        SubCommand::Shell {} => eprintln!("already in hhq shell"),
+        SubCommand::Config {} => {
+            if fmt == Format::Plain {
+                print_dbmeta();
+            } else {
+                write_dbmeta(&mut out, fmt)?;
+            }
+        }
+        SubCommand::Group { uid } => {
+            let res = query_colliding_uids(ctx.catalog(), uid)?;
+
+            if fmt != Format::Plain {
+                let recs: Vec<CollideRecord> = res
+                    .into_iter()
+                    .map(|collide| CollideRecord {
+                        uid,
+                        hash: format!("{:08x}", hash_bili2(uid)),
+                        collide,
+                    })
+                    .collect();
+
+                fmt.write_records(&mut out, &recs)?;
+            } else if res.is_empty() {
+                println!("No Collision.");
+            } else {
+                println!("Collide with: {}", uids_str(&res));
+            }
+        }
+        SubCommand::Inspect { tables } => {
+            fmt.write_header::<_, TblHeader>(&mut out)?;
+
+            for path in tables {
+                let header = match inspect(&path) {
+                    Ok(header) => header,
+                    Err(err) => {
+                        eprintln!("{}: {}", path.display(), err);
+                        ctx.failed = true;
+                        continue;
+                    }
+                };
+
+                if !header.is_ok() {
+                    ctx.failed = true;
+                }
+
+                if fmt == Format::Plain {
+                    print!("{}", header);
                 } else {
-                    println!("Resolved: ");
-                    println!("{:#?}", res)
+                    fmt.write_record(&mut out, &header)?;
+                }
+            }
+        }
+        SubCommand::Export {
+            to,
+            output,
+            tables,
+            range,
+        } => {
+            let plan = QueryPlan::default().restrict(&tables);
+
+            if let Err(err) = export(ctx.catalog(), &plan, range, to, output.as_deref()) {
+                eprintln!("export: {}", err);
+                ctx.failed = true;
+            }
+        }
+        SubCommand::Diff { a, b } => match diff_dbs(&mut out, fmt, &a, &b) {
+            Ok(summaries) => {
+                for summary in summaries.iter() {
+                    eprintln!("{}", summary);
+                }
+
+                ctx.failed = !summaries.last().unwrap().is_same();
+            }
+            Err(err) => {
+                eprintln!("diff: {}", err);
+                ctx.failed = true;
+            }
+        },
+        SubCommand::Dump {
+            table,
+            from_row,
+            to_row,
+            from_key,
+            to_key,
+        } => {
+            let range = DumpRange {
+                from_row,
+                to_row,
+                from_key: from_key.map(|x| format_hex_str(&x).unwrap()),
+                to_key: to_key.map(|x| format_hex_str(&x).unwrap()),
+            };
+
+            if let Err(err) = dump(&mut out, fmt, &table, range) {
+                eprintln!("{}: {}", table.display(), err);
+                ctx.failed = true;
+            }
+        }
+        SubCommand::Locate { uids } => {
+            let catalog = ctx.catalog();
+
+            fmt.write_header::<_, LocateRecord>(&mut out)?;
+
+            for uid in uids {
+                let loc = catalog.locate(uid)?;
+
+                if fmt != Format::Plain {
+                    for rec in LocateRecord::of(uid, &loc) {
+                        fmt.write_record(&mut out, &rec)?;
+                    }
+                    continue;
+                }
+
+                let loc = match loc {
+                    Some(loc) => loc,
+                    None => {
+                        println!(
+                            "{} -> {:08x}: not in generated uids {}",
+                            uid,
+                            hash_bili2(uid),
+                            catalog.coverage().covered_str()
+                        );
+                        continue;
+                    }
+                };
+
+                match loc.row {
+                    Some(row) => println!(
+                        "{} -> {:08x}: {} row {}",
+                        uid, loc.hash, loc.path, row
+                    ),
+                    None => println!(
+                        "{} -> {:08x}: missing from {}",
+                        uid, loc.hash, loc.path
+                    ),
+                }
+
+                for (row, run_uid) in loc.run.iter() {
+                    let mark = if *run_uid == uid { '>' } else { ' ' };
+                    println!("{} row {}: {}", mark, row, run_uid);
+                }
+            }
+        }
+        SubCommand::Hash { uids, spec } => {
+            let hashes = hash_uids(&spec.into_spec(), &uids);
+            let recs: Vec<HashRecord> = uids
+                .iter()
+                .zip(hashes)
+                .map(|(uid, hash)| HashRecord {
+                    uid: *uid,
+                    hash: format!("{:08x}", hash),
+                })
+                .collect();
+
+            if fmt == Format::Plain {
+                for rec in recs.iter() {
+                    println!("{}: {}", rec.uid, rec.hash);
+                }
+            } else {
+                fmt.write_records(&mut out, &recs)?;
+            }
+        }
+        SubCommand::Check { hash, uid, spec } => {
+            let hash = format_hex_str(&hash).unwrap();
+            let check = check_pair(&spec.into_spec(), hash, uid);
+
+            if fmt == Format::Plain {
+                println!("{}", check);
+            } else {
+                fmt.write_records(&mut out, &[CheckRecord::of(&check)])?;
+            }
+
+            ctx.failed = !check.ok();
+        }
+        SubCommand::Override { action } => {
+            let mut ovs = Overrides::load()?;
+
+            match action {
+                OverrideAction::Add { hash, uid, verdict, note } => {
+                    let hash = format_hex_str(&hash).unwrap();
+
+                    ovs.add(Override::new(hash, uid, verdict, &note));
+                    ovs.save()?;
+                }
+                OverrideAction::Remove { hash, uid } => {
+                    let hash = format_hex_str(&hash).unwrap();
+                    let cnt = ovs.remove(hash, uid);
+
+                    ovs.save()?;
+                    eprintln!("removed {}", cnt);
+                }
+                OverrideAction::List { hash } => {
+                    let list = match hash {
+                        Some(hash) => ovs.of(format_hex_str(&hash).unwrap()),
+                        None => &ovs.0[..],
+                    };
+                    let recs: Vec<OverrideRecord> =
+                        list.iter().map(OverrideRecord::of).collect();
+
+                    fmt.write_records(&mut out, &recs)?;
+                }
+                OverrideAction::Import { csv } => {
+                    let cnt = import_csv(&csv).map_err(with_path(&csv))?;
+
+                    eprintln!("imported {}", cnt);
+                }
+            }
+        }
+        SubCommand::Stats { top, json } => {
+            let stats = stats_bili2(ctx.catalog(), top)?;
+            let fmt = if json { Format::Json } else { fmt };
+
+            match fmt {
+                Format::Plain => println!("{}", stats),
+                Format::Json => Format::write_json(&mut out, &stats)?,
+                Format::Csv | Format::Tsv => {
+                    fmt.write_records(&mut out, &StatsRecord::of(&stats))?;
+                }
+            }
+        }
+        SubCommand::Eval { csv } => {
+            let truths = load_truth(&csv).map_err(with_path(&csv))?;
+            let report = eval_bili2(ctx.catalog(), &truths)?;
+
+            if fmt == Format::Plain {
+                println!("{}", report);
+            } else {
+                fmt.write_records(&mut out, &report.0)?;
+            }
+        }
+        SubCommand::Search { csv, wordlist, templates, polys, inits, xorouts, depth, top } => {
+            let pairs: Vec<(u32, u32)> = load_truth(&csv)
+                .map_err(with_path(&csv))?
+                .into_iter()
+                .filter_map(|truth| truth.uid.map(|uid| (truth.hash, uid)))
+                .collect();
+
+            let mut space = SearchSpace {
+                max_depth: depth,
+                ..Default::default()
+            };
+
+            if let Some(wordlist) = wordlist {
+                space.salts = load_wordlist(&wordlist).map_err(with_path(&wordlist))?;
+                space.templates = vec![
+                    "{uid}".to_string(),
+                    "{salt}{uid}".to_string(),
+                    "{uid}{salt}".to_string(),
+                ];
+            }
+            if !templates.is_empty() {
+                space.templates = templates;
+            }
+            for poly in polys {
+                space.polys.push(format_hex_str(&poly).unwrap());
+            }
+            for init in inits {
+                space.inits.push(format_hex_str(&init).unwrap());
+            }
+            for xorout in xorouts {
+                space.xorouts.push(format_hex_str(&xorout).unwrap());
+            }
+
+            let report = match search(&space, &pairs, top) {
+                Ok(report) => report,
+                Err(err) => {
+                    eprintln!("search: {}", err);
+                    ctx.failed = true;
+                    return Ok(());
+                }
+            };
+
+            if fmt == Format::Plain {
+                println!("{}", report);
+            } else {
+                // full matches, then the partial ones
+                let recs: Vec<SpecRecord> = report
+                    .matched
+                    .iter()
+                    .map(|spec| SpecRecord::of(report.pairs, report.pairs, spec))
+                    .chain(
+                        report
+                            .best
+                            .iter()
+                            .filter(|(hit, _)| *hit < report.pairs)
+                            .map(|(hit, spec)| SpecRecord::of(*hit, report.pairs, spec)),
+                    )
+                    .collect();
+
+                fmt.write_records(&mut out, &recs)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+const SHELL_COMMANDS: [&str; 6] = ["explain", "format", "reload", "help", "exit", "quit"];
+const SHELL_HELP: &str = "\
+explain on|off    report probes of every query but --prefix
+format <fmt>      plain, csv, tsv or json
+reload            rescan the database
+help              this, and the hhq commands
+exit              (or ctrl-d)
+any hhq command   e.g. `q c4ff7ac1`, `hash 6487381`, `stats`, `locate 5`";
+
+/// Completes the first word from the commands, flags from those of the
+/// command, and the argument of `format`/`explain`
+struct ShellHelper {
+    /// (command, its long flags)
+    commands: Vec<(String, Vec<String>)>,
+}
+
+impl ShellHelper {
+    fn new() -> Self {
+        let cmd = Cli::command();
+        let global: Vec<String> = cmd
+            .get_arguments()
+            .filter(|arg| arg.is_global_set())
+            .filter_map(|arg| arg.get_long().map(|x| format!("--{}", x)))
+            .collect();
+
+        let mut commands: Vec<(String, Vec<String>)> = cmd
+            .get_subcommands()
+            .flat_map(|sub| {
+                let mut flags: Vec<String> = sub
+                    .get_arguments()
+                    .filter_map(|arg| arg.get_long().map(|x| format!("--{}", x)))
+                    .collect();
+                flags.extend(global.iter().cloned());
+
+                sub.get_all_aliases()
+                    .chain([sub.get_name()])
+                    .map(|name| (name.to_string(), flags.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        for name in SHELL_COMMANDS {
+            commands.push((name.to_string(), vec![]));
+        }
+        commands.sort();
+
+        Self { commands }
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let head = &line[..pos];
+        let start = head.rfind(' ').map(|i| i + 1).unwrap_or(0);
+        let word = &head[start..];
+        let words: Vec<&str> = head[..start].split_whitespace().collect();
+
+        let choices: Vec<String> = match words.as_slice() {
+            [] => self.commands.iter().map(|(name, _)| name.clone()).collect(),
+            ["format"] => Format::value_variants()
+                .iter()
+                .filter_map(|x| x.to_possible_value())
+                .map(|x| x.get_name().to_string())
+                .collect(),
+            ["explain"] => vec!["on".to_string(), "off".to_string()],
+            [cmd, ..] if word.starts_with('-') => self
+                .commands
+                .iter()
+                .find(|(name, _)| name == cmd)
+                .map(|(_, flags)| flags.clone())
+                .unwrap_or_default(),
+            _ => vec![],
+        };
+
+        Ok((
+            start,
+            choices.into_iter().filter(|x| x.starts_with(word)).collect(),
+        ))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+impl Highlighter for ShellHelper {}
+impl Validator for ShellHelper {}
+impl Helper for ShellHelper {}
+
+
+/// Words of a line, `'`/`"` quote spaces
+fn split_line(line: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
                 }
             }
-            SubCommand::Config {} => print_dbmeta(),
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err("unterminated quote".to_string());
+    }
+    if in_word {
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+fn format_name(fmt: Format) -> String {
+    fmt.to_possible_value().unwrap().get_name().to_string()
+}
+
+/// The words pass `--format` themselves, as `--format json` or `--format=json`
+fn sets_format(words: &[&str]) -> bool {
+    words.iter().any(|w| *w == "--format" || w.starts_with("--format="))
+}
+
+/// One line of `hhq shell`, false to quit
+fn run_shell_line(line: &str, fmt: &mut Format, ctx: &mut Ctx) -> bool {
+    let words = match split_line(line) {
+        Ok(words) => words,
+        Err(err) => {
+            eprintln!("{}", err);
+            return true;
+        }
+    };
+    let words: Vec<&str> = words.iter().map(|x| x.as_str()).collect();
+
+    match words.as_slice() {
+        [] => (),
+        ["exit" | "quit"] => return false,
+        ["help"] => {
+            println!("{}\n", SHELL_HELP);
+            Cli::command().print_help().unwrap();
+            println!();
         }
+        ["reload"] => ctx.catalog = Some(Catalog::load()),
+        ["explain"] => println!("explain {}", if ctx.explain { "on" } else { "off" }),
+        ["explain", "on"] => ctx.explain = true,
+        ["explain", "off"] => ctx.explain = false,
+        ["format"] => println!("format {}", format_name(*fmt)),
+        ["format", name] => match Format::from_str(name, true) {
+            Ok(x) => *fmt = x,
+            Err(err) => eprintln!("{}", err),
+        },
+        _ => {
+            // same parser as the command line, `format` is the default
+            let mut args = vec!["hhq".to_string()];
+            if !sets_format(&words) {
+                args.push("--format".to_string());
+                args.push(format_name(*fmt));
+            }
+            args.extend(words.iter().map(|x| x.to_string()));
+
+            let cli = match Cli::try_parse_from(args) {
+                Ok(cli) => cli,
+                Err(err) => {
+                    let _ = err.print();
+                    return true;
+                }
+            };
+
+            if let Some(command) = cli.command {
+                // a failed command leaves the shell running
+                if let Err(err) = run(command, cli.format, ctx) {
+                    eprintln!("{}", err);
+                }
+            }
+        }
+    }
+
+    true
+}
+
+fn run_shell(mut fmt: Format, ctx: &mut Ctx) {
+    ctx.shell = true;
+
+    let history = PathBuf::from(tilde("~/.hhq_history").to_string());
+    let mut rl = Editor::<ShellHelper>::new();
+    rl.set_helper(Some(ShellHelper::new()));
+    let _ = rl.load_history(&history);
+
+    loop {
+        match rl.readline("hhq> ") {
+            Ok(line) => {
+                rl.add_history_entry(line.as_str());
+
+                if !run_shell_line(&line, &mut fmt, ctx) {
+                    break;
+                }
+            }
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("{}", err);
+                break;
+            }
+        }
+    }
+
+    if let Err(err) = rl.save_history(&history) {
+        eprintln!("save history {}: {}", history.display(), err);
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use rustyline::{completion::Completer, history::History, Context};
+
+    use super::{sets_format, split_line, Cli, ShellHelper};
+
+    #[test]
+    fn test_split_line() {
+        let words = |line: &str| split_line(line).unwrap();
+
+        assert_eq!(words("  bili2  1388875d "), vec!["bili2", "1388875d"]);
+        assert_eq!(words(r#"override add 1 2 --note 'a "b" c'"#)[5], r#"a "b" c"#);
+        assert_eq!(words(r#"x"y z"w ''"#), vec!["xy zw", ""]);
+        assert!(words("").is_empty());
+        assert!(split_line("check 'abc").is_err());
+
+        assert!(sets_format(&["stats", "--format=json"]));
+        assert!(sets_format(&["stats", "--format", "csv"]));
+        assert!(!sets_format(&["check", "--formats"]));
+    }
+
+    #[test]
+    fn test_complete() {
+        let helper = ShellHelper::new();
+        let history = History::new();
+        let complete = |line: &str| {
+            helper.complete(line, line.len(), &Context::new(&history)).unwrap()
+        };
+
+        // first word: commands, aliases and shell builtins
+        let (start, choices) = complete("re");
+        assert_eq!(start, 0);
+        assert!(choices.contains(&"reload".to_string()));
+        assert!(choices.iter().all(|x| x.starts_with("re")));
+
+        // flags of the command, with the global ones
+        let (start, choices) = complete("bili2 1388875d --ta");
+        assert_eq!((start, choices), (15, vec!["--tables".to_string()]));
+        assert!(complete("bili2 --").1.contains(&"--format".to_string()));
+
+        assert_eq!(complete("format c").1, vec!["csv".to_string()]);
+        assert!(complete("bili2 13").1.is_empty());
+        assert!(complete("nosuch --").1.is_empty());
+    }
+
+    #[test]
+    fn test_bili2_needs_hashes() {
+        let parse = |args: &[&str]| Cli::try_parse_from(["hhq", "bili2"].iter().chain(args));
+
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--tables", "normal"]).is_err());
+        assert!(parse(&["1388875d", "-f", "hashes.txt"]).is_ok());
+        assert!(parse(&["--prefix", "c4ff"]).is_ok());
+        assert!(parse(&["--stdin"]).is_ok());
+        assert!(parse(&["--prefix", "c4ff", "--explain"]).is_err());
     }
 }
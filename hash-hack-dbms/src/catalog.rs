@@ -0,0 +1,769 @@
+//! Catalog && Query Plan
+//!
+//! Every table family found in a database dir (the working dir by default),
+//! loaded once, and the order they are consulted in for a query.
+//!
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, VecDeque},
+    fmt::Display,
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
+
+use serde_derive::Serialize;
+
+use crate::{
+    data::{DBMeta2, GroupMember, UInt},
+    gendata::{CollisionResolver, CountRead, TblReader, BUNDLE_LEN},
+    overrides::{Override, Overrides, Verdict},
+    query::{
+        default_threads, load_family_in, query_tables_explain, Candidate,
+        Explain, KeyRange, TblCursor,
+    },
+    hash::hash_bili2,
+    shell::{path2str, TblFamily, CWD},
+};
+
+
+pub struct Catalog {
+    /// dir the table paths are under
+    pub(crate) root: PathBuf,
+    pub(crate) normal: DBMeta2,
+    pub(crate) dup: DBMeta2,
+    pub(crate) group: DBMeta2,
+    pub(crate) imports: DBMeta2,
+    pub(crate) resolves: Vec<(CollisionResolver, DBMeta2)>,
+    pub(crate) overrides: Overrides,
+}
+
+#[derive(Clone, Debug)]
+pub struct QueryPlan {
+    /// families in probe order
+    pub families: Vec<TblFamily>,
+    /// resolvers in priority order, probed where `Resolve` is in `families`
+    pub resolvers: Vec<CollisionResolver>,
+    /// workers probing the tables of the plan concurrently
+    pub threads: usize,
+}
+
+/// Where the row of a uid is in the normal tables
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Location {
+    pub uid: u32,
+    pub hash: u32,
+    /// table covering the uid
+    pub id: u32,
+    pub path: String,
+    /// `None` if the table misses the uid
+    pub row: Option<u64>,
+    /// (row, uid) of the collision run of `hash` in the table
+    pub run: Vec<(u64, u32)>,
+}
+
+/// Uid intervals (inclusive) the normal tables were generated for, and the
+/// ones in between / above that were not
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Coverage {
+    pub covered: Vec<(u32, u32)>,
+    pub gaps: Vec<(u32, u32)>,
+}
+
+
+/// Curated overrides, normal, then the redundant dup/group tables, pairs
+/// imported from other sources, then resolvers
+const PLAN_ORDER: [TblFamily; 6] = [
+    TblFamily::Override,
+    TblFamily::Normal,
+    TblFamily::Dup,
+    TblFamily::Group,
+    TblFamily::Import,
+    TblFamily::Resolve,
+];
+
+impl Default for QueryPlan {
+    /// `PLAN_ORDER` but dup, asked for only: every row of it is in normal,
+    /// and tables written before dups were sorted can not be searched
+    fn default() -> Self {
+        Self {
+            families: PLAN_ORDER
+                .iter()
+                .filter(|family| **family != TblFamily::Dup)
+                .cloned()
+                .collect(),
+            resolvers: CollisionResolver::ALL.to_vec(),
+            threads: default_threads(),
+        }
+    }
+}
+
+impl QueryPlan {
+    /// Only `families` (`Dup` too), in plan order, empty means no change
+    pub fn restrict(mut self, families: &[TblFamily]) -> Self {
+        if !families.is_empty() {
+            self.families = PLAN_ORDER
+                .iter()
+                .filter(|family| families.contains(family))
+                .cloned()
+                .collect();
+        }
+
+        self
+    }
+}
+
+
+impl Catalog {
+    pub fn coverage(&self) -> Coverage {
+        Coverage::of(&self.normal)
+    }
+
+    pub fn load() -> Self {
+        Self::load_in(Path::new(CWD))
+    }
+
+    /// Tables and overrides under `root`
+    pub fn load_in(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            normal: load_family_in(root, TblFamily::Normal),
+            dup: load_family_in(root, TblFamily::Dup),
+            group: load_family_in(root, TblFamily::Group),
+            imports: load_family_in(root, TblFamily::Import),
+            resolves: CollisionResolver::ALL
+                .iter()
+                .map(|resolve| (*resolve, resolve.load_dbmeta_in(root)))
+                .collect(),
+            overrides: Overrides::load_in(root).unwrap_or_else(|err| {
+                eprintln!("ignoring overrides: {}", err);
+                Overrides::default()
+            }),
+        }
+    }
+
+    /// Tables of the family, resolvers in `plan` order, overrides are merged
+    /// after the tables instead
+    pub(crate) fn tables_of(
+        &self,
+        family: TblFamily,
+        plan: &QueryPlan,
+    ) -> Vec<(Option<CollisionResolver>, &DBMeta2)> {
+        match family {
+            TblFamily::Normal => vec![(None, &self.normal)],
+            TblFamily::Dup => vec![(None, &self.dup)],
+            TblFamily::Group => vec![(None, &self.group)],
+            TblFamily::Import => vec![(None, &self.imports)],
+            TblFamily::Override => vec![],
+            TblFamily::Resolve => plan
+                .resolvers
+                .iter()
+                .filter_map(|resolve| {
+                    self.resolves
+                        .iter()
+                        .find(|(x, _)| x == resolve)
+                        .map(|(x, dbmeta)| (Some(*x), dbmeta))
+                })
+                .collect(),
+        }
+    }
+
+    /// Candidates of each hash (in input order) following `plan`, a uid is
+    /// kept once as the first step found it, the later ones in `also`
+    pub fn query(
+        &self,
+        plan: &QueryPlan,
+        ids: &[u32],
+    ) -> Result<Vec<Vec<Candidate>>, io::Error> {
+        self.query_probes(plan, ids, None)
+    }
+
+    /// `query`, and how each table was probed
+    pub fn query_explain(
+        &self,
+        plan: &QueryPlan,
+        ids: &[u32],
+    ) -> Result<(Vec<Vec<Candidate>>, Explain), io::Error> {
+        let mut explain = Explain::default();
+        let res = self.query_probes(plan, ids, Some(&mut explain))?;
+
+        Ok((res, explain))
+    }
+
+    fn query_probes(
+        &self,
+        plan: &QueryPlan,
+        ids: &[u32],
+        explain: Option<&mut Explain>,
+    ) -> Result<Vec<Vec<Candidate>>, io::Error> {
+        let keys: Vec<UInt> = ids.iter().map(|id| UInt::U32(*id)).collect();
+        let mut res: Vec<Vec<Candidate>> = vec![vec![]; ids.len()];
+
+        // every table of the plan on the same workers, merged in plan order
+        let mut tbls = vec![];
+        let mut steps = vec![];
+
+        for family in plan.families.iter() {
+            for (resolve, dbmeta) in self.tables_of(*family, plan) {
+                tbls.extend(dbmeta.0.iter().cloned());
+                steps.extend(dbmeta.0.iter().map(|_| (*family, resolve)));
+            }
+        }
+
+        let tbl_rows =
+            query_tables_explain(&self.root, &tbls, &keys, plan.threads, explain)?;
+
+        for ((family, resolve), rows) in steps.into_iter().zip(tbl_rows) {
+            for ((cands, rows), id) in res.iter_mut().zip(rows).zip(ids.iter()) {
+                for vals in rows {
+                    let cand = candidate_of(family, resolve, *id, vals);
+
+                    match cands.iter_mut().find(|x| x.uid == cand.uid) {
+                        Some(x) => x.merge(cand),
+                        None => cands.push(cand),
+                    }
+                }
+            }
+        }
+
+        let confirm = plan.families.contains(&TblFamily::Override);
+        for (cands, id) in res.iter_mut().zip(ids.iter()) {
+            self.overrides.apply(*id, cands, confirm);
+        }
+
+        Ok(res)
+    }
+}
+
+
+impl Catalog {
+    /// Row of `uid` in the normal table whose uid range covers it (by the
+    /// hash, no scan), `None` if no table covers it. Only normal tables are
+    /// laid out by uid, imported pairs are not searched.
+    pub fn locate(&self, uid: u32) -> Result<Option<Location>, io::Error> {
+        let found = self.normal.0.iter().find(|(ty, meta)| {
+            let start = ty.id() as u64 * BUNDLE_LEN as u64 + 1;
+
+            start <= uid as u64 && (uid as u64) < start + meta.len
+        });
+
+        let (ty, meta) = match found {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let hash = hash_bili2(uid);
+        let reader = TblReader::open(*meta, &ty.path_in(&self.root))?.into_reader();
+        let mut cursor = TblCursor::new(reader, *meta);
+
+        let pos = cursor.lower_bound(UInt::U32(hash), 0, meta.len)?;
+        let (rows, _) = cursor.scan_eq(UInt::U32(hash), pos)?;
+
+        let run: Vec<(u64, u32)> = rows
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut vals)| (pos + i as u64, vals.pop().unwrap().into_u32()))
+            .collect();
+
+        Ok(Some(Location {
+            uid,
+            hash,
+            id: ty.id(),
+            path: path2str(&ty.pathbuf()),
+            row: run.iter().find(|(_, x)| *x == uid).map(|(row, _)| *row),
+            run,
+        }))
+    }
+
+    /// Rows with keys in `range` across the tables of `plan`, in key order
+    /// (plan order for the same key), a uid is kept once per hash,
+    /// rejected overrides are dropped and confirmed ones come first if
+    /// `override` is in the plan. Every key in `lo..=hi` is read, so a mask
+    /// with unknown leading digits walks most of the tables.
+    pub fn range(
+        &self,
+        plan: &QueryPlan,
+        range: KeyRange,
+    ) -> Result<RangeIter<'_>, io::Error> {
+        let mut tbls = vec![];
+
+        for family in plan.families.iter() {
+            for (resolve, dbmeta) in self.tables_of(*family, plan) {
+                for (ty, meta) in dbmeta.0.iter() {
+                    let reader = TblReader::open(*meta, &ty.path_in(&self.root))?.into_reader();
+                    let mut cursor = TblCursor::new(reader, *meta);
+
+                    let pos = cursor.lower_bound(UInt::U32(range.lo), 0, meta.len)?;
+                    cursor.seek_row(pos)?;
+
+                    tbls.push(RangeTbl {
+                        family: *family,
+                        resolve,
+                        cursor,
+                        pos,
+                    });
+                }
+            }
+        }
+
+        let mut confirmed = VecDeque::new();
+        if plan.families.contains(&TblFamily::Override) {
+            confirmed.extend(self.overrides.0.iter().filter(|ov| {
+                ov.verdict == Verdict::Confirmed && range.contains(ov.hash)
+            }));
+        }
+
+        let mut iter = RangeIter {
+            overrides: &self.overrides,
+            confirmed,
+            range,
+            tbls,
+            heap: BinaryHeap::new(),
+            key: None,
+            seen: vec![],
+        };
+
+        for i in 0..iter.tbls.len() {
+            iter.advance(i)?;
+        }
+
+        Ok(iter)
+    }
+}
+
+
+struct RangeTbl {
+    family: TblFamily,
+    resolve: Option<CollisionResolver>,
+    cursor: TblCursor<BufReader<CountRead<File>>>,
+    pos: u64,
+}
+
+/// See `Catalog::range`
+pub struct RangeIter<'a> {
+    overrides: &'a Overrides,
+    /// confirmed overrides in range not yielded yet, in key order
+    confirmed: VecDeque<&'a Override>,
+    range: KeyRange,
+    tbls: Vec<RangeTbl>,
+    /// next row of each table: (key, table idx, value columns)
+    heap: BinaryHeap<Reverse<(u32, usize, Vec<UInt>)>>,
+    key: Option<u32>,
+    /// uids of `key` so far
+    seen: Vec<u32>,
+}
+
+impl RangeIter<'_> {
+    /// Push the next row of table `i` in range, if any
+    fn advance(&mut self, i: usize) -> Result<(), io::Error> {
+        let tbl = &mut self.tbls[i];
+
+        if tbl.pos >= tbl.cursor.meta().len {
+            return Ok(());
+        }
+
+        let (key, vals) = tbl.cursor.read_row()?;
+        let key = key.into_u32();
+        tbl.pos += 1;
+
+        if key <= self.range.hi {
+            self.heap.push(Reverse((key, i, vals)));
+        }
+
+        Ok(())
+    }
+
+    fn next_row(&mut self) -> Result<Option<Candidate>, io::Error> {
+        loop {
+            let next_key = self.heap.peek().map(|Reverse((key, ..))| *key);
+
+            // a confirmed override comes before the tables for its key
+            let cand = match self.confirmed.front() {
+                Some(ov) if next_key.is_none_or(|key| ov.hash <= key) => {
+                    self.confirmed.pop_front().unwrap().candidate()
+                }
+                _ => {
+                    let Some(Reverse((key, i, vals))) = self.heap.pop() else {
+                        return Ok(None);
+                    };
+                    self.advance(i)?;
+
+                    if !self.range.contains(key) {
+                        continue;
+                    }
+
+                    let tbl = &self.tbls[i];
+                    candidate_of(tbl.family, tbl.resolve, key, vals)
+                }
+            };
+            let key = cand.hash;
+
+            if self.key != Some(key) {
+                self.key = Some(key);
+                self.seen.clear();
+            }
+            if self.seen.contains(&cand.uid) {
+                continue;
+            }
+            self.seen.push(cand.uid);
+
+            let rejected = self.overrides.of(key).iter().any(|ov| {
+                ov.uid == cand.uid && ov.verdict == Verdict::Rejected
+            });
+            if rejected {
+                continue;
+            }
+
+            return Ok(Some(cand));
+        }
+    }
+}
+
+impl Iterator for RangeIter<'_> {
+    type Item = Result<Candidate, io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_row().transpose()
+    }
+}
+
+
+/// Candidate from the value columns of a row of a `family` table
+pub(crate) fn candidate_of(
+    family: TblFamily,
+    resolve: Option<CollisionResolver>,
+    hash: u32,
+    mut vals: Vec<UInt>,
+) -> Candidate {
+    let val = vals.pop().unwrap();
+
+    Candidate {
+        hash,
+        uid: match family {
+            TblFamily::Group => GroupMember::from_uint(val).uid,
+            _ => val.into_u32(),
+        },
+        table: family,
+        resolver: resolve.map(|x| x.to_string()),
+        orig: match family {
+            TblFamily::Resolve => vals.pop().map(|uint| uint.into_u32()),
+            _ => None,
+        },
+        also: vec![],
+    }
+}
+
+
+impl Coverage {
+    /// Normal table `N` holds uids from `N * BUNDLE_LEN + 1`, as many as its
+    /// items (a table cut short holds the head of its range, a compacted one
+    /// the ranges of the tables merged into it)
+    pub(crate) fn of(dbmeta: &DBMeta2) -> Self {
+        let mut ranges: Vec<(u64, u64)> = dbmeta
+            .0
+            .iter()
+            .filter(|(_, meta)| meta.len > 0)
+            .map(|(ty, meta)| {
+                let start = ty.id() as u64 * BUNDLE_LEN as u64 + 1;
+
+                (start, (start + meta.len - 1).min(u32::MAX as u64))
+            })
+            .filter(|(start, _)| *start <= u32::MAX as u64)
+            .collect();
+        ranges.sort_unstable();
+
+        let mut covered: Vec<(u32, u32)> = vec![];
+        for (start, end) in ranges {
+            match covered.last_mut() {
+                Some(last) if start <= last.1 as u64 + 1 => {
+                    last.1 = last.1.max(end as u32);
+                }
+                _ => covered.push((start as u32, end as u32)),
+            }
+        }
+
+        let mut gaps = vec![];
+        let mut nxt = 1u64;
+        for (start, end) in covered.iter() {
+            if (*start as u64) > nxt {
+                gaps.push((nxt as u32, start - 1));
+            }
+            nxt = *end as u64 + 1;
+        }
+        if nxt <= u32::MAX as u64 {
+            gaps.push((nxt as u32, u32::MAX));
+        }
+
+        Self { covered, gaps }
+    }
+
+    pub fn contains(&self, uid: u32) -> bool {
+        self.covered
+            .iter()
+            .any(|(start, end)| *start <= uid && uid <= *end)
+    }
+
+    /// `1..=102400000 204800001..=307200000`
+    pub fn covered_str(&self) -> String {
+        let ranges: Vec<String> = self
+            .covered
+            .iter()
+            .map(|(start, end)| format!("{}..={}", start, end))
+            .collect();
+
+        ranges.join(" ")
+    }
+}
+
+/// `1_228_800_000`
+fn group_digits(x: u32) -> String {
+    let s = x.to_string();
+    let mut res = String::new();
+
+    for (i, c) in s.chars().enumerate() {
+        if i > 0 && (s.len() - i).is_multiple_of(3) {
+            res.push('_');
+        }
+        res.push(c);
+    }
+
+    res
+}
+
+fn ranges_str(ranges: &[(u32, u32)]) -> String {
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|(start, end)| {
+            format!("{}..={}", group_digits(*start), group_digits(*end))
+        })
+        .collect();
+
+    ranges.join(", ")
+}
+
+/// What an empty answer means: `no preimage in uids 1..=1_228_800_000;
+/// ranges above not generated`
+impl Display for Coverage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.covered.is_empty() {
+            return write!(f, "no uid ranges generated");
+        }
+
+        write!(f, "no preimage in uids {}", ranges_str(&self.covered))?;
+
+        match self.gaps.as_slice() {
+            [] => Ok(()),
+            [(start, u32::MAX)] if *start == self.covered.last().unwrap().1 + 1 => {
+                write!(f, "; ranges above not generated")
+            }
+            gaps => write!(f, "; not generated {}", ranges_str(gaps)),
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        fs::{create_dir_all, remove_dir_all, write, File},
+        io::{Seek, SeekFrom, Write},
+    };
+
+    use m6coll::ToLeBytes;
+
+    use super::{Catalog, Coverage, QueryPlan};
+    use crate::{
+        data::{DBMeta2, TblMeta, UInt},
+        gendata::{DBWriter, BILI2_HASHUID_TBL_META, BUNDLE_LEN},
+        hash::hash_bili2,
+        query::{KeyRange, Provenance},
+        shell::{tbl_override_path, TblFamily, TblTy},
+    };
+
+    #[test]
+    fn test_plan_restrict() {
+        let plan = QueryPlan::default()
+            .restrict(&[TblFamily::Resolve, TblFamily::Normal]);
+
+        assert_eq!(plan.families, vec![TblFamily::Normal, TblFamily::Resolve]);
+
+        let plan = QueryPlan::default().restrict(&[]);
+        assert_eq!(plan.families.len(), 5);
+        assert!(!plan.families.contains(&TblFamily::Dup));
+
+        let plan = QueryPlan::default().restrict(&[TblFamily::Dup, TblFamily::Override]);
+        assert_eq!(plan.families, vec![TblFamily::Override, TblFamily::Dup]);
+    }
+
+    #[test]
+    fn test_coverage() {
+        let meta = |len| TblMeta {
+            len,
+            keysize: 4,
+            valsize: 4,
+            valcols: 0,
+        };
+
+        let dbmeta = DBMeta2(
+            (0..12).map(|i| (TblTy::Normal(i), meta(1_0240_0000))).collect(),
+        );
+        let coverage = Coverage::of(&dbmeta);
+
+        assert_eq!(coverage.covered, vec![(1, 1_228_800_000)]);
+        assert_eq!(coverage.gaps, vec![(1_228_800_001, u32::MAX)]);
+        assert_eq!(
+            coverage.to_string(),
+            "no preimage in uids 1..=1_228_800_000; ranges above not generated"
+        );
+
+        let dbmeta = DBMeta2(vec![
+            (TblTy::Normal(0), meta(1_0240_0000)),
+            (TblTy::Normal(2), meta(1000)),
+        ]);
+        let coverage = Coverage::of(&dbmeta);
+
+        assert!(coverage.contains(204_801_000) && !coverage.contains(204_801_001));
+        assert_eq!(
+            coverage.to_string(),
+            "no preimage in uids 1..=102_400_000, 204_800_001..=204_801_000; \
+             not generated 102_400_001..=204_800_000, 204_801_001..=4_294_967_295"
+        );
+        assert_eq!(Coverage::of(&DBMeta2(vec![])).to_string(), "no uid ranges generated");
+
+        // data0 compacted with data1
+        let dbmeta = DBMeta2(vec![(TblTy::Normal(0), meta(2_0480_0000))]);
+        assert_eq!(Coverage::of(&dbmeta).covered, vec![(1, 204_800_000)]);
+    }
+
+    #[test]
+    fn test_locate() {
+        let root = temp_dir().join(format!("hh_locate_{}", std::process::id()));
+        let b = BUNDLE_LEN as u64;
+
+        // (hash, uid) rows of `uids` written at the tail of a table of `len` rows,
+        // the head is a hole (hash 0) as left by a compaction of full tables
+        let write = |id: u32, len: u64, uids: &[u32]| {
+            let mut rows: Vec<(u32, u32)> = uids.iter().map(|uid| (hash_bili2(*uid), *uid)).collect();
+            rows.sort_unstable();
+
+            let meta = TblMeta { len, ..BILI2_HASHUID_TBL_META };
+            let path = TblTy::Normal(id).path_in(&root);
+            create_dir_all(path.parent().unwrap()).unwrap();
+
+            let mut f = File::create(path).unwrap();
+            f.write_all(&meta.to_le_bytes()).unwrap();
+            f.set_len(meta.tbl_bytes()).unwrap();
+            f.seek(SeekFrom::End(-(rows.len() as i64 * 8))).unwrap();
+            for (hash, uid) in rows {
+                f.write_all(&UInt::U32(hash).to_le_bytes()).unwrap();
+                f.write_all(&UInt::U32(uid).to_le_bytes()).unwrap();
+            }
+        };
+
+        // uids 1..=3 but the row of 2 lost
+        write(0, 3, &[1, 3, 7]);
+        // data2 compacted with data3
+        write(2, b + 2, &[(3 * b + 1) as u32, (3 * b + 2) as u32]);
+
+        let catalog = Catalog::load_in(&root);
+        let locate = |uid| catalog.locate(uid).unwrap();
+
+        let loc = locate(3).unwrap();
+        assert_eq!((loc.id, loc.path.as_str(), loc.hash), (0, "data0/db.bin", hash_bili2(3)));
+        assert_eq!(loc.run, vec![(loc.row.unwrap(), 3)]);
+
+        let loc = locate(2).unwrap();
+        assert_eq!((loc.id, loc.row), (0, None));
+        assert!(loc.run.is_empty());
+
+        assert_eq!(locate(4), None);
+        assert_eq!(locate((b + 1) as u32), None);
+
+        let loc = locate((3 * b + 2) as u32).unwrap();
+        assert_eq!(loc.id, 2);
+        assert!(loc.row.unwrap() >= b);
+        assert_eq!(locate((3 * b + 3) as u32), None);
+
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_query_merge() {
+        let root = temp_dir().join(format!("hh_catalog_merge_{}", std::process::id()));
+
+        let write_tbl = |ty: TblTy, uids: &[u32]| {
+            let mut rows: Vec<(u32, u32)> = uids.iter().map(|uid| (hash_bili2(*uid), *uid)).collect();
+            rows.sort_unstable();
+
+            let mut db_writer = DBWriter::init_in(&root, BILI2_HASHUID_TBL_META, ty);
+            let mut tbl_writer = db_writer.nxt_tbl_writer().unwrap();
+            for (hash, uid) in rows {
+                tbl_writer.write_row(UInt::U32(hash), &[UInt::U32(uid)]).unwrap();
+            }
+            tbl_writer.finish().unwrap();
+        };
+
+        write_tbl(TblTy::Normal(0), &[1, 7]);
+        write_tbl(TblTy::Import(0), &[7, 9]);
+
+        let catalog = Catalog::load_in(&root);
+        let plan = QueryPlan::default().restrict(&[TblFamily::Normal, TblFamily::Import]);
+        let cands = catalog.query(&plan, &[hash_bili2(7), hash_bili2(9)]).unwrap();
+
+        assert_eq!(cands[0].len(), 1);
+        assert_eq!((cands[0][0].uid, cands[0][0].table), (7, TblFamily::Normal));
+        assert_eq!(cands[0][0].also, vec![Provenance {
+            table: TblFamily::Import,
+            resolver: None,
+            orig: None,
+        }]);
+        assert_eq!(cands[0][0].found_in(TblFamily::Import).unwrap().table, TblFamily::Import);
+
+        assert_eq!(cands[1].len(), 1);
+        assert_eq!((cands[1][0].uid, cands[1][0].table), (9, TblFamily::Import));
+        assert!(cands[1][0].also.is_empty());
+
+        // rejections apply without `override` in the plan, a malformed file
+        // is ignored
+        let ovs = root.join(tbl_override_path());
+        create_dir_all(ovs.parent().unwrap()).unwrap();
+        write(&ovs, format!(
+            "{:08x}\t7\trejected\t0\t\n{:08x}\t100\tconfirmed\t0\t\n",
+            hash_bili2(7),
+            hash_bili2(9)
+        ))
+        .unwrap();
+
+        let catalog = Catalog::load_in(&root);
+        let cands = catalog.query(&plan, &[hash_bili2(7)]).unwrap();
+        assert!(cands[0].is_empty());
+
+        // a range yields confirmed overrides first for their hash
+        let plan = QueryPlan::default()
+            .restrict(&[TblFamily::Override, TblFamily::Normal, TblFamily::Import]);
+        let cands: Vec<(u32, u32, TblFamily)> = catalog
+            .range(&plan, KeyRange::between(0, u32::MAX))
+            .unwrap()
+            .map(|cand| cand.map(|x| (x.hash, x.uid, x.table)).unwrap())
+            .collect();
+
+        assert_eq!(cands.len(), 3);
+        assert!(cands.contains(&(hash_bili2(1), 1, TblFamily::Normal)));
+        assert_eq!(
+            cands.iter().filter(|x| x.0 == hash_bili2(9)).collect::<Vec<_>>(),
+            vec![
+                &(hash_bili2(9), 100, TblFamily::Override),
+                &(hash_bili2(9), 9, TblFamily::Import)
+            ]
+        );
+
+        write(&ovs, "xyz\n").unwrap();
+        let cands = Catalog::load_in(&root).query(&plan, &[hash_bili2(7)]).unwrap();
+        assert_eq!(cands[0].len(), 1);
+
+        remove_dir_all(root).unwrap();
+    }
+}
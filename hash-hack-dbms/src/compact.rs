@@ -0,0 +1,495 @@
+//! Compaction
+//!
+//! K-way merge of tables of a family into one sorted table, holding a row of
+//! each input at a time. The output is written next to the first input,
+//! verified against the inputs (row count, order, a checksum of the rows),
+//! renamed over the first input (see `TblWriter`), then the other inputs are
+//! deleted. The deletions are journaled (`compact.journal`) before the merge
+//! with the row count of the output: a crash before the rename leaves the
+//! inputs as they were, after it `finish_journal` (run at startup by
+//! `clean_temporaries`) deletes what is left of them.
+//!
+//! Normal table `N` holds the uids from `N * BUNDLE_LEN + 1`, so normal inputs
+//! must continue each other for the output to hold the head of the range of
+//! the first.
+//!
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fmt::Display,
+    fs::{read_to_string, remove_dir, remove_file, rename, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::{
+    data::{DBMeta2, TblMeta},
+    gendata::{part_path, sync_parent, Digest, TblReader, TblWriter, BUNDLE_LEN},
+    query::load_family_in,
+    shell::{path2str, TblFamily, TblTy, CWD},
+};
+
+
+const JOURNAL_NAME: &str = "compact.journal";
+
+#[derive(Debug)]
+pub struct CompactReport {
+    pub inputs: Vec<PathBuf>,
+    pub output: PathBuf,
+    pub rows: u64,
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+
+impl Display for CompactReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inputs: Vec<String> = self.inputs.iter().map(|x| path2str(x)).collect();
+
+        write!(
+            f,
+            "{} ({} rows) <- {}",
+            path2str(&self.output),
+            self.rows,
+            inputs.join(" ")
+        )
+    }
+}
+
+
+/// Tables of `family` under `root`
+pub(crate) fn family_dbmeta(root: &Path, family: TblFamily) -> Result<DBMeta2, io::Error> {
+    match family {
+        TblFamily::Override => Err(invalid_input(
+            "overrides are not a table".to_string(),
+        )),
+        _ => Ok(load_family_in(root, family)),
+    }
+}
+
+/// Each normal table starts where the one before ends
+fn check_continuous(tbls: &[(TblTy, TblMeta)]) -> Result<(), io::Error> {
+    for pair in tbls.windows(2) {
+        let (prev, prev_meta) = pair[0];
+        let (ty, _) = pair[1];
+        let end = prev.id() as u64 * BUNDLE_LEN as u64 + prev_meta.len;
+
+        if ty.id() as u64 * BUNDLE_LEN as u64 != end {
+            return Err(invalid_input(format!(
+                "{} does not continue {} (uids up to {})",
+                path2str(&ty.pathbuf()),
+                path2str(&prev.pathbuf()),
+                end
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Merge the sorted `inputs` (same row layout) into a table at `output`
+pub(crate) fn merge_tables(
+    inputs: &[(TblMeta, PathBuf)],
+    output: PathBuf,
+    pb: &ProgressBar,
+) -> Result<(TblMeta, Digest), io::Error> {
+    let first = inputs[0].0;
+
+    for (meta, path) in inputs.iter() {
+        if (meta.keysize, meta.valsize, meta.valcols)
+            != (first.keysize, first.valsize, first.valcols)
+        {
+            return Err(invalid_input(format!(
+                "{}: row layout differs from {}",
+                path.display(),
+                inputs[0].1.display()
+            )));
+        }
+    }
+
+    let meta = TblMeta {
+        len: inputs.iter().map(|(meta, _)| meta.len).sum(),
+        ..first
+    };
+
+    let mut readers = vec![];
+    let mut heap = BinaryHeap::with_capacity(inputs.len());
+    let mut digest = Digest::default();
+
+    // a truncated input fails the merge instead of ending its rows early
+    let read = |reader: &mut TblReader, i: usize| {
+        reader.try_read_item().map_err(|err| {
+            invalid_data(format!("{}: {}", inputs[i].1.display(), err))
+        })
+    };
+
+    for (i, (meta, path)) in inputs.iter().enumerate() {
+        let mut reader = TblReader::open(*meta, path)?;
+
+        if let Some(item) = read(&mut reader, i)? {
+            digest.add(reader.item_bytes());
+            heap.push(Reverse((item.0, reader.vals(), i)));
+        }
+        readers.push(reader);
+    }
+
+    let mut writer = TblWriter::create(meta, output.clone())?;
+    let mut cnt = 0u64;
+
+    while let Some(Reverse((key, vals, i))) = heap.pop() {
+        writer.write_row(key, &vals)?;
+
+        if let Some(item) = read(&mut readers[i], i)? {
+            digest.add(readers[i].item_bytes());
+            heap.push(Reverse((item.0, readers[i].vals(), i)));
+        }
+
+        cnt += 1;
+        if cnt.is_multiple_of(1000) {
+            pb.set_position(cnt);
+        }
+    }
+
+    if writer.digest() != digest {
+        return Err(invalid_data(format!(
+            "{}: rows written differ from the inputs",
+            output.display()
+        )));
+    }
+    writer.finish()?;
+
+    Ok((meta, digest))
+}
+
+/// Inputs of a compaction still to delete once `target` holds `len` rows,
+/// see the module doc
+struct Journal {
+    target: TblTy,
+    len: u64,
+    inputs: Vec<TblTy>,
+}
+
+impl Journal {
+    /// `<target path>\t<len>` then a path per line, relative to the root
+    fn save(&self, root: &Path) -> Result<(), io::Error> {
+        let path = root.join(JOURNAL_NAME);
+        let mut f = File::create(part_path(&path))?;
+
+        writeln!(f, "{}\t{}", path2str(&self.target.pathbuf()), self.len)?;
+        for ty in self.inputs.iter() {
+            writeln!(f, "{}", path2str(&ty.pathbuf()))?;
+        }
+        f.sync_all()?;
+
+        rename(part_path(&path), &path)?;
+        sync_parent(&path)
+    }
+
+    fn load(root: &Path) -> Result<Option<Self>, io::Error> {
+        let path = root.join(JOURNAL_NAME);
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let s = read_to_string(&path)?;
+        let invalid = || invalid_data(format!("{}: invalid journal", path.display()));
+        let ty_of = |s: &str| TblTy::of_path(Path::new(s)).ok_or_else(invalid);
+
+        let mut lines = s.lines();
+        let (target, len) = lines
+            .next()
+            .and_then(|line| line.split_once('\t'))
+            .ok_or_else(invalid)?;
+
+        Ok(Some(Self {
+            target: ty_of(target)?,
+            len: len.parse().map_err(|_| invalid())?,
+            inputs: lines.map(ty_of).collect::<Result<_, _>>()?,
+        }))
+    }
+
+    /// Delete the inputs still there, paths relative to the root
+    fn delete_inputs(&self, root: &Path) -> Result<Vec<PathBuf>, io::Error> {
+        let mut deleted = vec![];
+
+        for ty in self.inputs.iter() {
+            let path = ty.path_in(root);
+
+            if path.exists() {
+                remove_file(&path)?;
+                deleted.push(ty.pathbuf());
+            }
+
+            if let TblTy::Normal(_) = ty {
+                // other files in it are not ours to delete
+                let _ = remove_dir(path.parent().unwrap());
+            }
+        }
+
+        Ok(deleted)
+    }
+}
+
+/// Finish a compaction interrupted after its output was renamed in place,
+/// the inputs deleted
+pub(crate) fn finish_journal(root: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let part = part_path(&root.join(JOURNAL_NAME));
+    if part.exists() {
+        remove_file(part)?;
+    }
+
+    let journal = match Journal::load(root)? {
+        Some(x) => x,
+        None => return Ok(vec![]),
+    };
+
+    let target = journal.target.path_in(root);
+    let merged = target.exists() && journal.target.load_meta_in(root)?.len == journal.len;
+
+    let deleted = if merged {
+        journal.delete_inputs(root)?
+    } else {
+        vec![]
+    };
+    remove_file(root.join(JOURNAL_NAME))?;
+
+    Ok(deleted)
+}
+
+/// Merge the tables of `family` with `ids` (all if empty) into the first of
+/// them
+pub fn compact_bili2(
+    family: TblFamily,
+    ids: &[u32],
+) -> Result<CompactReport, io::Error> {
+    compact_in(Path::new(CWD), family, ids)
+}
+
+/// `compact_bili2` in the database under `root`
+fn compact_in(
+    root: &Path,
+    family: TblFamily,
+    ids: &[u32],
+) -> Result<CompactReport, io::Error> {
+    let tbls: Vec<(TblTy, TblMeta)> = family_dbmeta(root, family)?
+        .0
+        .into_iter()
+        .filter(|(ty, _)| ids.is_empty() || ids.contains(&ty.id()))
+        .collect();
+
+    if tbls.len() < 2 {
+        return Err(invalid_input(format!(
+            "{} {:?} tables selected, nothing to compact",
+            tbls.len(),
+            family
+        )));
+    }
+    if family == TblFamily::Normal {
+        check_continuous(&tbls)?;
+    }
+
+    let inputs: Vec<(TblMeta, PathBuf)> = tbls
+        .iter()
+        .map(|(ty, meta)| (*meta, ty.path_in(root)))
+        .collect();
+    let target = inputs[0].1.clone();
+
+    let journal = Journal {
+        target: tbls[0].0,
+        len: inputs.iter().map(|(meta, _)| meta.len).sum(),
+        inputs: tbls[1..].iter().map(|(ty, _)| *ty).collect(),
+    };
+    journal.save(root)?;
+
+    let pb = ProgressBar::new(inputs.iter().map(|(meta, _)| meta.len).sum());
+    pb.set_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner:.green} [{elapsed_precise}] {pos:>7}/{len} {msg}"),
+    );
+
+    pb.set_message("merge");
+    // on error the journal is left to `finish_journal`, the rename may be done
+    let (meta, _) = merge_tables(&inputs, target, &pb)?;
+
+    journal.delete_inputs(root)?;
+    remove_file(root.join(JOURNAL_NAME))?;
+    pb.finish_with_message("done");
+
+    Ok(CompactReport {
+        inputs: tbls.iter().map(|(ty, _)| ty.pathbuf()).collect(),
+        output: tbls[0].0.pathbuf(),
+        rows: meta.len,
+    })
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        fs::{create_dir_all, remove_dir_all, OpenOptions},
+        io::{self, Seek, SeekFrom, Write},
+    };
+
+    use indicatif::ProgressBar;
+
+    use super::{compact_in, finish_journal, merge_tables, Journal, JOURNAL_NAME};
+    use crate::{
+        data::{TblMeta, UInt},
+        gendata::{
+            check_digest, verify_table, DBWriter, Digest, TblReader, TblWriter,
+            BILI2_HASHUID_TBL_META, DIGEST_LEN,
+        },
+        query::load_family_in,
+        shell::{TblFamily, TblTy},
+    };
+
+    /// (hash, uid) rows of the import tables under `root`, table by table
+    fn import_rows(root: &std::path::Path) -> Vec<Vec<(u32, u32)>> {
+        load_family_in(root, TblFamily::Import)
+            .0
+            .iter()
+            .map(|(ty, meta)| {
+                assert_eq!(check_digest(&ty.path_in(root), *meta).unwrap(), Some(true));
+
+                let mut reader = TblReader::open(*meta, &ty.path_in(root)).unwrap();
+                let mut rows = vec![];
+                while let Some(item) = reader.try_read_item().unwrap() {
+                    rows.push((item.0.into_u32(), item.1.into_u32()));
+                }
+                rows
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_tables() {
+        let dir = temp_dir().join(format!("hh_compact_{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+
+        let meta = |len| TblMeta {
+            len,
+            keysize: 4,
+            valsize: 4,
+            valcols: 2,
+        };
+
+        let tbls = [
+            vec![(1u32, 5u32, 10u32), (4, 1, 11), (9, 1, 12)],
+            vec![(2, 0, 20), (4, 0, 21)],
+            vec![],
+        ];
+        let mut inputs = vec![];
+
+        for (i, rows) in tbls.iter().enumerate() {
+            let path = dir.join(format!("db_{}.bin", i));
+            let mut w = TblWriter::create(meta(rows.len() as u64), path.clone()).unwrap();
+
+            for (key, orig, uid) in rows.iter() {
+                w.write_row(UInt::U32(*key), &[UInt::U32(*orig), UInt::U32(*uid)])
+                    .unwrap();
+            }
+            w.finish().unwrap();
+
+            inputs.push((meta(rows.len() as u64), path));
+        }
+
+        let out = dir.join("out.bin");
+        let (merged, digest) =
+            merge_tables(&inputs, out.clone(), &ProgressBar::hidden()).unwrap();
+        assert_eq!(merged.len, 5);
+        assert_eq!(digest.rows, 5);
+        verify_table(&out, merged, digest).unwrap();
+        assert_eq!(check_digest(&out, merged).unwrap(), Some(true));
+
+        let mut reader = TblReader::open(merged, &out).unwrap();
+        let mut rows = vec![];
+        while let Some(item) = reader.try_read_item().unwrap() {
+            rows.push((item.0.into_u32(), reader.vals()[0].into_u32(), item.1.into_u32()));
+        }
+        assert_eq!(rows, vec![(1, 5, 10), (2, 0, 20), (4, 0, 21), (4, 1, 11), (9, 1, 12)]);
+
+        assert!(verify_table(&out, merged, Digest::default()).is_err());
+
+        // a row changed on disk
+        let mut f = OpenOptions::new().write(true).open(&out).unwrap();
+        f.seek(SeekFrom::End(-4 - DIGEST_LEN as i64)).unwrap();
+        f.write_all(&13u32.to_le_bytes()).unwrap();
+        drop(f);
+        assert!(verify_table(&out, merged, digest).is_err());
+        assert_eq!(check_digest(&out, merged).unwrap(), Some(false));
+
+        let other = (TblMeta { valcols: 0, ..meta(0) }, dir.join("db_2.bin"));
+        assert!(merge_tables(&[inputs[0].clone(), other], dir.join("x.bin"), &ProgressBar::hidden()).is_err());
+
+        // an input cut short after its header claimed the rows
+        let f = OpenOptions::new().write(true).open(&inputs[0].1).unwrap();
+        f.set_len(16 + 12 + 6).unwrap();
+        drop(f);
+        let res = merge_tables(&inputs, dir.join("x.bin"), &ProgressBar::hidden());
+        assert_eq!(res.err().map(|err| err.kind()), Some(io::ErrorKind::InvalidData));
+        assert!(!dir.join("x.bin").exists());
+
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compact() {
+        let root = temp_dir().join(format!("hh_compact_in_{}", std::process::id()));
+
+        let write = |id: u32, tbls: &[&[(u32, u32)]]| {
+            let mut db = DBWriter::init_in(&root, BILI2_HASHUID_TBL_META, TblTy::Import(id));
+            for rows in tbls {
+                let mut w = db.nxt_tbl_writer().unwrap();
+                for (key, uid) in rows.iter() {
+                    w.write_row(UInt::U32(*key), &[UInt::U32(*uid)]).unwrap();
+                }
+                w.finish().unwrap();
+            }
+        };
+        write(0, &[&[(3, 30), (7, 70)], &[(1, 10), (7, 71)], &[(5, 50)]]);
+
+        let rep = compact_in(&root, TblFamily::Import, &[]).unwrap();
+        assert_eq!(rep.rows, 5);
+        assert_eq!(rep.output, TblTy::Import(0).pathbuf());
+        assert_eq!(rep.inputs.len(), 3);
+        assert_eq!(import_rows(&root), vec![vec![(1, 10), (3, 30), (5, 50), (7, 70), (7, 71)]]);
+        assert!(!TblTy::Import(1).path_in(&root).exists());
+        assert!(!root.join(JOURNAL_NAME).exists());
+
+        assert!(compact_in(&root, TblFamily::Import, &[]).is_err());
+        assert!(compact_in(&root, TblFamily::Override, &[]).is_err());
+
+        // crashed after the output was renamed in place, table 1 left behind
+        write(1, &[&[(9, 90)]]);
+        let journal = |len| Journal {
+            target: TblTy::Import(0),
+            len,
+            inputs: vec![TblTy::Import(1)],
+        };
+        journal(5).save(&root).unwrap();
+        assert_eq!(finish_journal(&root).unwrap(), vec![TblTy::Import(1).pathbuf()]);
+        assert_eq!(import_rows(&root).len(), 1);
+        assert!(!root.join(JOURNAL_NAME).exists());
+
+        // crashed before the rename, the inputs are kept
+        write(1, &[&[(9, 90)]]);
+        journal(6).save(&root).unwrap();
+        assert!(finish_journal(&root).unwrap().is_empty());
+        assert_eq!(import_rows(&root).len(), 2);
+        assert!(!root.join(JOURNAL_NAME).exists());
+
+        remove_dir_all(root).unwrap();
+    }
+}
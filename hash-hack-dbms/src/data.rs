@@ -1,8 +1,14 @@
 //! Table Scheme
 //!
-//! TblMeta(8 + 4 + 4 = 16)
-//! TblItem ...
+//! TblMeta(8 + 4 + 2 + 2 = 16)
+//! TblItem(key + valsize * valcols) ...
 //!
+//! valcols = 0 is the plain (key, val) scheme (read as 1 column),
+//! the uid is always the last column.
+//!
+//! Resolve table item: resolved key(4) + original hash(4) + uid(4)
+//!
+//! Group table item: hash(4) + GroupMember(8), GroupMember = size << 32 | uid
 //!
 
 use std::{mem::size_of, fmt::Display};
@@ -29,7 +35,9 @@ pub(crate) struct DBMeta2(
 pub struct TblMeta {
     pub len: u64,
     pub keysize: u32,
-    pub valsize: u32
+    /// size of each value column
+    pub valsize: u16,
+    pub valcols: u16,
 }
 
 
@@ -38,17 +46,28 @@ pub struct Tbl {
     pub data: *mut u8
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 #[repr(C)]
 pub enum UInt {
     U32(u32),
     U64(u64)
 }
 
+/// Member of a collision group, every uid sharing a hash with `size` members
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct GroupMember {
+    pub uid: u32,
+    pub size: u32,
+}
+
 
 impl TblMeta {
 
     /// From raw head
+    ///
+    /// # Safety
+    ///
+    /// `raw` must point to at least `size_of::<TblMeta>()` readable bytes.
     pub unsafe fn from_raw(raw: *mut u8) -> Self {
 
         let mut p = raw;
@@ -59,22 +78,59 @@ impl TblMeta {
         let keysize = *(p as *mut u32);
         p = p.add(size_of::<u32>());
 
-        let valsize = *(p as *mut u32);
+        let valsize = *(p as *mut u16);
+        p = p.add(size_of::<u16>());
+
+        let valcols = *(p as *mut u16);
 
         Self {
             len,
             keysize,
             valsize,
+            valcols,
         }
     }
 
+    #[inline]
+    pub fn val_cols(&self) -> u32 {
+        self.valcols.max(1) as u32
+    }
+
+    /// Bytes of a row
+    #[inline]
+    pub fn unit_len(&self) -> u32 {
+        self.keysize + self.valsize as u32 * self.val_cols()
+    }
+
     pub fn tbl_bytes(&self) -> u64 {
-        let unit = (self.keysize + self.valsize) as u64;
+        let unit = self.unit_len() as u64;
         let data = unit * self.len;
 
         data + size_of::<Self>() as u64
     }
 
+    /// `tbl_bytes`, `None` if a corrupt header overflows it
+    pub fn checked_tbl_bytes(&self) -> Option<u64> {
+        (self.unit_len() as u64)
+            .checked_mul(self.len)?
+            .checked_add(size_of::<Self>() as u64)
+    }
+
+    /// Value columns of a row
+    pub fn vals_from_slice(&self, row: &[u8]) -> Vec<UInt> {
+        row[self.keysize as usize..]
+            .chunks(self.valsize as usize)
+            .map(UInt::from_slice)
+            .collect()
+    }
+
+    /// The last value column (uid) of a row
+    pub fn val_from_slice(&self, row: &[u8]) -> UInt {
+        let unit_len = self.unit_len() as usize;
+
+        UInt::from_slice(&row[unit_len - self.valsize as usize..unit_len])
+    }
+
 }
 
 impl ToLeBytes for TblMeta {
@@ -84,7 +140,8 @@ impl ToLeBytes for TblMeta {
 
         arr[0..8].copy_from_slice(&self.len.to_le_bytes());
         arr[8..12].copy_from_slice(&self.keysize.to_le_bytes());
-        arr[12..].copy_from_slice(&self.valsize.to_le_bytes());
+        arr[12..14].copy_from_slice(&self.valsize.to_le_bytes());
+        arr[14..].copy_from_slice(&self.valcols.to_le_bytes());
 
         arr
     }
@@ -92,6 +149,10 @@ impl ToLeBytes for TblMeta {
 
 impl Tbl {
     /// From raw head
+    ///
+    /// # Safety
+    ///
+    /// `raw` must point to a whole table image (header followed by rows).
     pub unsafe fn from_raw(raw: *mut u8) -> Self {
         let meta = TblMeta::from_raw(raw);
         let data = raw.add(size_of::<TblMeta>());  // 16 bytes alignments maybe Itanium spec
@@ -133,6 +194,7 @@ impl Display for DBMeta2 {
 }
 
 
+#[allow(clippy::len_without_is_empty)]
 impl UInt {
     pub fn len(&self) -> usize {
         match self {
@@ -159,6 +221,55 @@ impl UInt {
             _ => unreachable!("{:#?}", self),
         }
     }
+
+    pub fn into_u64(self) -> u64 {
+        match self {
+            UInt::U64(v) => v,
+            _ => unreachable!("{:#?}", self),
+        }
+    }
+
+    /// Little endian bytes appended to `buf`
+    pub fn extend_le(self, buf: &mut Vec<u8>) {
+        match self {
+            UInt::U32(v) => buf.extend_from_slice(&v.to_le_bytes()),
+            UInt::U64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        }
+    }
+
+    /// Either width as u64
+    pub fn widen(self) -> u64 {
+        match self {
+            UInt::U32(v) => v as u64,
+            UInt::U64(v) => v,
+        }
+    }
+}
+
+impl ToLeBytes for UInt {
+    fn to_le_bytes(&self) -> Array<u8> {
+        match self {
+            // m6coll's `ToLeBytes for u64` truncates, use the std one
+            UInt::U32(v) => Array::copy_from_slice(&u32::to_le_bytes(*v)),
+            UInt::U64(v) => Array::copy_from_slice(&u64::to_le_bytes(*v)),
+        }
+    }
+}
+
+
+impl GroupMember {
+    pub fn from_uint(uint: UInt) -> Self {
+        let v = uint.into_u64();
+
+        Self {
+            uid: v as u32,
+            size: (v >> 32) as u32,
+        }
+    }
+
+    pub fn to_uint(&self) -> UInt {
+        UInt::U64((self.size as u64) << 32 | self.uid as u64)
+    }
 }
 
 
@@ -169,7 +280,9 @@ impl UInt {
 mod tests {
     use std::mem::size_of;
 
-    use super::TblMeta;
+    use m6coll::ToLeBytes;
+
+    use super::{GroupMember, TblMeta, UInt};
 
 
     #[test]
@@ -177,5 +290,34 @@ mod tests {
         assert_eq!(16, size_of::<TblMeta>())
     }
 
+    #[test]
+    fn test_tblmeta() {
+        let meta = TblMeta { len: 3, keysize: 4, valsize: 4, valcols: 2 };
+        let mut raw = meta.to_le_bytes();
+        let meta = unsafe { TblMeta::from_raw(raw.as_ptr()) };
+
+        assert_eq!(meta.unit_len(), 12);
+        assert_eq!(meta.tbl_bytes(), 16 + 36);
+
+        let row = [1u32, 2, 3].map(u32::to_le_bytes).concat();
+        assert_eq!(meta.vals_from_slice(&row), vec![UInt::U32(2), UInt::U32(3)]);
+        assert_eq!(meta.val_from_slice(&row), UInt::U32(3));
+
+        // plain (key, val) header
+        raw[12..].copy_from_slice(&[4, 0, 0, 0]);
+        let meta = unsafe { TblMeta::from_raw(raw.as_mut_ptr()) };
+        assert_eq!(meta.val_cols(), 1);
+        assert_eq!(meta.unit_len(), 8);
+    }
+
+    #[test]
+    fn test_group_member() {
+        let member = GroupMember { uid: 6487381, size: 3 };
+        let uint = member.to_uint();
+
+        assert_eq!(GroupMember::from_uint(uint), member);
+        assert_eq!(UInt::from_slice(&uint.to_le_bytes()[..]), uint);
+    }
+
 
 }
\ No newline at end of file
@@ -0,0 +1,451 @@
+//! Database Diff
+//!
+//! Compare two generated databases family by family, streaming the rows of
+//! every table of a family of each side in key order. How the rows are split
+//! into tables does not matter, only the rows of each key: a key on one side
+//! only, or the values of a key differing between the sides. Resolve tables
+//! are compared resolver by resolver, so a row moving to another resolver's
+//! tables differs.
+//!
+//! Tables of each side are found under its dir, a table missing from one
+//! side has no rows there (its keys are only in the other side), a missing
+//! dir or an unreadable table is an error.
+//!
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    fmt::Display,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde_derive::Serialize;
+
+use crate::{
+    compact::family_dbmeta,
+    data::{DBMeta2, UInt},
+    gendata::{CollisionResolver, TblReader},
+    output::{DiffRecord, Format},
+    shell::TblFamily,
+};
+
+
+/// Families compared, overrides are not tables
+pub const DIFF_FAMILIES: [TblFamily; 5] = [
+    TblFamily::Normal,
+    TblFamily::Dup,
+    TblFamily::Group,
+    TblFamily::Import,
+    TblFamily::Resolve,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffKind {
+    OnlyA,
+    OnlyB,
+    /// the key is on both sides with other values
+    Differ,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DiffSummary {
+    pub family: Option<TblFamily>,
+    /// of the Resolve tables compared
+    pub resolver: Option<String>,
+    pub rows_a: u64,
+    pub rows_b: u64,
+    /// keys of each kind
+    pub only_a: u64,
+    pub only_b: u64,
+    pub differ: u64,
+}
+
+/// K-way merge of the tables of a family, by (key, value columns)
+struct RowMerger {
+    readers: Vec<TblReader>,
+    paths: Vec<PathBuf>,
+    heap: BinaryHeap<Reverse<(UInt, Vec<UInt>, usize)>>,
+}
+
+type Run = (UInt, Vec<Vec<UInt>>);
+
+/// Tables merged into one stream, a family or the Resolve tables of a
+/// resolver
+type Source = (TblFamily, Option<CollisionResolver>);
+
+
+impl RowMerger {
+    /// Tables of `dbmeta` under `root`
+    fn open(root: &Path, dbmeta: &DBMeta2) -> Result<Self, io::Error> {
+        let mut merger = Self {
+            readers: vec![],
+            paths: vec![],
+            heap: BinaryHeap::with_capacity(dbmeta.0.len()),
+        };
+
+        for (i, (ty, meta)) in dbmeta.0.iter().enumerate() {
+            let path = ty.path_in(root);
+
+            merger.readers.push(TblReader::open(*meta, &path)?);
+            merger.paths.push(path);
+            merger.push_next(i)?;
+        }
+
+        Ok(merger)
+    }
+
+    /// Next row of table `i` into the heap, a truncated table is an error
+    /// instead of the end of its rows
+    fn push_next(&mut self, i: usize) -> Result<(), io::Error> {
+        let item = self.readers[i].try_read_item().map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", self.paths[i].display(), err),
+            )
+        })?;
+
+        if let Some(item) = item {
+            self.heap.push(Reverse((item.0, self.readers[i].vals(), i)));
+        }
+
+        Ok(())
+    }
+
+    fn read_row(&mut self) -> Result<Option<(UInt, Vec<UInt>)>, io::Error> {
+        let Reverse((key, vals, i)) = match self.heap.pop() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        self.push_next(i)?;
+
+        Ok(Some((key, vals)))
+    }
+
+    /// Rows of the next key, sorted
+    fn read_run(&mut self) -> Result<Option<Run>, io::Error> {
+        let (key, vals) = match self.read_row()? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let mut run = vec![vals];
+
+        while let Some(Reverse((nxt_key, _, _))) = self.heap.peek() {
+            if *nxt_key != key {
+                break;
+            }
+
+            // just peeked
+            run.push(self.read_row()?.unwrap().1);
+        }
+
+        Ok(Some((key, run)))
+    }
+}
+
+
+impl DiffSummary {
+    pub fn is_same(&self) -> bool {
+        self.only_a == 0 && self.only_b == 0 && self.differ == 0
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.rows_a += other.rows_a;
+        self.rows_b += other.rows_b;
+        self.only_a += other.only_a;
+        self.only_b += other.only_b;
+        self.differ += other.differ;
+    }
+}
+
+impl Display for DiffSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let family = match (self.family, &self.resolver) {
+            (Some(family), Some(resolver)) => format!("{:?}:{}", family, resolver),
+            (Some(family), None) => format!("{:?}", family),
+            (None, _) => "total".to_string(),
+        };
+
+        write!(
+            f,
+            "{:<14} rows {} / {}, keys only in a {}, only in b {}, differing {}",
+            family, self.rows_a, self.rows_b, self.only_a, self.only_b, self.differ
+        )
+    }
+}
+
+
+/// Rows of `a` not in `b`, both sorted
+fn minus(a: &[Vec<UInt>], b: &[Vec<UInt>]) -> Vec<Vec<UInt>> {
+    let mut res = vec![];
+    let mut j = 0;
+
+    for row in a.iter() {
+        while j < b.len() && b[j] < *row {
+            j += 1;
+        }
+
+        if j < b.len() && b[j] == *row {
+            j += 1;
+        } else {
+            res.push(row.clone());
+        }
+    }
+
+    res
+}
+
+/// `DIFF_FAMILIES`, Resolve split by resolver
+fn diff_sources() -> Vec<Source> {
+    DIFF_FAMILIES
+        .iter()
+        .flat_map(|family| match family {
+            TblFamily::Resolve => CollisionResolver::ALL
+                .iter()
+                .map(|resolve| (*family, Some(*resolve)))
+                .collect(),
+            _ => vec![(*family, None)],
+        })
+        .collect()
+}
+
+fn open_mergers(dir: &Path, sources: &[Source]) -> Result<Vec<RowMerger>, io::Error> {
+    if !dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{}: no such dir", dir.display()),
+        ));
+    }
+
+    sources
+        .iter()
+        .map(|source| {
+            let dbmeta = match source {
+                (_, Some(resolve)) => resolve.load_dbmeta_in(dir),
+                (family, None) => family_dbmeta(dir, *family)?,
+            };
+
+            RowMerger::open(dir, &dbmeta)
+        })
+        .collect()
+}
+
+fn write_diff<W: Write>(w: &mut W, fmt: Format, rec: &DiffRecord) -> Result<(), io::Error> {
+    if fmt == Format::Plain {
+        return writeln!(w, "{}", rec.plain());
+    }
+
+    fmt.write_record(w, rec)
+}
+
+/// Differing keys of two streams of runs
+fn diff_family<W: Write, A, B>(
+    w: &mut W,
+    fmt: Format,
+    (family, resolver): Source,
+    mut next_a: A,
+    mut next_b: B,
+) -> Result<DiffSummary, io::Error>
+where
+    A: FnMut() -> Result<Option<Run>, io::Error>,
+    B: FnMut() -> Result<Option<Run>, io::Error>,
+{
+    let mut summary = DiffSummary {
+        family: Some(family),
+        resolver: resolver.map(|x| x.to_string()),
+        ..Default::default()
+    };
+    let mut a = next_a()?;
+    let mut b = next_b()?;
+
+    loop {
+        let ord = match (&a, &b) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((ka, _)), Some((kb, _))) => ka.cmp(kb),
+        };
+
+        match ord {
+            Ordering::Less => {
+                let (key, rows) = a.take().unwrap();
+                summary.rows_a += rows.len() as u64;
+                summary.only_a += 1;
+
+                let rec = DiffRecord::of((family, resolver), key, DiffKind::OnlyA, &rows, &[]);
+                write_diff(w, fmt, &rec)?;
+
+                a = next_a()?;
+            }
+            Ordering::Greater => {
+                let (key, rows) = b.take().unwrap();
+                summary.rows_b += rows.len() as u64;
+                summary.only_b += 1;
+
+                let rec = DiffRecord::of((family, resolver), key, DiffKind::OnlyB, &[], &rows);
+                write_diff(w, fmt, &rec)?;
+
+                b = next_b()?;
+            }
+            Ordering::Equal => {
+                let (key, rows_a) = a.take().unwrap();
+                let (_, rows_b) = b.take().unwrap();
+                summary.rows_a += rows_a.len() as u64;
+                summary.rows_b += rows_b.len() as u64;
+
+                let only_a = minus(&rows_a, &rows_b);
+                let only_b = minus(&rows_b, &rows_a);
+
+                if !only_a.is_empty() || !only_b.is_empty() {
+                    summary.differ += 1;
+
+                    let rec =
+                        DiffRecord::of((family, resolver), key, DiffKind::Differ, &only_a, &only_b);
+                    write_diff(w, fmt, &rec)?;
+                }
+
+                a = next_a()?;
+                b = next_b()?;
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Write the differing keys of the databases in dirs `a` and `b`, summary
+/// of each family (of each resolver for Resolve) then the total
+pub fn diff_dbs<W: Write>(
+    w: &mut W,
+    fmt: Format,
+    a: &Path,
+    b: &Path,
+) -> Result<Vec<DiffSummary>, io::Error> {
+    let sources = diff_sources();
+    let mergers_a = open_mergers(a, &sources)?;
+    let mergers_b = open_mergers(b, &sources)?;
+
+    fmt.write_header::<_, DiffRecord>(w)?;
+
+    let mut res = vec![];
+    let mut total = DiffSummary::default();
+
+    for ((source, mut ma), mut mb) in sources.into_iter().zip(mergers_a).zip(mergers_b) {
+        let summary = diff_family(w, fmt, source, || ma.read_run(), || mb.read_run())?;
+
+        total.add(&summary);
+        res.push(summary);
+    }
+    res.push(total);
+
+    Ok(res)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env::{current_dir, temp_dir},
+        fs::{create_dir_all, remove_dir_all, remove_file},
+        io,
+        path::Path,
+    };
+
+    use super::{diff_dbs, diff_family, Run};
+    use crate::{
+        data::{TblMeta, UInt},
+        gendata::{CollisionResolver, TblWriter},
+        output::Format,
+        shell::{TblFamily, TblTy},
+    };
+
+    fn runs(rows: &[(u32, &[u32])]) -> impl FnMut() -> Result<Option<Run>, io::Error> {
+        let mut runs: Vec<Run> = rows
+            .iter()
+            .map(|(key, uids)| {
+                (UInt::U32(*key), uids.iter().map(|uid| vec![UInt::U32(*uid)]).collect())
+            })
+            .collect();
+        runs.reverse();
+
+        move || Ok(runs.pop())
+    }
+
+    #[test]
+    fn test_diff_family() {
+        let a = runs(&[(1, &[10]), (3, &[30, 31]), (5, &[50]), (7, &[70])]);
+        let b = runs(&[(3, &[31, 32]), (5, &[50]), (6, &[60]), (7, &[70, 71])]);
+
+        let mut buf = vec![];
+        let summary = diff_family(&mut buf, Format::Csv, (TblFamily::Normal, None), a, b).unwrap();
+
+        assert_eq!(
+            (summary.rows_a, summary.rows_b, summary.only_a, summary.only_b, summary.differ),
+            (5, 6, 1, 1, 2)
+        );
+        assert!(!summary.is_same());
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "Normal,,00000001,only_a,10,\n\
+             Normal,,00000003,differ,30,32\n\
+             Normal,,00000006,only_b,,60\n\
+             Normal,,00000007,differ,,71\n"
+        );
+
+        let same = diff_family(&mut vec![], Format::Csv, (TblFamily::Dup, None), runs(&[(1, &[2])]), runs(&[(1, &[2])]));
+        assert!(same.unwrap().is_same());
+    }
+
+    /// Table `ty` under `root` of (key, value columns)
+    fn write_tbl(root: &Path, ty: TblTy, rows: &[(u32, &[u32])]) {
+        let meta = TblMeta {
+            len: rows.len() as u64,
+            keysize: 4,
+            valsize: 4,
+            valcols: rows.first().map(|(_, vals)| vals.len() as u16).unwrap_or(1),
+        };
+        let path = ty.path_in(root);
+        create_dir_all(path.parent().unwrap()).unwrap();
+
+        let mut w = TblWriter::create(meta, path).unwrap();
+        for (key, vals) in rows {
+            let vals: Vec<UInt> = vals.iter().map(|x| UInt::U32(*x)).collect();
+            w.write_row(UInt::U32(*key), &vals).unwrap();
+        }
+        w.finish().unwrap();
+    }
+
+    #[test]
+    fn test_diff_dbs() {
+        let dir = temp_dir().join(format!("hh_diff_{}", std::process::id()));
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        write_tbl(&a, TblTy::Normal(0), &[(1, &[1]), (7, &[2]), (9, &[3])]);
+        write_tbl(&b, TblTy::Normal(0), &[(1, &[1]), (8, &[2]), (9, &[3])]);
+        // (rehashed key, orig, uid)
+        write_tbl(&a, TblTy::Resolve(0, CollisionResolver::Rehash), &[(5, &[3, 4])]);
+
+        let cwd = current_dir().unwrap();
+        let mut buf = vec![];
+        let res = diff_dbs(&mut buf, Format::Csv, &a, &b).unwrap();
+        let total = res.last().unwrap();
+
+        assert_eq!(current_dir().unwrap(), cwd);
+        assert_eq!((total.rows_a, total.rows_b, total.only_a, total.only_b), (4, 3, 2, 1));
+
+        let resolve = res.iter().find(|x| x.family == Some(TblFamily::Resolve)).unwrap();
+        assert_eq!((resolve.resolver.as_deref(), resolve.only_a), (Some("Rehash"), 1));
+        assert!(String::from_utf8(buf)
+            .unwrap()
+            .ends_with("Resolve,Rehash,00000005,only_a,3/4,\n"));
+
+        // a table missing from one side has no rows there, a missing dir is
+        // an error
+        remove_file(TblTy::Normal(0).path_in(&b)).unwrap();
+        let res = diff_dbs(&mut vec![], Format::Csv, &a, &b).unwrap();
+        assert_eq!(res.last().map(|t| (t.rows_b, t.only_a)), Some((0, 4)));
+        assert!(diff_dbs(&mut vec![], Format::Csv, &a, &dir.join("none")).is_err());
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
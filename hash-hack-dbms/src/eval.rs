@@ -0,0 +1,250 @@
+//! Ground-truth Evaluation
+//!
+//! Score each table family against known `hashid,uid` pairs
+//! (e.g. `test_data/bili2/bullet.csv`), rows with a blank uid are unlabeled.
+//!
+
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use serde_derive::Serialize;
+
+use crate::{
+    catalog::Catalog,
+    data::{DBMeta2, UInt},
+    hash::parse_hash,
+    query::{default_threads, query_db_many_rows_explain},
+};
+
+
+pub struct Truth {
+    pub hash: u32,
+    pub uid: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// the known uid is the only candidate
+    Hit,
+    /// the known uid is one of several candidates
+    Ambiguous,
+    Miss,
+    /// no known uid, only whether anything was found
+    Unlabeled(bool),
+}
+
+#[derive(Default, Serialize)]
+pub struct SourceReport {
+    pub name: String,
+    pub hit: usize,
+    pub ambiguous: usize,
+    pub miss: usize,
+    pub unlabeled_found: usize,
+    pub unlabeled: usize,
+}
+
+pub struct EvalReport(pub Vec<SourceReport>);
+
+
+impl Outcome {
+    pub fn score(truth: &Truth, candidates: &[u32]) -> Self {
+        let uid = if let Some(uid) = truth.uid {
+            uid
+        } else {
+            return Self::Unlabeled(!candidates.is_empty());
+        };
+
+        if !candidates.contains(&uid) {
+            Self::Miss
+        } else if candidates.iter().all(|x| *x == uid) {
+            Self::Hit
+        } else {
+            Self::Ambiguous
+        }
+    }
+}
+
+impl SourceReport {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Hit => self.hit += 1,
+            Outcome::Ambiguous => self.ambiguous += 1,
+            Outcome::Miss => self.miss += 1,
+            Outcome::Unlabeled(found) => {
+                self.unlabeled += 1;
+
+                if found {
+                    self.unlabeled_found += 1;
+                }
+            }
+        }
+    }
+
+    pub fn labeled(&self) -> usize {
+        self.hit + self.ambiguous + self.miss
+    }
+
+    fn rate(&self, n: usize) -> f64 {
+        if self.labeled() == 0 {
+            0.0
+        } else {
+            n as f64 * 100.0 / self.labeled() as f64
+        }
+    }
+}
+
+
+impl Display for EvalReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:<12} {:>8} {:>15} {:>15} {:>15} {:>10}",
+            "source", "labeled", "hit", "ambiguous", "miss", "unlabeled"
+        )?;
+
+        for rep in self.0.iter() {
+            writeln!(
+                f,
+                "{:<12} {:>8} {:>6} ({:>5.1}%) {:>6} ({:>5.1}%) {:>6} ({:>5.1}%) {:>4}/{:<5}",
+                rep.name,
+                rep.labeled(),
+                rep.hit,
+                rep.rate(rep.hit),
+                rep.ambiguous,
+                rep.rate(rep.ambiguous),
+                rep.miss,
+                rep.rate(rep.miss),
+                rep.unlabeled_found,
+                rep.unlabeled
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Parse `hashid,uid` csv with header, the uid column may be blank
+pub fn parse_truth<R: BufRead>(reader: R) -> Result<Vec<Truth>, io::Error> {
+    let mut res = vec![];
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+
+        if i == 0 || line.is_empty() {
+            continue;
+        }
+
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: invalid row {:?}", i + 1, line),
+            )
+        };
+
+        let mut cols = line.split(',').map(|col| col.trim());
+        let hash = cols.next().ok_or_else(invalid)?;
+        let hash = parse_hash(hash).ok_or_else(invalid)?;
+
+        let uid = match cols.next() {
+            Some(uid) if !uid.is_empty() => {
+                Some(uid.parse::<u32>().map_err(|_| invalid())?)
+            }
+            _ => None,
+        };
+
+        res.push(Truth { hash, uid });
+    }
+
+    Ok(res)
+}
+
+pub fn load_truth(path: &Path) -> Result<Vec<Truth>, io::Error> {
+    parse_truth(BufReader::new(File::open(path)?))
+}
+
+
+fn eval_source(
+    name: String,
+    catalog: &Catalog,
+    dbmeta: &DBMeta2,
+    truths: &[Truth],
+) -> Result<SourceReport, io::Error> {
+    let mut rep = SourceReport::new(name);
+
+    let keys: Vec<UInt> = truths.iter().map(|truth| UInt::U32(truth.hash)).collect();
+    let rows =
+        query_db_many_rows_explain(&catalog.root, dbmeta, &keys, default_threads(), None)?;
+
+    for (truth, rows) in truths.iter().zip(rows) {
+        let candidates: Vec<u32> = rows
+            .into_iter()
+            .map(|mut vals| vals.pop().unwrap().into_u32())
+            .collect();
+
+        rep.add(Outcome::score(truth, &candidates));
+    }
+
+    Ok(rep)
+}
+
+/// Score the plain tables and each collision resolver of `catalog` against
+/// `truths`
+pub fn eval_bili2(catalog: &Catalog, truths: &[Truth]) -> Result<EvalReport, io::Error> {
+    let mut reps = vec![
+        eval_source("Normal".to_string(), catalog, &catalog.normal, truths)?,
+        eval_source("Dup".to_string(), catalog, &catalog.dup, truths)?,
+    ];
+
+    for (resolve, dbmeta) in catalog.resolves.iter() {
+        reps.push(eval_source(resolve.to_string(), catalog, dbmeta, truths)?);
+    }
+
+    Ok(EvalReport(reps))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_truth, Outcome, Truth};
+
+    #[test]
+    fn test_parse_truth() {
+        let csv = "hashid,uid\nc4ff7ac1,\nd9e2cc6,\n55f5defa,6487381\n";
+        let truths = parse_truth(csv.as_bytes()).unwrap();
+
+        assert_eq!(truths.len(), 3);
+        assert_eq!(truths[1].hash, 0x0d9e2cc6);
+        assert_eq!(truths[1].uid, None);
+        assert_eq!(truths[2].hash, 0x55f5defa);
+        assert_eq!(truths[2].uid, Some(6487381));
+
+        assert!(parse_truth("hashid,uid\nxyz,1\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_score() {
+        let truth = Truth { hash: 0x55f5defa, uid: Some(6487381) };
+
+        assert_eq!(Outcome::score(&truth, &[6487381]), Outcome::Hit);
+        assert_eq!(Outcome::score(&truth, &[1, 6487381]), Outcome::Ambiguous);
+        assert_eq!(Outcome::score(&truth, &[1]), Outcome::Miss);
+        assert_eq!(Outcome::score(&truth, &[]), Outcome::Miss);
+
+        let truth = Truth { hash: 0xc4ff7ac1, uid: None };
+        assert_eq!(Outcome::score(&truth, &[]), Outcome::Unlabeled(false));
+    }
+}
@@ -0,0 +1,399 @@
+//! Export
+//!
+//! Rows of the tables of a plan (or of a key range of them) as csv, JSON
+//! Lines or a SQLite database, one row per (hash, uid) as stored in each
+//! table: nothing is merged across tables, and overrides are not tables so
+//! they are not exported.
+//!
+//! SQLite schema: `pairs(hash, uid, family, id, resolver, orig)` indexed on
+//! `hash`, hashes are integers there (`printf('%08x', hash)` for the hex).
+//! The database is built at `<output>.part` and renamed once indexed, a
+//! failed export leaves nothing at `output`.
+//!
+
+use std::{
+    fs::{remove_file, rename, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::ArgEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use rusqlite::{params, Connection};
+
+use crate::{
+    catalog::{candidate_of, Catalog, QueryPlan},
+    data::{TblMeta, UInt},
+    gendata::{part_path, CollisionResolver, TblReader},
+    output::{ExportRecord, Format},
+    query::{Candidate, KeyRange, TblCursor},
+    shell::{TblFamily, TblTy},
+};
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ArgEnum)]
+pub enum ExportFmt {
+    Csv,
+    Jsonl,
+    Sqlite,
+}
+
+/// Rows `start..end` of a table
+struct ExportTbl {
+    family: TblFamily,
+    resolve: Option<CollisionResolver>,
+    ty: TblTy,
+    meta: TblMeta,
+    start: u64,
+    end: u64,
+}
+
+pub(crate) enum Sink {
+    Records(Format, Box<dyn Write>),
+    Sqlite(SqliteOut),
+}
+
+/// Database being built at the `.part` of `path`, removed if dropped before
+/// `Sink::finish`
+pub(crate) struct SqliteOut {
+    conn: Connection,
+    path: PathBuf,
+}
+
+impl Drop for SqliteOut {
+    fn drop(&mut self) {
+        let _ = remove_file(part_path(&self.path));
+    }
+}
+
+
+fn sql_err(err: rusqlite::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+
+impl Sink {
+    /// `output` is required for sqlite, stdout otherwise
+    pub(crate) fn open(to: ExportFmt, output: Option<&Path>) -> Result<Self, io::Error> {
+        let w = || -> Result<Box<dyn Write>, io::Error> {
+            Ok(match output {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(BufWriter::new(io::stdout())),
+            })
+        };
+
+        match to {
+            ExportFmt::Csv => {
+                let mut w = w()?;
+                Format::Csv.write_header::<_, ExportRecord>(&mut w)?;
+
+                Ok(Self::Records(Format::Csv, w))
+            }
+            ExportFmt::Jsonl => Ok(Self::Records(Format::Json, w()?)),
+            ExportFmt::Sqlite => {
+                let path = output.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "sqlite export needs an output path",
+                    )
+                })?;
+
+                if path.exists() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} exists", path.display()),
+                    ));
+                }
+
+                // left by an export killed before it could clean up
+                let part = part_path(path);
+                if part.exists() {
+                    remove_file(&part)?;
+                }
+
+                let conn = Connection::open(&part).map_err(sql_err)?;
+                conn.execute_batch(
+                    "PRAGMA journal_mode = OFF;
+                     PRAGMA synchronous = OFF;
+                     CREATE TABLE pairs (
+                         hash INTEGER NOT NULL,
+                         uid INTEGER NOT NULL,
+                         family TEXT NOT NULL,
+                         id INTEGER NOT NULL,
+                         resolver TEXT,
+                         orig INTEGER
+                     );
+                     BEGIN;",
+                )
+                .map_err(sql_err)?;
+
+                Ok(Self::Sqlite(SqliteOut {
+                    conn,
+                    path: path.to_path_buf(),
+                }))
+            }
+        }
+    }
+
+    pub(crate) fn push(&mut self, cand: &Candidate, id: u32) -> Result<(), io::Error> {
+        match self {
+            Self::Records(fmt, w) => fmt.write_record(w, &ExportRecord::of(cand, id)),
+            Self::Sqlite(out) => {
+                out.conn.prepare_cached("INSERT INTO pairs VALUES (?, ?, ?, ?, ?, ?)")
+                    .and_then(|mut stmt| {
+                        stmt.execute(params![
+                            cand.hash,
+                            cand.uid,
+                            format!("{:?}", cand.table),
+                            id,
+                            cand.resolver,
+                            cand.orig,
+                        ])
+                    })
+                    .map_err(sql_err)?;
+
+                Ok(())
+            }
+        }
+    }
+
+    /// The index is built once every row is in
+    pub(crate) fn finish(self) -> Result<(), io::Error> {
+        match self {
+            Self::Records(_, mut w) => w.flush(),
+            Self::Sqlite(out) => {
+                out.conn
+                    .execute_batch(
+                        "COMMIT;
+                         CREATE INDEX pairs_hash ON pairs (hash);",
+                    )
+                    .map_err(sql_err)?;
+
+                rename(part_path(&out.path), &out.path)
+            }
+        }
+    }
+}
+
+
+/// Tables of `plan` and the rows of each in `range`
+fn export_tables(
+    catalog: &Catalog,
+    plan: &QueryPlan,
+    range: Option<KeyRange>,
+) -> Result<Vec<ExportTbl>, io::Error> {
+    let mut res = vec![];
+
+    for family in plan.families.iter() {
+        for (resolve, dbmeta) in catalog.tables_of(*family, plan) {
+            for (ty, meta) in dbmeta.0.iter() {
+                let (start, end) = match range {
+                    None => (0, meta.len),
+                    Some(range) => {
+                        let reader = TblReader::open(*meta, &ty.path_in(&catalog.root))?
+                            .into_reader();
+                        let mut cursor = TblCursor::new(reader, *meta);
+
+                        let start =
+                            cursor.lower_bound(UInt::U32(range.lo), 0, meta.len)?;
+                        let end = match range.hi.checked_add(1) {
+                            Some(hi) => {
+                                cursor.lower_bound(UInt::U32(hi), start, meta.len)?
+                            }
+                            None => meta.len,
+                        };
+
+                        (start, end)
+                    }
+                };
+
+                res.push(ExportTbl {
+                    family: *family,
+                    resolve,
+                    ty: *ty,
+                    meta: *meta,
+                    start,
+                    end,
+                });
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+/// Stream the rows of `plan` (in `range`) to `to`, number exported
+pub fn export(
+    catalog: &Catalog,
+    plan: &QueryPlan,
+    range: Option<KeyRange>,
+    to: ExportFmt,
+    output: Option<&Path>,
+) -> Result<u64, io::Error> {
+    let tbls = export_tables(catalog, plan, range)?;
+    let mut sink = Sink::open(to, output)?;
+
+    let pb = ProgressBar::new(tbls.iter().map(|tbl| tbl.end - tbl.start).sum());
+    pb.set_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner:.green} [{elapsed_precise}] {pos:>7}/{len} {msg}"),
+    );
+
+    let mut read_cnt = 0u64;
+    let mut cnt = 0u64;
+
+    for tbl in tbls.iter() {
+        let path = tbl.ty.path_in(&catalog.root);
+        let mut reader = TblReader::open(tbl.meta, &path)?;
+        reader.seek_row(tbl.start)?;
+        pb.set_message(format!("{:?} {}", tbl.family, tbl.ty.id()));
+
+        for _ in tbl.start..tbl.end {
+            // cut short since the catalog was loaded
+            let item = reader.try_read_item().map_err(|err| {
+                io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+            })?;
+            let key = match item {
+                Some(item) => item.0.into_u32(),
+                None => break,
+            };
+            read_cnt += 1;
+
+            if read_cnt.is_multiple_of(1000) {
+                pb.set_position(read_cnt);
+            }
+
+            if range.is_some_and(|range| !range.contains(key)) {
+                continue;
+            }
+
+            let cand = candidate_of(tbl.family, tbl.resolve, key, reader.vals());
+            sink.push(&cand, tbl.ty.id())?;
+            cnt += 1;
+        }
+    }
+
+    sink.finish()?;
+    pb.finish_with_message(format!("{} rows", cnt));
+
+    Ok(cnt)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        fs::{read_to_string, remove_dir_all, remove_file},
+    };
+
+    use rusqlite::Connection;
+
+    use super::{export, ExportFmt, Sink};
+    use crate::{
+        catalog::{Catalog, QueryPlan},
+        data::UInt,
+        gendata::{part_path, DBWriter, BILI2_HASHUID_TBL_META},
+        query::{Candidate, KeyRange},
+        shell::{TblFamily, TblTy},
+    };
+
+    #[test]
+    fn test_sqlite_sink() {
+        let path = temp_dir().join(format!("hh_export_{}.sqlite", std::process::id()));
+        let _ = remove_file(&path);
+
+        let mut sink = Sink::open(ExportFmt::Sqlite, Some(&path)).unwrap();
+        for (uid, table, orig) in [
+            (86825, TblFamily::Normal, None),
+            (14740604, TblFamily::Dup, None),
+            (7, TblFamily::Resolve, Some(0x0d9e2cc6)),
+        ] {
+            let cand = Candidate {
+                hash: 0x1388875d,
+                uid,
+                table,
+                resolver: orig.map(|_| "Rehash".to_string()),
+                orig,
+                also: vec![],
+            };
+            sink.push(&cand, 0).unwrap();
+        }
+        sink.finish().unwrap();
+        assert!(!part_path(&path).exists());
+
+        assert!(Sink::open(ExportFmt::Sqlite, Some(&path)).is_err());
+
+        // a failed export leaves neither the database nor its part
+        let other = path.with_extension("other");
+        let sink = Sink::open(ExportFmt::Sqlite, Some(&other)).unwrap();
+        assert!(part_path(&other).exists());
+        drop(sink);
+        assert!(!other.exists() && !part_path(&other).exists());
+
+        let conn = Connection::open(&path).unwrap();
+        let (cnt, orig): (u32, u32) = conn
+            .query_row(
+                "SELECT count(*), max(orig) FROM pairs WHERE hash = ?",
+                [0x1388875du32],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((cnt, orig), (3, 0x0d9e2cc6));
+
+        let plan: String = conn
+            .query_row(
+                "EXPLAIN QUERY PLAN SELECT uid FROM pairs WHERE hash = 1",
+                [],
+                |row| row.get(3),
+            )
+            .unwrap();
+        assert!(plan.contains("pairs_hash"), "{}", plan);
+
+        drop(conn);
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_export_range() {
+        let root = temp_dir().join(format!("hh_export_{}", std::process::id()));
+
+        let mut db = DBWriter::init_in(&root, BILI2_HASHUID_TBL_META, TblTy::Normal(0));
+        for rows in [[(0x10u32, 1u32), (0x20, 2), (0x30, 3)], [(0x20, 5), (0x2f, 4), (0x31, 6)]] {
+            let mut w = db.nxt_tbl_writer().unwrap();
+            for (key, uid) in rows {
+                w.write_row(UInt::U32(key), &[UInt::U32(uid)]).unwrap();
+            }
+            w.finish().unwrap();
+        }
+
+        let catalog = Catalog::load_in(&root);
+        let plan = QueryPlan::default();
+        let range = Some(KeyRange::between(0x20, 0x30));
+
+        let csv = root.join("out.csv");
+        let cnt = export(&catalog, &plan, range, ExportFmt::Csv, Some(&csv)).unwrap();
+        assert_eq!(cnt, 4);
+        assert_eq!(
+            read_to_string(&csv).unwrap(),
+            "hash,uid,table,id,resolver,orig\n\
+             00000020,2,Normal,0,,\n\
+             00000030,3,Normal,0,,\n\
+             00000020,5,Normal,1,,\n\
+             0000002f,4,Normal,1,,\n"
+        );
+
+        let jsonl = root.join("out.jsonl");
+        let cnt = export(&catalog, &plan, None, ExportFmt::Jsonl, Some(&jsonl)).unwrap();
+        let lines: Vec<String> = read_to_string(&jsonl).unwrap().lines().map(String::from).collect();
+        assert_eq!(cnt, 6);
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[0],
+            r#"{"hash":"00000010","uid":1,"table":"Normal","id":0,"resolver":null,"orig":null}"#
+        );
+
+        remove_dir_all(root).unwrap();
+    }
+}
@@ -1,29 +1,48 @@
+// `////` lines are section banners, not doc comments
+#![allow(clippy::four_forward_slashes)]
+
 use std::{
     cmp::Reverse,
     collections::BinaryHeap,
-    fs::File,
-    io::{BufReader, BufWriter, Read, Write, Seek},
+    fmt::Display,
+    fs::{create_dir_all, metadata, read_dir, read_to_string, remove_file, rename, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
     mem::size_of,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
-use crc32fast::hash as crc32;
 use indicatif::{ProgressBar, ProgressStyle};
-use m6coll::{array, Array, BitMap, Entry, ToLeBytes};
+use m6coll::{array, Array, Entry, ToLeBytes};
 
-use crate::data::{TblMeta, UInt};
-use crate::query::load_dbmeta;
+use crate::compact::{finish_journal, merge_tables};
+use crate::data::{DBMeta2, GroupMember, TblMeta, UInt};
+use crate::hash::{hash_bili2, rehash_bili2, HashSpec};
+use crate::query::{load_dbmeta, load_family_in};
 use crate::{query::load_dup_dbmeta, shell::*};
 
 
 // < 512 * 1024 * 1024 = 5_3687_0912
-const BUNDLE_LEN: u32 = 1_0240_0000; // 1亿 (100_000 * 1024)
+pub(crate) const BUNDLE_LEN: u32 = 1_0240_0000; // 1亿 (100_000 * 1024)
 const BILI2_KEY_SIZE: u32 = 4;
-const BILI2_VAL_SIZE: u32 = 4;
-const BILI2_HASHUID_TBL_META: TblMeta = TblMeta {
+const BILI2_VAL_SIZE: u16 = 4;
+pub(crate) const BILI2_HASHUID_TBL_META: TblMeta = TblMeta {
+    len: BUNDLE_LEN as u64,
+    keysize: BILI2_KEY_SIZE,
+    valsize: BILI2_VAL_SIZE,
+    valcols: 0,
+};
+const BILI2_GROUP_TBL_META: TblMeta = TblMeta {
+    len: BUNDLE_LEN as u64,
+    keysize: BILI2_KEY_SIZE,
+    valsize: 8,
+    valcols: 0,
+};
+/// (resolved key, original hash, uid)
+const BILI2_RESOLVE_TBL_META: TblMeta = TblMeta {
     len: BUNDLE_LEN as u64,
     keysize: BILI2_KEY_SIZE,
     valsize: BILI2_VAL_SIZE,
+    valcols: 2,
 };
 
 
@@ -34,149 +53,491 @@ const BILI2_HASHUID_TBL_META: TblMeta = TblMeta {
 ///////////////////////////////////////
 //// Reader
 
+/// Counts the bytes read from `R`, under a `BufReader` that is the real I/O
+pub(crate) struct CountRead<R> {
+    inner: R,
+    pub(crate) bytes: u64,
+}
+
+impl<R> CountRead<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self { inner, bytes: 0 }
+    }
+}
+
+impl<R: Read> Read for CountRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for CountRead<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
 pub(crate) struct TblReader {
     meta: TblMeta,
     cnt: u64,
     buf: Array<u8>,
-    reader: BufReader<File>,
+    reader: BufReader<CountRead<File>>,
 }
 
 impl TblReader {
-    pub(crate) fn new(meta: TblMeta, ty: TblTy) -> Self {
-        let buf = array![0; (meta.keysize + meta.valsize) as usize];
+    /// Table at any path (not only the catalog layout)
+    pub(crate) fn open(meta: TblMeta, path: &Path) -> Result<Self, io::Error> {
+        let buf = array![0; meta.unit_len() as usize];
 
-        let file = if let Ok(res) = File::open(ty.pathbuf()) {
-            res
-        } else {
-            panic!("Unable to open file {:#?}", &ty.pathbuf())
-        };
-
-        let mut reader = BufReader::new(file);
-        // let mut reader = file;
+        let mut reader = BufReader::new(CountRead::new(File::open(path)?));
 
         // skip meta
         let mut tmp_buf = [0; size_of::<TblMeta>()];
-        reader.read(&mut tmp_buf).unwrap();
+        reader.read_exact(&mut tmp_buf)?;
 
-        Self {
+        Ok(Self {
             meta,
             cnt: 0,
             buf,
             reader,
-        }
+        })
+    }
+
+    /// Next `try_read_item` yields row `pos`
+    pub(crate) fn seek_row(&mut self, pos: u64) -> Result<(), io::Error> {
+        let pos = pos.min(self.meta.len);
+        let offset = size_of::<TblMeta>() as u64 + pos * self.meta.unit_len() as u64;
+
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.cnt = pos;
+
+        Ok(())
     }
 
-    pub(crate) fn read_item(&mut self) -> Result<Entry<UInt, UInt>, ()> {
+    /// Value columns of the item read last
+    pub(crate) fn vals(&self) -> Vec<UInt> {
+        self.meta.vals_from_slice(&self.buf[..])
+    }
+
+    /// Raw bytes of the item read last
+    pub(crate) fn item_bytes(&self) -> &[u8] {
+        &self.buf[..]
+    }
+
+    /// Next item, `None` after the last row and an error if the file
+    /// ends before the rows the header claims
+    pub(crate) fn try_read_item(&mut self) -> Result<Option<Entry<UInt, UInt>>, io::Error> {
         if self.cnt == self.meta.len {
-            return Err(());
+            return Ok(None);
         }
 
-        self.reader.read(&mut self.buf[..]).unwrap(); // should be same with
+        self.reader.read_exact(&mut self.buf[..])?;
 
         let key = UInt::from_slice(&self.buf[..self.meta.keysize as usize]);
-        let val = UInt::from_slice(&self.buf[self.meta.keysize as usize..]);
+        let val = self.meta.val_from_slice(&self.buf[..]);
 
         self.cnt += 1;
 
-        Ok(Entry(key, val))
+        Ok(Some(Entry(key, val)))
     }
 
-    pub(crate) fn into_reader(self) -> impl Read + Seek {
+    pub(crate) fn into_reader(self) -> BufReader<CountRead<File>> {
         self.reader
     }
 }
 
 
+/// K-way merge of sorted tables, yield items in (key, val) order
+pub(crate) struct MergeReader {
+    readers: Vec<TblReader>,
+    paths: Vec<PathBuf>,
+    heap: BinaryHeap<Reverse<(UInt, UInt, usize)>>,
+}
+
+/// Item and the index into `dbmeta` of its table
+type MergeItem = (Entry<UInt, UInt>, usize);
+
+/// Key and the (val, index) of its items
+type MergeRun = (UInt, Vec<(UInt, usize)>);
+
+impl MergeReader {
+    /// Tables of `dbmeta` under `root`
+    pub(crate) fn open(root: &Path, dbmeta: &DBMeta2) -> Result<Self, io::Error> {
+        let mut merger = Self {
+            readers: vec![],
+            paths: vec![],
+            heap: BinaryHeap::with_capacity(dbmeta.0.len()),
+        };
+
+        for (i, (ty, meta)) in dbmeta.0.iter().enumerate() {
+            let path = ty.path_in(root);
+
+            merger.readers.push(TblReader::open(*meta, &path)?);
+            merger.paths.push(path);
+            merger.push_next(i)?;
+        }
+
+        Ok(merger)
+    }
+
+    /// Next item of table `i` into the heap, a truncated table fails the
+    /// merge instead of ending its rows early
+    fn push_next(&mut self, i: usize) -> Result<(), io::Error> {
+        let item = self.readers[i]
+            .try_read_item()
+            .map_err(|err| invalid_table(&self.paths[i], &err.to_string()))?;
+
+        if let Some(Entry(key, val)) = item {
+            self.heap.push(Reverse((key, val, i)));
+        }
+
+        Ok(())
+    }
+
+    /// Index into `dbmeta` of the table the item comes from
+    pub(crate) fn read_item(&mut self) -> Result<Option<MergeItem>, io::Error> {
+        let Reverse((key, val, i)) = match self.heap.pop() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        self.push_next(i)?;
+
+        Ok(Some((Entry(key, val), i)))
+    }
+
+    /// All items sharing the next key, vals sorted
+    pub(crate) fn read_run(&mut self) -> Result<Option<MergeRun>, io::Error> {
+        let (Entry(key, val), i) = match self.read_item()? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let mut run = vec![(val, i)];
+
+        while let Some(Reverse((nxt_key, _, _))) = self.heap.peek() {
+            if *nxt_key != key {
+                break;
+            }
+
+            // just peeked
+            let (Entry(_, val), i) = self.read_item()?.unwrap();
+            run.push((val, i));
+        }
+        run.sort();
+
+        Ok(Some((key, run)))
+    }
+}
+
+
+///////////////////////////////////////
+//// Digest
+
+/// Trailer after the rows of a table: magic then the digest sum
+const DIGEST_MAGIC: &[u8; 8] = b"hhdigest";
+pub(crate) const DIGEST_LEN: u64 = 16;
+
+/// Row count and the (order independent) sum of the row checksums
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Digest {
+    pub(crate) rows: u64,
+    sum: u64,
+}
+
+impl Digest {
+    pub(crate) fn add(&mut self, row: &[u8]) {
+        self.rows += 1;
+        self.sum = self.sum.wrapping_add(crc32fast::hash(row) as u64);
+    }
+
+    fn trailer(&self) -> Vec<u8> {
+        let mut buf = DIGEST_MAGIC.to_vec();
+        buf.extend_from_slice(&self.sum.to_le_bytes());
+
+        buf
+    }
+}
+
+fn invalid_table(path: &Path, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), msg),
+    )
+}
+
+/// Digest sum stored after the rows of the table at `path`, `None` for a
+/// table written before digests were stored
+fn stored_digest(path: &Path, meta: TblMeta) -> Result<Option<u64>, io::Error> {
+    let bytes = metadata(path)?.len();
+
+    if bytes == meta.tbl_bytes() {
+        return Ok(None);
+    }
+    if bytes != meta.tbl_bytes() + DIGEST_LEN {
+        return Err(invalid_table(path, "size differs from its header"));
+    }
+
+    let mut file = File::open(path)?;
+    let mut buf = [0; DIGEST_LEN as usize];
+    file.seek(SeekFrom::Start(meta.tbl_bytes()))?;
+    file.read_exact(&mut buf)?;
+
+    if &buf[..8] != DIGEST_MAGIC {
+        return Err(invalid_table(path, "no digest after the rows"));
+    }
+
+    Ok(Some(u64::from_le_bytes(buf[8..].try_into().unwrap())))
+}
+
+/// Digest of the rows of the table at `path`, which must be sorted
+fn read_digest(path: &Path, meta: TblMeta) -> Result<Digest, io::Error> {
+    let mut reader = TblReader::open(meta, path)?;
+    let mut found = Digest::default();
+    let mut prev: Option<UInt> = None;
+
+    while let Some(item) = reader.try_read_item()? {
+        if prev.is_some_and(|prev| prev > item.0) {
+            return Err(invalid_table(path, &format!("not sorted at row {}", found.rows)));
+        }
+        prev = Some(item.0);
+
+        found.add(reader.item_bytes());
+    }
+
+    Ok(found)
+}
+
+/// The table at `path` is `meta`, sorted, and holds the rows of `digest`,
+/// which is also the one stored
+pub(crate) fn verify_table(
+    path: &Path,
+    meta: TblMeta,
+    digest: Digest,
+) -> Result<(), io::Error> {
+    if stored_digest(path, meta)? != Some(digest.sum) {
+        return Err(invalid_table(path, "stored digest differs from the rows written"));
+    }
+    if read_digest(path, meta)? != digest {
+        return Err(invalid_table(path, "rows differ from the ones written"));
+    }
+
+    Ok(())
+}
+
+/// Rows of the table at `path` against its stored digest, `None` if it has
+/// none
+pub(crate) fn check_digest(path: &Path, meta: TblMeta) -> Result<Option<bool>, io::Error> {
+    let stored = match stored_digest(path, meta)? {
+        Some(stored) => stored,
+        None => return Ok(None),
+    };
+    let found = read_digest(path, meta)?;
+
+    Ok(Some(found.rows == meta.len && found.sum == stored))
+}
+
+
 ///////////////////////////////////////
 //// Writer
 
-struct DBWriter {
+/// Suffix of a file being written, renamed away once complete
+const PART_EXT: &str = "part";
+
+/// `path` with the `.part` suffix
+pub(crate) fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", PART_EXT));
+
+    PathBuf::from(name)
+}
+
+/// Make a rename in the dir of `path` survive a crash
+pub(crate) fn sync_parent(path: &Path) -> Result<(), io::Error> {
+    let dir = path
+        .parent()
+        .filter(|x| !x.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+
+    File::open(dir)?.sync_all()
+}
+
+pub(crate) struct DBWriter {
+    root: PathBuf,
     id_cnt: TblTy,
     tblmeta: TblMeta,
 }
 
 impl DBWriter {
-    fn init(tblmeta: TblMeta, ty: TblTy) -> Self {
+    /// Tables from `ty` on, under `root`
+    pub(crate) fn init_in(root: &Path, tblmeta: TblMeta, ty: TblTy) -> Self {
         Self {
+            root: root.to_path_buf(),
             id_cnt: ty,
             tblmeta,
         }
     }
 
-    fn nxt_tbl_writer(&mut self) -> TblWriter {
-        let tblwriter = TblWriter::new(self.tblmeta, self.id_cnt);
+    pub(crate) fn nxt_tbl_writer(&mut self) -> Result<TblWriter, io::Error> {
+        let tblwriter = TblWriter::new(&self.root, self.tblmeta, self.id_cnt)?;
         self.id_cnt = self.id_cnt.add();
-        tblwriter
+        Ok(tblwriter)
     }
 }
 
-struct TblWriter {
+/// Rows go to `path.part`, then the digest of the rows. `finish` syncs it,
+/// checks it against the rows written and renames it to `path`, so a table
+/// under its real name is always whole. Dropped unfinished, the `.part` is
+/// removed.
+pub(crate) struct TblWriter {
     meta: TblMeta,
-    ty: TblTy,
+    path: PathBuf,
     cnt: u64,
+    digest: Digest,
     writer: BufWriter<File>,
+    done: bool,
 }
 
 impl TblWriter {
-    fn new(meta: TblMeta, ty: TblTy) -> Self {
-        ty.init();
+    fn new(root: &Path, meta: TblMeta, ty: TblTy) -> Result<Self, io::Error> {
+        let path = ty.path_in(root);
+        create_dir_all(path.parent().unwrap())?;
 
-        let ty = ty;
-        let file = File::create(ty.pathbuf()).unwrap();
+        Self::create(meta, path)
+    }
+
+    /// Table at any path, its dir must exist
+    pub(crate) fn create(meta: TblMeta, path: PathBuf) -> Result<Self, io::Error> {
+        let file = File::create(part_path(&path))?;
         let mut writer = BufWriter::new(file);
 
         // skip meta
-        writer.write_all(&meta.to_le_bytes()).unwrap();
+        writer.write_all(&meta.to_le_bytes())?;
 
-        TblWriter {
+        Ok(TblWriter {
             meta,
-            ty,
+            path,
             cnt: 0,
+            digest: Digest::default(),
             writer,
+            done: false,
+        })
+    }
+
+    /// Put the table in place, its meta and the digest of its rows
+    pub(crate) fn finish(mut self) -> Result<(TblMeta, Digest), io::Error> {
+        let meta = TblMeta {
+            len: self.cnt,
+            ..self.meta
+        };
+        let tmp = part_path(&self.path);
+
+        self.writer.write_all(&self.digest.trailer())?;
+
+        // the last table of a db is usually not full, write back the real len
+        if self.cnt != self.meta.len {
+            self.writer.seek(SeekFrom::Start(0))?;
+            self.writer.write_all(&meta.to_le_bytes())?;
         }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+
+        verify_table(&tmp, meta, self.digest)?;
+
+        rename(&tmp, &self.path)?;
+        self.done = true;
+        sync_parent(&self.path)?;
+
+        Ok((meta, self.digest))
+    }
+
+    #[inline]
+    pub(crate) fn digest(&self) -> Digest {
+        self.digest
     }
 
     #[inline]
-    fn path(&self) -> PathBuf {
-        self.ty.pathbuf()
+    pub(crate) fn path(&self) -> PathBuf {
+        self.path.clone()
     }
 
     #[inline]
-    fn is_end(&self) -> bool {
+    pub(crate) fn is_end(&self) -> bool {
         self.cnt == self.meta.len
     }
 
-    fn write_item(&mut self, buf: &[u8]) -> Result<(), ()> {
+    /// An error past the rows of the header or if the write fails
+    pub(crate) fn write_item(&mut self, buf: &[u8]) -> Result<(), io::Error> {
         if self.is_end() {
-            return Err(());
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: all {} rows written", self.path.display(), self.meta.len),
+            ));
         }
 
-        debug_assert_eq!(
-            (self.meta.keysize + self.meta.valsize) as usize,
-            buf.len()
-        );
+        debug_assert_eq!(self.meta.unit_len() as usize, buf.len());
 
-        self.writer.write_all(buf).unwrap(); // should be same with
+        self.writer.write_all(buf)?;
 
+        self.digest.add(buf);
         self.cnt += 1;
 
         Ok(())
     }
+
+    pub(crate) fn write_row(&mut self, key: UInt, vals: &[UInt]) -> Result<(), io::Error> {
+        let mut buf = Vec::with_capacity(self.meta.unit_len() as usize);
+
+        // not `ToLeBytes`, m6coll's `Array` is never freed
+        key.extend_le(&mut buf);
+        for val in vals.iter() {
+            val.extend_le(&mut buf);
+        }
+
+        self.write_item(&buf)
+    }
+}
+
+impl Drop for TblWriter {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = remove_file(part_path(&self.path));
+        }
+    }
 }
 
 
 ////////////////////////////////////////////////////////////////////////////////
 //// Collision Resolver
 
-#[derive(Clone, Copy)]
-pub(crate) enum CollisionResolver {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CollisionResolver {
     Rehash,  // Hash(Hash(x)),
 }
 
 impl CollisionResolver {
-    fn resolve(&self, hashval: u32, _x: u32) -> u32 {
+    /// In priority order
+    pub const ALL: [Self; 1] = [Self::Rehash];
+
+    pub fn resolve(&self, hashval: u32, _x: u32) -> u32 {
+        match self {
+            CollisionResolver::Rehash => rehash_bili2(hashval),
+        }
+    }
+
+    /// `resolve` for hashes of `spec` instead of bili2
+    pub fn resolve_with(&self, spec: &HashSpec, hashval: u32, _x: u32) -> u32 {
         match self {
-            CollisionResolver::Rehash => crc32(hashval.to_string().as_bytes()),
+            CollisionResolver::Rehash => spec.rehash(hashval),
+        }
+    }
+}
+
+impl Display for CollisionResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CollisionResolver::Rehash => write!(f, "Rehash"),
         }
     }
 }
@@ -184,162 +545,841 @@ impl CollisionResolver {
 
 
 ////////////////////////////////////////////////////////////////////////////////
-//// Service
+//// Checkpoint
+
+/// Uids hashed and sorted in memory at a time
+const RUN_LEN: u32 = BUNDLE_LEN / 16;
+const CHECKPOINT_NAME: &str = "checkpoint";
+
+/// Sorted runs of a bundle written so far: `lo hi run_len` of the bundle then
+/// a `lo hi` line per run (`run_K.bin`), a run is recorded once renamed
+#[derive(Debug, PartialEq, Eq)]
+struct Checkpoint {
+    lo: u32,
+    hi: u32,
+    run_len: u32,
+    runs: Vec<(u32, u32)>,
+}
+
+impl Checkpoint {
+    fn run_path(dir: &Path, k: usize) -> PathBuf {
+        dir.join(format!("run_{}.bin", k))
+    }
+
+    fn run_meta(lo: u32, hi: u32) -> TblMeta {
+        TblMeta {
+            len: (hi - lo) as u64 + 1,
+            ..BILI2_HASHUID_TBL_META
+        }
+    }
+
+    fn load(path: &Path) -> Result<Option<Self>, io::Error> {
+        let text = match read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: invalid checkpoint", path.display()),
+            )
+        };
+
+        let mut lines = text.lines().map(|line| {
+            line.split_whitespace()
+                .map(|x| x.parse::<u32>().map_err(|_| invalid()))
+                .collect::<Result<Vec<u32>, io::Error>>()
+        });
+
+        let head = lines.next().ok_or_else(invalid)??;
+        let (lo, hi, run_len) = match head.as_slice() {
+            [lo, hi, run_len] => (*lo, *hi, *run_len),
+            _ => return Err(invalid()),
+        };
+
+        let mut runs = vec![];
+        for line in lines {
+            match line?.as_slice() {
+                [lo, hi] => runs.push((*lo, *hi)),
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(Some(Self {
+            lo,
+            hi,
+            run_len,
+            runs,
+        }))
+    }
+
+    /// Written aside then renamed, never half written
+    fn save(&self, path: &Path) -> Result<(), io::Error> {
+        let tmp = part_path(path);
+        let mut w = BufWriter::new(File::create(&tmp)?);
+
+        writeln!(w, "{} {} {}", self.lo, self.hi, self.run_len)?;
+        for (lo, hi) in self.runs.iter() {
+            writeln!(w, "{} {}", lo, hi)?;
+        }
+        w.into_inner()?.sync_all()?;
+
+        rename(tmp, path)
+    }
+
+    /// Runs recorded and whole on disk, the first missing one and every one
+    /// after are dropped
+    fn keep_whole_runs(&mut self, dir: &Path) {
+        let whole = self
+            .runs
+            .iter()
+            .enumerate()
+            .take_while(|(k, (lo, hi))| {
+                metadata(Self::run_path(dir, *k)).is_ok_and(|x| {
+                    x.len() == Self::run_meta(*lo, *hi).tbl_bytes() + DIGEST_LEN
+                })
+            })
+            .count();
+
+        self.runs.truncate(whole);
+    }
+}
+
+/// Runs, partial outputs and the checkpoint of an earlier generation in `dir`
+fn remove_runs(dir: &Path) -> Result<(), io::Error> {
+    for entry in read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        let part = name.ends_with(&format!(".{}", PART_EXT));
+
+        if part || name.starts_with("run_") || name == CHECKPOINT_NAME {
+            remove_file(dir.join(name))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// (hash, uid) of `lo..=hi` sorted into `run_K.bin`
+fn gen_run(dir: &Path, k: usize, lo: u32, hi: u32, pb: &ProgressBar) -> Result<(), io::Error> {
+    let mut rows = Vec::with_capacity((hi - lo) as usize + 1);
+
+    for uid in lo..=hi {
+        rows.push((hash_bili2(uid), uid));
 
+        if uid.is_multiple_of(1000) {
+            pb.inc(1);
+        }
+    }
+    rows.sort_unstable();
+
+    let path = Checkpoint::run_path(dir, k);
+    let mut writer = TblWriter::create(Checkpoint::run_meta(lo, hi), path)?;
 
-pub fn gen_data_bili2(id: u32) {
-    let mut writer = TblWriter::new(BILI2_HASHUID_TBL_META, TblTy::Dup(id));
+    for (hash, uid) in rows {
+        writer.write_row(UInt::U32(hash), &[UInt::U32(uid)])?;
+    }
+    writer.finish()?;
+
+    Ok(())
+}
 
-    debug_assert!(BUNDLE_LEN % 1024 == 0);
-    let mut heap = BinaryHeap::with_capacity(BUNDLE_LEN.try_into().unwrap());
+/// `dir/db.bin` of uids `lo..=hi`: sorted runs of `run_len` uids, each
+/// recorded in the checkpoint once written, then merged. Tables are only
+/// put in place whole (see `TblWriter`), so an interrupted generation
+/// never leaves a `db.bin` behind. With `resume`
+/// the runs of the checkpoint are kept, otherwise it starts over.
+pub(crate) fn gen_bundle(
+    dir: &Path,
+    lo: u32,
+    hi: u32,
+    run_len: u32,
+    resume: bool,
+) -> Result<(), io::Error> {
+    let ckpt_path = dir.join(CHECKPOINT_NAME);
+    let mut ckpt = Checkpoint {
+        lo,
+        hi,
+        run_len,
+        runs: vec![],
+    };
+
+    match Checkpoint::load(&ckpt_path)? {
+        Some(mut old) if resume => {
+            if (old.lo, old.hi, old.run_len) != (lo, hi, run_len) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{}: checkpoint of uids {}..={} (runs of {}), not {}..={}",
+                        ckpt_path.display(), old.lo, old.hi, old.run_len, lo, hi
+                    ),
+                ));
+            }
 
-    let unit = 1000;
-    let start = id * BUNDLE_LEN + 1;
-    let end = (id + 1) * BUNDLE_LEN + 1;
+            old.keep_whole_runs(dir);
+            ckpt = old;
+        }
+        _ => remove_runs(dir)?,
+    }
 
-    let pb = ProgressBar::new((BUNDLE_LEN / unit).into());
+    let pb = ProgressBar::new(((hi - lo) as u64 + 1) / 1000);
     pb.set_style(
         ProgressStyle::default_spinner()
             .template("       calc crc32 {spinner:.green} [{elapsed_precise}] {pos:5}k/{len}k")
-
     );
-    for i in start..end {
-        let k = crc32(i.to_string().as_bytes());
-        let v = i;
+    if !ckpt.runs.is_empty() {
+        let (_, done) = ckpt.runs.last().unwrap();
+        pb.println(format!("resume after uid {} ({} runs)", done, ckpt.runs.len()));
+    }
 
-        heap.push(Reverse(Entry(k, v)));
+    let mut nxt = ckpt.runs.last().map(|(_, hi)| *hi as u64 + 1).unwrap_or(lo as u64);
+    pb.set_position((nxt - lo as u64) / 1000);
 
-        if (i - start) % unit == 0 {
-            pb.set_position(((i - start) / unit).into());
-        }
+    while nxt <= hi as u64 {
+        let run_lo = nxt as u32;
+        let run_hi = (nxt + run_len as u64 - 1).min(hi as u64) as u32;
+
+        gen_run(dir, ckpt.runs.len(), run_lo, run_hi, &pb)?;
+        ckpt.runs.push((run_lo, run_hi));
+        ckpt.save(&ckpt_path)?;
+
+        nxt = run_hi as u64 + 1;
     }
     pb.finish();
 
-    let pb = ProgressBar::new((BUNDLE_LEN / unit).into());
+    let inputs: Vec<(TblMeta, PathBuf)> = ckpt
+        .runs
+        .iter()
+        .enumerate()
+        .map(|(k, (lo, hi))| (Checkpoint::run_meta(*lo, *hi), Checkpoint::run_path(dir, k)))
+        .collect();
+
+    let pb = ProgressBar::new((hi - lo) as u64 + 1);
     pb.set_style(
         ProgressStyle::default_spinner()
-            .template("heap pop && write {spinner:.green} [{elapsed_precise}] {pos:5}k/{len}k")
-
+            .template("      merge runs {spinner:.green} [{elapsed_precise}] {pos:>9}/{len}")
     );
-    for i in 0..BUNDLE_LEN {
-        let entry = heap.pop().unwrap().0;
 
-        writer.write_item(&entry.to_le_bytes()).unwrap();
+    merge_tables(&inputs, dir.join(BUNDLE_NAME), &pb)?;
+    pb.finish_with_message("Done.");
 
-        if i % unit == 0 {
-            pb.set_position((i / unit).into());
+    remove_runs(dir)
+}
+
+
+
+////////////////////////////////////////////////////////////////////////////////
+//// Service
+
+
+/// Remove what interrupted writes left in the table dirs of the working dir:
+/// the inputs of a compaction whose output is in place (see `compact`), the
+/// `.part` files. Sorted runs and checkpoints are kept for `--resume`.
+/// Not to be run alongside another writer.
+pub fn clean_temporaries() -> Result<Vec<PathBuf>, io::Error> {
+    clean_temporaries_in(Path::new(CWD))
+}
+
+/// `clean_temporaries` under `root`, paths relative to it
+fn clean_temporaries_in(root: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let mut removed = finish_journal(root)?;
+
+    let mut dirs = vec![tbl_dup_dir(), tbl_group_dir(), tbl_import_dir()];
+
+    for resolve in CollisionResolver::ALL {
+        dirs.push(TblTy::Resolve(0, resolve).pathbuf().parent().unwrap().to_path_buf());
+    }
+    let top = if root.as_os_str().is_empty() { Path::new(".") } else { root };
+
+    for entry in read_dir(top)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+
+        if name
+            .strip_prefix("data")
+            .is_some_and(|id| !id.is_empty() && id.bytes().all(|x| x.is_ascii_digit()))
+        {
+            dirs.push(PathBuf::from(name));
         }
     }
-    pb.finish_with_message("Done.");
+
+    for dir in dirs.iter().filter(|dir| root.join(dir).is_dir()) {
+        for entry in read_dir(root.join(dir))? {
+            let name = PathBuf::from(entry?.file_name());
+            if name.extension().unwrap_or_default() == PART_EXT {
+                remove_file(root.join(dir).join(&name))?;
+                removed.push(dir.join(name));
+            }
+        }
+    }
+    removed.sort();
+
+    Ok(removed)
 }
 
+/// Another normal table holding uids of `lo..=hi`, as one compacted over the
+/// range of table `id`
+fn covering_table(dbmeta: &DBMeta2, id: u32, lo: u64, hi: u64) -> Option<TblTy> {
+    dbmeta
+        .0
+        .iter()
+        .filter(|(ty, meta)| ty.id() != id && meta.len > 0)
+        .find(|(ty, meta)| {
+            let start = ty.id() as u64 * BUNDLE_LEN as u64 + 1;
+
+            start <= hi && lo < start + meta.len
+        })
+        .map(|(ty, _)| *ty)
+}
 
-/// ReGeneration
-pub fn gen_collision_data_bili2() {
+/// Normal table `id` (`data{id}/db.bin`), resumable (`resume`) from the
+/// sorted runs of an interrupted run, see `gen_bundle`. Refused if another
+/// table holds uids of its range.
+pub fn gen_data_bili2(id: u32, resume: bool) -> Result<(), io::Error> {
+    gen_data_bili2_in(Path::new(CWD), id, resume)
+}
+
+/// `gen_data_bili2` under `root`
+fn gen_data_bili2_in(root: &Path, id: u32, resume: bool) -> Result<(), io::Error> {
+    const { assert!(BUNDLE_LEN.is_multiple_of(1024)) };
+
+    let lo = id as u64 * BUNDLE_LEN as u64 + 1;
+    let hi = ((id as u64 + 1) * BUNDLE_LEN as u64).min(u32::MAX as u64);
+    if lo > hi {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("table {} is past the uid range", id),
+        ));
+    }
+
+    let dbmeta = load_family_in(root, TblFamily::Normal);
+    if let Some(ty) = covering_table(&dbmeta, id, lo, hi) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("uids {}..={} are in {}", lo, hi, path2str(&ty.pathbuf())),
+        ));
+    }
+
+    let ty = TblTy::Normal(id);
+    ty.init_in(root)?;
+
+    gen_bundle(&root.join(tbl_dir(id)), lo as u32, hi as u32, RUN_LEN, resume)?;
+    println!("wrote into {}", path2str(&ty.path_in(root)));
+
+    Ok(())
+}
+
+
+/// ReGeneration, every row of a hash but the first (lowest uid), sorted
+pub fn gen_collision_data_bili2() -> Result<(), io::Error> {
     let dbmeta = load_dbmeta();
 
+    let pb = ProgressBar::new(dbmeta.total_items());
+    pb.set_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner:.green} [{elapsed_precise}] {pos:>7}/{len} {msg}"),
+    );
+
+    gen_dup_in(Path::new(CWD), &dbmeta, &pb)
+}
+
+/// Dup tables under `root` from the normal tables `dbmeta`
+fn gen_dup_in(root: &Path, dbmeta: &DBMeta2, pb: &ProgressBar) -> Result<(), io::Error> {
     let mut dup_db_writer =
-        DBWriter::init(BILI2_HASHUID_TBL_META, TblTy::Dup(0));
-    let mut dup_tbl_writer = dup_db_writer.nxt_tbl_writer();
+        DBWriter::init_in(root, BILI2_HASHUID_TBL_META, TblTy::Dup(0));
+    let mut dup_tbl_writer = dup_db_writer.nxt_tbl_writer()?;
+
+    let mut merger = MergeReader::open(root, dbmeta)?;
+    let mut read_cnt = 0u64;
+    let mut dup_cnt = 0u64;
+
+    while let Some((key, run)) = merger.read_run()? {
+        let prev_cnt = read_cnt;
+        read_cnt += run.len() as u64;
+
+        for (val, _) in run.into_iter().skip(1) {
+            if dup_tbl_writer.is_end() {
+                pb.println(format!(
+                    "wrote into {}",
+                    path2str(&dup_tbl_writer.path())
+                ));
+                dup_tbl_writer.finish()?;
+                dup_tbl_writer = dup_db_writer.nxt_tbl_writer()?;
+            }
+            dup_tbl_writer.write_row(key, &[val])?;
+            dup_cnt += 1;
+        }
+
+        if prev_cnt / 1000 != read_cnt / 1000 {
+            pb.set_position(read_cnt);
+            pb.set_message(format!("{} dups", dup_cnt));
+        }
+    }
+
+    pb.println(format!("wrote into {}", path2str(&dup_tbl_writer.path())));
+    dup_tbl_writer.finish()?;
+    pb.finish_with_message(format!("{} dups", dup_cnt));
+
+    Ok(())
+}
+
 
-    let pb = ProgressBar::new(0);
+/// Collision Group, every uid sharing a hash together with the group size
+pub fn gen_collision_group_data_bili2() -> Result<(), io::Error> {
+    let dbmeta = load_dbmeta();
+
+    let pb = ProgressBar::new(dbmeta.total_items());
     pb.set_style(
         ProgressStyle::default_spinner()
-            .template("{spinner:.green} [{elapsed_precise}] {pos:>7}"),
+            .template("{spinner:.green} [{elapsed_precise}] {pos:>7}/{len} {msg}"),
     );
 
-    let mut map = BitMap::new(u32::MAX as u128 + 1);
-    let mut dup_cnt = 0;
+    gen_group_in(Path::new(CWD), &dbmeta, &pb)
+}
+
+/// Group tables under `root` from the normal tables `dbmeta`
+fn gen_group_in(root: &Path, dbmeta: &DBMeta2, pb: &ProgressBar) -> Result<(), io::Error> {
+    let mut group_db_writer =
+        DBWriter::init_in(root, BILI2_GROUP_TBL_META, TblTy::Group(0));
+    let mut group_tbl_writer = group_db_writer.nxt_tbl_writer()?;
+
+    let mut merger = MergeReader::open(root, dbmeta)?;
+    let mut read_cnt = 0;
+    let mut group_cnt = 0u64;
+    let mut member_cnt = 0u64;
 
-    for (ty, meta) in dbmeta.0.iter() {
+    while let Some((key, run)) = merger.read_run()? {
+        let prev_cnt = read_cnt;
+        read_cnt += run.len() as u64;
 
-        let mut reader = TblReader::new(*meta, *ty);
+        if run.len() > 1 {
+            let size = run.len() as u32;
 
-        while let Ok(Entry(key, _val)) = reader.read_item() {
-            let key_as_usize = key.into_u32() as usize;
+            for (val, _) in run {
+                let member = GroupMember {
+                    uid: val.into_u32(),
+                    size,
+                };
 
-            if map.test(key_as_usize) {
-                if dup_tbl_writer.is_end() {
+                if group_tbl_writer.is_end() {
                     pb.println(format!(
                         "wrote into {}",
-                        path2str(&dup_tbl_writer.path())
+                        path2str(&group_tbl_writer.path())
                     ));
-                    dup_tbl_writer = dup_db_writer.nxt_tbl_writer();
+                    group_tbl_writer.finish()?;
+                    group_tbl_writer = group_db_writer.nxt_tbl_writer()?;
                 }
-                dup_tbl_writer.write_item(&reader.buf[..]).unwrap();
-                dup_cnt += 1;
-
-                if dup_cnt % 1000 == 0 {
-                    pb.set_position(dup_cnt);
-                }
-            } else {
-                map.set(key_as_usize)
+                group_tbl_writer.write_row(key, &[member.to_uint()])?;
             }
+
+            group_cnt += 1;
+            member_cnt += size as u64;
         }
 
-        pb.println(format!("wrote into {}", path2str(&dup_tbl_writer.path())));
+        if prev_cnt / 1000 != read_cnt / 1000 {
+            pb.set_position(read_cnt);
+            pb.set_message(format!("{} groups", group_cnt));
+        }
     }
 
-    pb.set_length(dup_cnt);
-    pb.finish();
+    pb.println(format!("wrote into {}", path2str(&group_tbl_writer.path())));
+    group_tbl_writer.finish()?;
+    pb.finish_with_message(format!(
+        "{} groups, {} members",
+        group_cnt, member_cnt
+    ));
+
+    Ok(())
 }
 
 
-/// Collision Resolve
-pub fn gen_collision_resolve_data_bili2() {
+/// Collision Resolve, each table the next `BUNDLE_LEN` dup rows sorted by
+/// resolved key, through sorted runs of `RUN_LEN` rows as in `gen_bundle`
+pub fn gen_collision_resolve_data_bili2() -> Result<(), io::Error> {
     let dbmeta = load_dup_dbmeta();
-    let resolve = CollisionResolver::Rehash;
-    let mut resolve_db_writer = DBWriter::init(
-        BILI2_HASHUID_TBL_META,
-        TblTy::Resolve(0, resolve),
-    );
-    let mut resolv_tbl_writer = resolve_db_writer.nxt_tbl_writer();
 
-    let pb = ProgressBar::new(0);
+    let pb = ProgressBar::new(dbmeta.total_items());
     pb.set_style(
         ProgressStyle::default_spinner()
-            .template("{spinner:.green} [{elapsed_precise}] {pos:>7}"),
+            .template("{spinner:.green} [{elapsed_precise}] {pos:>7}/{len}"),
     );
 
-    let mut dup_cnt = 0;
+    gen_resolve_in(Path::new(CWD), &dbmeta, RUN_LEN as usize, BUNDLE_LEN as u64, &pb)
+}
+
+/// Resolve tables under `root` of `tbl_len` rows from the dup tables
+/// `dbmeta`, at most `run_len` rows kept in memory
+fn gen_resolve_in(
+    root: &Path,
+    dbmeta: &DBMeta2,
+    run_len: usize,
+    tbl_len: u64,
+    pb: &ProgressBar,
+) -> Result<(), io::Error> {
+    let resolve = CollisionResolver::Rehash;
+    let mut ty = TblTy::Resolve(0, resolve);
+    ty.init_in(root)?;
+
+    // runs sit next to the tables, gone once merged
+    let dir = ty.path_in(root).parent().unwrap().to_path_buf();
+    remove_runs(&dir)?;
+
+    // (resolved key, original hash, uid)
+    let mut rows: Vec<(u32, u32, u32)> = Vec::with_capacity(run_len);
+    let mut runs = vec![];
+    let mut tbl_rows = 0u64;
+    let mut tbl_cnt = 0;
+    let mut dup_cnt = 0u64;
 
-    for (ty, meta) in dbmeta.0.iter() {
-        let mut reader = TblReader::new(*meta, *ty);
+    for (dup_ty, meta) in dbmeta.0.iter() {
+        let mut reader = TblReader::open(*meta, &dup_ty.path_in(root))?;
 
-        while let Ok(Entry(key, val)) = reader.read_item() {
-            let key  = key.into_u32();
+        while let Some(Entry(key, val)) = reader.try_read_item()? {
+            let key = key.into_u32();
             let val = val.into_u32();
-            let new_key = resolve.resolve(key, val);
-            let entry = Entry(new_key, val);
 
-            if resolv_tbl_writer.is_end() {
-                pb.println(format!(
-                    "wrote into {}",
-                    path2str(&resolv_tbl_writer.path())
-                ));
-                drop(resolv_tbl_writer);
-                resolv_tbl_writer = resolve_db_writer.nxt_tbl_writer();
+            rows.push((resolve.resolve(key, val), key, val));
+            tbl_rows += 1;
+
+            if rows.len() == run_len || tbl_rows == tbl_len {
+                write_resolve_run(&dir, &mut rows, &mut runs)?;
+            }
+            if tbl_rows == tbl_len {
+                write_resolve_tbl(root, ty, &mut runs, pb)?;
+                ty = ty.add();
+                tbl_rows = 0;
+                tbl_cnt += 1;
             }
-            resolv_tbl_writer.write_item(&entry.to_le_bytes()).unwrap();
-            dup_cnt += 1;
 
-            if dup_cnt % 1000 == 0 {
+            dup_cnt += 1;
+            if dup_cnt.is_multiple_of(1000) {
                 pb.set_position(dup_cnt);
             }
         }
+    }
 
-        drop(reader);
+    if !rows.is_empty() {
+        write_resolve_run(&dir, &mut rows, &mut runs)?;
+    }
+    if !runs.is_empty() || tbl_cnt == 0 {
+        write_resolve_tbl(root, ty, &mut runs, pb)?;
     }
+    pb.finish_with_message(format!("{} rows", dup_cnt));
 
-    pb.println(format!("wrote into {}", path2str(&resolv_tbl_writer.path())));
-    pb.set_length(dup_cnt);
-    pb.finish();
+    remove_runs(&dir)
+}
+
+/// `rows` sorted into the next `run_K.bin` of `dir`
+fn write_resolve_run(
+    dir: &Path,
+    rows: &mut Vec<(u32, u32, u32)>,
+    runs: &mut Vec<(TblMeta, PathBuf)>,
+) -> Result<(), io::Error> {
+    rows.sort_unstable();
+
+    let path = Checkpoint::run_path(dir, runs.len());
+    let meta = TblMeta {
+        len: rows.len() as u64,
+        ..BILI2_RESOLVE_TBL_META
+    };
+    let mut writer = TblWriter::create(meta, path.clone())?;
+
+    for (new_key, key, val) in rows.drain(..) {
+        writer.write_row(UInt::U32(new_key), &[UInt::U32(key), UInt::U32(val)])?;
+    }
+    writer.finish()?;
+    runs.push((meta, path));
+
+    Ok(())
+}
+
+/// Table `ty` merged from `runs`, which are removed
+fn write_resolve_tbl(
+    root: &Path,
+    ty: TblTy,
+    runs: &mut Vec<(TblMeta, PathBuf)>,
+    pb: &ProgressBar,
+) -> Result<(), io::Error> {
+    let path = ty.path_in(root);
+
+    if runs.is_empty() {
+        let meta = TblMeta {
+            len: 0,
+            ..BILI2_RESOLVE_TBL_META
+        };
+        TblWriter::create(meta, path.clone())?.finish()?;
+    } else {
+        merge_tables(runs, path.clone(), &ProgressBar::hidden())?;
+    }
+    pb.println(format!("wrote into {}", path2str(&path)));
+
+    for (_, run) in runs.drain(..) {
+        remove_file(run)?;
+    }
+
+    Ok(())
 }
 
 
 #[cfg(test)]
 mod tests {
-    use super::gen_data_bili2;
+    use std::{
+        env::temp_dir,
+        fs::{create_dir_all, read, read_dir, remove_dir_all, remove_file, write, OpenOptions},
+        io,
+        path::Path,
+    };
+
+    use indicatif::ProgressBar;
+    use m6coll::Entry;
+
+    use super::{
+        check_digest, covering_table, gen_bundle, gen_data_bili2, gen_data_bili2_in, gen_dup_in,
+        gen_group_in, gen_resolve_in, gen_run, part_path, Checkpoint, CollisionResolver,
+        DBWriter, MergeReader, TblReader, TblWriter, BILI2_HASHUID_TBL_META, BUNDLE_LEN,
+        CHECKPOINT_NAME, DIGEST_LEN,
+    };
+    use crate::{
+        data::{DBMeta2, GroupMember, TblMeta, UInt},
+        hash::rehash_bili2,
+        query::load_family_in,
+        shell::{TblFamily, TblTy, BUNDLE_NAME},
+    };
+
+    /// Normal tables under `root`, one per list of (hash, uid)
+    fn write_normal(root: &Path, tbls: &[&[(u32, u32)]]) {
+        let mut db = DBWriter::init_in(root, BILI2_HASHUID_TBL_META, TblTy::Normal(0));
+
+        for rows in tbls {
+            let mut w = db.nxt_tbl_writer().unwrap();
+            for (key, uid) in rows.iter() {
+                w.write_row(UInt::U32(*key), &[UInt::U32(*uid)]).unwrap();
+            }
+            w.finish().unwrap();
+        }
+    }
+
+    /// Rows of the tables of `family` under `root`, table by table
+    fn read_family(root: &Path, family: TblFamily) -> Vec<(u32, u64)> {
+        let mut rows = vec![];
+
+        for (ty, meta) in load_family_in(root, family).0.iter() {
+            assert_eq!(check_digest(&ty.path_in(root), *meta).unwrap(), Some(true));
+
+            let mut reader = TblReader::open(*meta, &ty.path_in(root)).unwrap();
+            while let Some(Entry(key, val)) = reader.try_read_item().unwrap() {
+                rows.push((key.into_u32(), val.widen()));
+            }
+        }
+
+        rows
+    }
+
+    #[test]
+    fn test_tbl_writer_atomic() {
+        let dir = temp_dir().join(format!("hh_writer_{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+
+        let path = dir.join("db.bin");
+        let meta = TblMeta {
+            len: 10,
+            keysize: 4,
+            valsize: 4,
+            valcols: 0,
+        };
+        let write = |rows: u32| {
+            let mut w = TblWriter::create(meta, path.clone()).unwrap();
+            for x in 0..rows {
+                w.write_row(UInt::U32(x), &[UInt::U32(x)]).unwrap();
+            }
+            w
+        };
+
+        let w = write(3);
+        assert!(!path.exists() && part_path(&path).exists());
+        let (written, digest) = w.finish().unwrap();
+        assert_eq!((written.len, digest), (3, write(3).digest()));
+        assert_eq!(read(&path).unwrap().len() as u64, written.tbl_bytes() + DIGEST_LEN);
+        assert_eq!(check_digest(&path, written).unwrap(), Some(true));
+        assert!(!part_path(&path).exists());
+
+        // an unfinished rewrite leaves the table as it was
+        drop(write(5));
+        assert_eq!(read(&path).unwrap().len() as u64, written.tbl_bytes() + DIGEST_LEN);
+        assert_eq!(read_dir(&dir).unwrap().count(), 1);
+
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_gen_bundle_resume() {
+        let dir = temp_dir().join(format!("hh_gen_{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let files = || read_dir(&dir).unwrap().count();
+
+        gen_bundle(&dir, 1, 1000, 300, false).unwrap();
+        let fresh = read(dir.join(BUNDLE_NAME)).unwrap();
+        assert_eq!(fresh.len() as u64, 16 + 1000 * 8 + DIGEST_LEN);
+        assert_eq!(files(), 1);
+        remove_file(dir.join(BUNDLE_NAME)).unwrap();
+
+        // interrupted while writing the third run, the second cut short
+        let pb = ProgressBar::hidden();
+        gen_run(&dir, 0, 1, 300, &pb).unwrap();
+        gen_run(&dir, 1, 301, 600, &pb).unwrap();
+        let ckpt = Checkpoint {
+            lo: 1,
+            hi: 1000,
+            run_len: 300,
+            runs: vec![(1, 300), (301, 600)],
+        };
+        ckpt.save(&dir.join(CHECKPOINT_NAME)).unwrap();
+        assert_eq!(Checkpoint::load(&dir.join(CHECKPOINT_NAME)).unwrap(), Some(ckpt));
+
+        let run = OpenOptions::new().write(true).open(Checkpoint::run_path(&dir, 1)).unwrap();
+        run.set_len(100).unwrap();
+        write(dir.join("run_2.bin.part"), b"partial").unwrap();
+
+        assert!(gen_bundle(&dir, 1, 999, 300, true).is_err());
+
+        gen_bundle(&dir, 1, 1000, 300, true).unwrap();
+        assert_eq!(read(dir.join(BUNDLE_NAME)).unwrap(), fresh);
+        assert_eq!(files(), 1);
+
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_read_run() {
+        let root = temp_dir().join(format!("hh_merge_{}", std::process::id()));
+        write_normal(&root, &[&[(5, 1), (7, 2), (9, 3)], &[(5, 104), (6, 105), (9, 101), (9, 107)]]);
+
+        let dbmeta = load_family_in(&root, TblFamily::Normal);
+        let mut merger = MergeReader::open(&root, &dbmeta).unwrap();
+        let mut runs = vec![];
+
+        while let Some((key, run)) = merger.read_run().unwrap() {
+            let run: Vec<(u32, usize)> = run.into_iter().map(|(val, i)| (val.into_u32(), i)).collect();
+            runs.push((key.into_u32(), run));
+        }
+
+        assert_eq!(
+            runs,
+            vec![
+                (5, vec![(1, 0), (104, 1)]),
+                (6, vec![(105, 1)]),
+                (7, vec![(2, 0)]),
+                (9, vec![(3, 0), (101, 1), (107, 1)]),
+            ]
+        );
+        assert!(merger.read_run().unwrap().is_none());
+
+        // a table cut short after loading fails the merge
+        let path = dbmeta.0[1].0.path_in(&root);
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(16 + 8 * 2).unwrap();
+        let mut merger = MergeReader::open(&root, &dbmeta).unwrap();
+        let err = loop {
+            match merger.read_run() {
+                Ok(Some(_)) => continue,
+                Ok(None) => panic!("merge ended early"),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_gen_collision_group() {
+        let root = temp_dir().join(format!("hh_group_{}", std::process::id()));
+        write_normal(&root, &[&[(5, 1), (7, 2), (9, 3)], &[(5, 104), (6, 105), (9, 101), (9, 107)]]);
+
+        let dbmeta = load_family_in(&root, TblFamily::Normal);
+        let pb = ProgressBar::hidden();
+        gen_group_in(&root, &dbmeta, &pb).unwrap();
+        gen_dup_in(&root, &dbmeta, &pb).unwrap();
+
+        let member = |uid, size| GroupMember { uid, size }.to_uint().into_u64();
+
+        // every member of a group, each with the size of its group
+        assert_eq!(
+            read_family(&root, TblFamily::Group),
+            vec![
+                (5, member(1, 2)),
+                (5, member(104, 2)),
+                (9, member(3, 3)),
+                (9, member(101, 3)),
+                (9, member(107, 3)),
+            ]
+        );
+        // all but the lowest uid of each hash, sorted
+        assert_eq!(
+            read_family(&root, TblFamily::Dup),
+            vec![(5, 104), (9, 101), (9, 107)]
+        );
+
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_gen_collision_resolve() {
+        let root = temp_dir().join(format!("hh_resolve_{}", std::process::id()));
+        let mut db = DBWriter::init_in(&root, BILI2_HASHUID_TBL_META, TblTy::Dup(0));
+        let dups: [&[(u32, u32)]; 2] = [&[(5, 104), (9, 101)], &[(6, 200), (8, 201), (9, 107)]];
+
+        for rows in dups {
+            let mut w = db.nxt_tbl_writer().unwrap();
+            for (key, uid) in rows.iter() {
+                w.write_row(UInt::U32(*key), &[UInt::U32(*uid)]).unwrap();
+            }
+            w.finish().unwrap();
+        }
+
+        // runs of 2 rows, tables of 3
+        let dbmeta = load_family_in(&root, TblFamily::Dup);
+        gen_resolve_in(&root, &dbmeta, 2, 3, &ProgressBar::hidden()).unwrap();
+
+        // the dup rows in order, each table sorted by resolved key
+        let mut expected = vec![];
+        for rows in dups.concat().chunks(3) {
+            let mut tbl: Vec<(u32, u64)> = rows
+                .iter()
+                .map(|(key, uid)| (rehash_bili2(*key), *uid as u64))
+                .collect();
+            tbl.sort_unstable();
+            expected.extend(tbl);
+        }
+        assert_eq!(load_family_in(&root, TblFamily::Resolve).0.len(), 2);
+        assert_eq!(read_family(&root, TblFamily::Resolve), expected);
+
+        let dir = TblTy::Resolve(0, CollisionResolver::Rehash).path_in(&root);
+        assert_eq!(read_dir(dir.parent().unwrap()).unwrap().count(), 2);
+
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_covering_table() {
+        let b = BUNDLE_LEN as u64;
+        let meta = |len| TblMeta { len, ..BILI2_HASHUID_TBL_META };
+        let range = |id: u64| (id * b + 1, (id + 1) * b);
+
+        // data0 compacted with data1, data3 cut short
+        let dbmeta = DBMeta2(vec![
+            (TblTy::Normal(0), meta(2 * b)),
+            (TblTy::Normal(3), meta(10)),
+        ]);
+        let covering = |id: u32| {
+            let (lo, hi) = range(id as u64);
+            covering_table(&dbmeta, id, lo, hi).map(|ty| ty.id())
+        };
+
+        assert_eq!(covering(0), None);
+        assert_eq!(covering(1), Some(0));
+        assert_eq!(covering(2), None);
+        assert_eq!(covering(3), None);
+        assert_eq!(covering(4), None);
+    }
 
     #[test]
     fn test_container() {
@@ -368,8 +1408,30 @@ mod tests {
     }
 
     #[test]
+    #[ignore = "generates a full table of BUNDLE_LEN rows"]
     fn test_run_bili2() {
-        gen_data_bili2(0);
+        let root = temp_dir().join(format!("hh_bili2_{}", std::process::id()));
+        create_dir_all(&root).unwrap();
+
+        gen_data_bili2_in(&root, 0, false).unwrap();
+
+        let dbmeta = load_family_in(&root, TblFamily::Normal);
+        assert_eq!(dbmeta.0.len(), 1);
+        let (ty, meta) = dbmeta.0[0];
+        assert!(matches!(ty, TblTy::Normal(0)));
+        assert_eq!(meta.len, BUNDLE_LEN as u64);
+        assert_eq!(check_digest(&ty.path_in(&root), meta).unwrap(), Some(true));
+
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_bili2_past_uid_range() {
+        let past = (u32::MAX as u64 / BUNDLE_LEN as u64 + 1) as u32;
+        assert_eq!(
+            gen_data_bili2(past, false).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
     }
 
     #[test]
@@ -0,0 +1,369 @@
+//! Hash Functions
+//!
+//! midHash = CRC32(uid.to_string()), the generator and every resolver go
+//! through here, `Crc32` is the parameterised (Rocksoft model) variant used
+//! to search for other hypotheses.
+//!
+
+use std::fmt::Display;
+
+use clap::ArgEnum;
+use crc32fast::hash as crc32;
+use serde_derive::Serialize;
+
+use crate::gendata::CollisionResolver;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CrcParams {
+    pub poly: u32,
+    pub init: u32,
+    pub refin: bool,
+    pub refout: bool,
+    pub xorout: u32,
+}
+
+pub struct Crc32 {
+    pub params: CrcParams,
+    table: [u32; 256],
+}
+
+/// How an intermediate hash is turned back into bytes when re-hashing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ArgEnum)]
+pub enum RehashFmt {
+    Dec,
+    Hex,
+}
+
+/// Full hash hypothesis: `depth` rounds of crc over `template` with
+/// `{uid}`/`{salt}` substituted
+#[derive(Clone, Debug)]
+pub struct HashSpec {
+    pub params: CrcParams,
+    pub template: String,
+    pub salt: String,
+    pub depth: u32,
+    pub fmt: RehashFmt,
+}
+
+/// How `uid` reaches `hash`: directly, or through collision resolvers
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct PairCheck {
+    pub hash: u32,
+    pub uid: u32,
+    /// hash of the uid under the spec
+    pub computed: u32,
+    pub direct: bool,
+    /// resolvers taking `computed` to `hash`, under the spec
+    pub resolvers: Vec<String>,
+}
+
+
+impl CrcParams {
+    /// The one bilibili uses for midHash (same as `crc32fast`)
+    pub const CRC32: Self = Self {
+        poly: 0x04C1_1DB7,
+        init: 0xFFFF_FFFF,
+        refin: true,
+        refout: true,
+        xorout: 0xFFFF_FFFF,
+    };
+
+    /// Known 32-bit polynomials
+    pub const POLYS: [(&'static str, u32); 5] = [
+        ("CRC-32", 0x04C1_1DB7),
+        ("CRC-32C", 0x1EDC_6F41),
+        ("CRC-32K", 0x741B_8CD7),
+        ("CRC-32Q", 0x8141_41AB),
+        ("CRC-32/XFER", 0x0000_00AF),
+    ];
+}
+
+impl Display for CrcParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "poly={:08x} init={:08x} refin={} refout={} xorout={:08x}",
+            self.poly, self.init, self.refin, self.refout, self.xorout
+        )
+    }
+}
+
+
+impl Crc32 {
+    pub fn new(params: CrcParams) -> Self {
+        let mut table = [0u32; 256];
+
+        for (i, slot) in table.iter_mut().enumerate() {
+            let mut crc = (i as u32) << 24;
+
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ params.poly
+                } else {
+                    crc << 1
+                };
+            }
+
+            *slot = crc;
+        }
+
+        Self { params, table }
+    }
+
+    pub fn checksum(&self, bytes: &[u8]) -> u32 {
+        let mut crc = self.params.init;
+
+        for b in bytes.iter() {
+            let b = if self.params.refin { b.reverse_bits() } else { *b };
+            let idx = ((crc >> 24) as u8 ^ b) as usize;
+
+            crc = (crc << 8) ^ self.table[idx];
+        }
+
+        if self.params.refout {
+            crc = crc.reverse_bits();
+        }
+
+        crc ^ self.params.xorout
+    }
+}
+
+
+impl RehashFmt {
+    pub fn render(&self, hashval: u32) -> String {
+        match self {
+            RehashFmt::Dec => hashval.to_string(),
+            RehashFmt::Hex => format!("{:08x}", hashval),
+        }
+    }
+}
+
+impl Display for RehashFmt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RehashFmt::Dec => write!(f, "dec"),
+            RehashFmt::Hex => write!(f, "hex"),
+        }
+    }
+}
+
+
+impl HashSpec {
+    /// What `hash_bili2` computes
+    pub fn bili2() -> Self {
+        Self {
+            params: CrcParams::CRC32,
+            template: "{uid}".to_string(),
+            salt: String::new(),
+            depth: 1,
+            fmt: RehashFmt::Dec,
+        }
+    }
+
+    pub fn render(template: &str, salt: &str, uid: u32) -> String {
+        template
+            .replace("{salt}", salt)
+            .replace("{uid}", &uid.to_string())
+    }
+
+    pub fn hash_with(&self, crc: &Crc32, uid: u32) -> u32 {
+        let s = Self::render(&self.template, &self.salt, uid);
+        let mut hashval = crc.checksum(s.as_bytes());
+
+        for _ in 1..self.depth {
+            hashval = crc.checksum(self.fmt.render(hashval).as_bytes());
+        }
+
+        hashval
+    }
+
+    pub fn hash(&self, uid: u32) -> u32 {
+        self.hash_with(&Crc32::new(self.params), uid)
+    }
+
+    /// One more round over a hash, the `Rehash` resolver under this spec
+    pub fn rehash(&self, hashval: u32) -> u32 {
+        Crc32::new(self.params).checksum(self.fmt.render(hashval).as_bytes())
+    }
+}
+
+impl Display for HashSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "crc32({}) template={:?} salt={:?} depth={} fmt={}",
+            self.params, self.template, self.salt, self.depth, self.fmt
+        )
+    }
+}
+
+
+impl PairCheck {
+    pub fn ok(&self) -> bool {
+        self.direct || !self.resolvers.is_empty()
+    }
+}
+
+impl Display for PairCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.direct {
+            return write!(f, "ok: {} -> {:08x}", self.uid, self.hash);
+        }
+
+        if self.resolvers.is_empty() {
+            return write!(
+                f,
+                "mismatch: {} -> {:08x}, not {:08x}",
+                self.uid, self.computed, self.hash
+            );
+        }
+
+        write!(
+            f,
+            "ok: {} -> {:08x} -> {:08x} ({})",
+            self.uid,
+            self.computed,
+            self.hash,
+            self.resolvers.join(", ")
+        )
+    }
+}
+
+
+/// Hash of each uid under `spec`
+pub fn hash_uids(spec: &HashSpec, uids: &[u32]) -> Vec<u32> {
+    let crc = Crc32::new(spec.params);
+
+    uids.iter().map(|uid| spec.hash_with(&crc, *uid)).collect()
+}
+
+/// Whether `hash` comes from `uid` under `spec`, directly or through each
+/// collision resolver
+pub fn check_pair(spec: &HashSpec, hash: u32, uid: u32) -> PairCheck {
+    let computed = spec.hash(uid);
+
+    PairCheck {
+        hash,
+        uid,
+        computed,
+        direct: computed == hash,
+        resolvers: CollisionResolver::ALL
+            .iter()
+            .filter(|resolve| resolve.resolve_with(spec, computed, uid) == hash)
+            .map(|resolve| resolve.to_string())
+            .collect(),
+    }
+}
+
+
+/// `s` without a leading `0x`/`0X`
+pub(crate) fn strip_hex_prefix(s: &str) -> &str {
+    s.strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s)
+}
+
+/// Parse a midHash, tolerant of `0x`, uppercase and missing leading zeros
+pub fn parse_hash(s: &str) -> Option<u32> {
+    let s = strip_hex_prefix(s.trim());
+
+    if s.is_empty() || s.len() > 8 || s.starts_with('+') {
+        return None;
+    }
+
+    u32::from_str_radix(s, 16).ok()
+}
+
+/// midHash of `uid`
+#[inline]
+pub fn hash_bili2(uid: u32) -> u32 {
+    crc32(uid.to_string().as_bytes())
+}
+
+/// Hash(Hash(x)), as tried by `CollisionResolver::Rehash`
+#[inline]
+pub fn rehash_bili2(hashval: u32) -> u32 {
+    crc32(hashval.to_string().as_bytes())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_pair, hash_bili2, hash_uids, parse_hash, rehash_bili2, Crc32,
+        CrcParams, HashSpec, RehashFmt,
+    };
+
+    #[test]
+    fn test_crc_params() {
+        let crc = Crc32::new(CrcParams::CRC32);
+
+        assert_eq!(crc.checksum(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc.checksum(b"6487381"), hash_bili2(6487381));
+
+        let crc32c = Crc32::new(CrcParams {
+            poly: 0x1EDC_6F41,
+            ..CrcParams::CRC32
+        });
+        assert_eq!(crc32c.checksum(b"123456789"), 0xE306_9283);
+
+        let bzip2 = Crc32::new(CrcParams {
+            refin: false,
+            refout: false,
+            ..CrcParams::CRC32
+        });
+        assert_eq!(bzip2.checksum(b"123456789"), 0xFC89_1918);
+    }
+
+    #[test]
+    fn test_parse_hash() {
+        assert_eq!(parse_hash("c4ff7ac1"), Some(0xc4ff7ac1));
+        assert_eq!(parse_hash(" 0xC4FF7AC1\n"), Some(0xc4ff7ac1));
+        assert_eq!(parse_hash("d9e2cc6"), Some(0x0d9e2cc6));
+        assert_eq!(parse_hash("0x"), None);
+        assert_eq!(parse_hash("+1"), None);
+        assert_eq!(parse_hash("123456789"), None);
+        assert_eq!(parse_hash("xyz"), None);
+    }
+
+    #[test]
+    fn test_hash_spec() {
+        let mut spec = HashSpec::bili2();
+
+        assert_eq!(spec.hash(6487381), 0x55f5_defa);
+
+        spec.depth = 2;
+        assert_eq!(spec.hash(6487381), rehash_bili2(hash_bili2(6487381)));
+    }
+
+    #[test]
+    fn test_check_pair() {
+        let spec = HashSpec::bili2();
+
+        assert_eq!(hash_uids(&spec, &[6487381, 12]), vec![0x55f5_defa, hash_bili2(12)]);
+
+        let check = check_pair(&spec, 0x55f5_defa, 6487381);
+        assert!(check.ok() && check.direct && check.resolvers.is_empty());
+
+        let check = check_pair(&spec, rehash_bili2(0x55f5_defa), 6487381);
+        assert!(check.ok() && !check.direct);
+        assert_eq!(check.resolvers, vec!["Rehash"]);
+
+        let check = check_pair(&spec, 0x55f5_defa, 6487382);
+        assert!(!check.ok());
+        assert_eq!(check.to_string(), format!("mismatch: 6487382 -> {:08x}, not 55f5defa", hash_bili2(6487382)));
+
+        // the resolver step rehashes under the spec, not as bili2
+        let spec = HashSpec {
+            fmt: RehashFmt::Hex,
+            ..HashSpec::bili2()
+        };
+        assert_ne!(spec.rehash(0x55f5_defa), rehash_bili2(0x55f5_defa));
+
+        let check = check_pair(&spec, spec.rehash(0x55f5_defa), 6487381);
+        assert_eq!(check.resolvers, vec!["Rehash"]);
+        assert!(!check_pair(&spec, rehash_bili2(0x55f5_defa), 6487381).ok());
+    }
+}
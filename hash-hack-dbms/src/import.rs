@@ -0,0 +1,296 @@
+//! Import
+//!
+//! Hash -> uid pairs collected from other sources (a csv with a header line,
+//! e.g. `test_data/bili2/bullet.csv`) written as `Import` tables
+//! (`data_import/db_import_N.bin`, laid out as the normal ones) and queried
+//! with the other families. Rows with a blank uid carry no pair and are
+//! skipped, the same pair twice is kept once.
+//!
+
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use clap::ArgEnum;
+
+use crate::{
+    data::UInt,
+    gendata::{DBWriter, BILI2_HASHUID_TBL_META},
+    hash::parse_hash,
+    query::load_family_in,
+    shell::{TblFamily, TblTy, CWD},
+};
+
+
+/// How the hash column is written, a `0x` prefix is always hex
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ArgEnum)]
+pub enum Radix {
+    Hex,
+    Dec,
+}
+
+/// Column by header name or 0-based index
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Column {
+    Index(usize),
+    Name(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct ImportSpec {
+    pub hash_col: Column,
+    pub uid_col: Column,
+    pub hash_radix: Radix,
+    pub delimiter: char,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub rows: usize,
+    /// rows without a uid
+    pub blank: usize,
+    /// pairs written
+    pub pairs: usize,
+    pub tables: Vec<PathBuf>,
+}
+
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// `0x` hex or decimal
+fn parse_uint(s: &str, radix: Radix) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None if radix == Radix::Hex => parse_hash(s),
+        None => s.parse().ok(),
+    }
+}
+
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty column".to_string());
+        }
+
+        Ok(s.parse()
+            .map(Self::Index)
+            .unwrap_or_else(|_| Self::Name(s.to_string())))
+    }
+}
+
+impl Column {
+    fn index(&self, header: &[&str]) -> Result<usize, io::Error> {
+        match self {
+            Self::Index(i) => Ok(*i),
+            Self::Name(name) => header
+                .iter()
+                .position(|x| x.eq_ignore_ascii_case(name))
+                .ok_or_else(|| {
+                    invalid_data(format!("no column {:?} in {:?}", name, header))
+                }),
+        }
+    }
+}
+
+impl Default for ImportSpec {
+    /// `hashid,uid` of `bullet.csv`
+    fn default() -> Self {
+        Self {
+            hash_col: Column::Index(0),
+            uid_col: Column::Index(1),
+            hash_radix: Radix::Hex,
+            delimiter: ',',
+        }
+    }
+}
+
+impl Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} rows, {} without uid, {} pairs",
+            self.rows, self.blank, self.pairs
+        )
+    }
+}
+
+
+/// (hash, uid) of each row with a uid, in input order, and the report
+pub fn parse_pairs<R: BufRead>(
+    reader: R,
+    spec: &ImportSpec,
+) -> Result<(Vec<(u32, u32)>, ImportReport), io::Error> {
+    let mut pairs = vec![];
+    let mut rep = ImportReport::default();
+    let mut cols = None;
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let fields: Vec<&str> = line
+            .split(spec.delimiter)
+            .map(|x| x.trim().trim_matches('"').trim())
+            .collect();
+
+        let (hash_col, uid_col) = match cols {
+            Some(cols) => cols,
+            None => {
+                cols = Some((spec.hash_col.index(&fields)?, spec.uid_col.index(&fields)?));
+                continue;
+            }
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+        rep.rows += 1;
+
+        let invalid = || invalid_data(format!("line {}: invalid row {:?}", i + 1, line));
+
+        let hash = fields.get(hash_col).ok_or_else(invalid)?;
+        let hash = parse_uint(hash, spec.hash_radix).ok_or_else(invalid)?;
+
+        match fields.get(uid_col) {
+            Some(uid) if !uid.is_empty() => {
+                let uid = parse_uint(uid, Radix::Dec).ok_or_else(invalid)?;
+                pairs.push((hash, uid));
+            }
+            _ => rep.blank += 1,
+        }
+    }
+
+    Ok((pairs, rep))
+}
+
+/// Sort the pairs of the csv at `path` into new `Import` tables, numbered
+/// after the existing ones
+pub fn import_bili2(path: &Path, spec: &ImportSpec) -> Result<ImportReport, io::Error> {
+    import_in(Path::new(CWD), path, spec)
+}
+
+/// `import_bili2` into the database under `root`
+fn import_in(root: &Path, path: &Path, spec: &ImportSpec) -> Result<ImportReport, io::Error> {
+    let (mut pairs, mut rep) = parse_pairs(BufReader::new(File::open(path)?), spec)?;
+
+    pairs.sort_unstable();
+    pairs.dedup();
+    rep.pairs = pairs.len();
+
+    if pairs.is_empty() {
+        return Ok(rep);
+    }
+
+    let id = load_family_in(root, TblFamily::Import)
+        .0
+        .last()
+        .map(|(ty, _)| ty.id() + 1)
+        .unwrap_or_default();
+
+    let mut db_writer = DBWriter::init_in(root, BILI2_HASHUID_TBL_META, TblTy::Import(id));
+    let mut tbl_writer = db_writer.nxt_tbl_writer()?;
+
+    for (hash, uid) in pairs {
+        if tbl_writer.is_end() {
+            rep.tables.push(tbl_writer.path());
+            tbl_writer.finish()?;
+            tbl_writer = db_writer.nxt_tbl_writer()?;
+        }
+
+        tbl_writer
+            .write_row(UInt::U32(hash), &[UInt::U32(uid)])
+            .unwrap();
+    }
+    rep.tables.push(tbl_writer.path());
+    tbl_writer.finish()?;
+
+    Ok(rep)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        fs::{create_dir_all, remove_dir_all, write},
+    };
+
+    use super::{import_in, parse_pairs, Column, ImportSpec, Radix};
+    use crate::{
+        catalog::{Catalog, QueryPlan},
+        shell::{TblFamily, TblTy},
+    };
+
+    #[test]
+    fn test_parse_pairs() {
+        let csv = "hashid,uid\n\
+                   c4ff7ac1,\n\
+                   d9e2cc6,\n\
+                   55f5defa,6487381\n\
+                   \n\
+                   0x1388875d, 0x1532d\n";
+
+        let (pairs, rep) = parse_pairs(csv.as_bytes(), &ImportSpec::default()).unwrap();
+        assert_eq!(pairs, vec![(0x55f5defa, 6487381), (0x1388875d, 86829)]);
+        assert_eq!((rep.rows, rep.blank), (4, 2));
+
+        let spec = ImportSpec {
+            hash_col: "Hash".parse().unwrap(),
+            uid_col: Column::Name("mid".to_string()),
+            hash_radix: Radix::Dec,
+            delimiter: '\t',
+        };
+        let tsv = "mid\thash\n\"6487381\"\t1442176762\n";
+        let (pairs, _) = parse_pairs(tsv.as_bytes(), &spec).unwrap();
+        assert_eq!(pairs, vec![(0x55f5defa, 6487381)]);
+
+        assert!(parse_pairs("mid,hash\n1,2\n".as_bytes(), &ImportSpec {
+            uid_col: Column::Name("uid".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(parse_pairs("hashid,uid\nxyz,1\n".as_bytes(), &ImportSpec::default()).is_err());
+    }
+
+    #[test]
+    fn test_import() {
+        let root = temp_dir().join(format!("hh_import_{}", std::process::id()));
+        create_dir_all(&root).unwrap();
+
+        let csv = root.join("pairs.csv");
+        let spec = ImportSpec::default();
+
+        write(&csv, "hashid,uid\n55f5defa,6487381\n1388875d,86829\n55f5defa,6487381\nc4ff7ac1,\n").unwrap();
+        let rep = import_in(&root, &csv, &spec).unwrap();
+        assert_eq!((rep.rows, rep.blank, rep.pairs), (4, 1, 2));
+        assert_eq!(rep.tables, vec![TblTy::Import(0).path_in(&root)]);
+
+        // numbered after the tables already there
+        write(&csv, "hashid,uid\n1388875d,7\n").unwrap();
+        let rep = import_in(&root, &csv, &spec).unwrap();
+        assert_eq!(rep.tables, vec![TblTy::Import(1).path_in(&root)]);
+
+        write(&csv, "hashid,uid\nc4ff7ac1,\n").unwrap();
+        assert!(import_in(&root, &csv, &spec).unwrap().tables.is_empty());
+
+        let catalog = Catalog::load_in(&root);
+        let plan = QueryPlan::default().restrict(&[TblFamily::Import]);
+        let uids: Vec<Vec<u32>> = catalog
+            .query(&plan, &[0x1388875d, 0x55f5defa, 0xc4ff7ac1])
+            .unwrap()
+            .into_iter()
+            .map(|cands| cands.into_iter().map(|cand| cand.uid).collect())
+            .collect();
+        assert_eq!(uids, vec![vec![86829, 7], vec![6487381], vec![]]);
+
+        remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,331 @@
+//! Table Inspection
+//!
+//! The parsed header of a table file, checked against its size and the
+//! digest stored after the rows, and its rows by row number or key, for
+//! debugging generator output.
+//!
+
+use std::{
+    fmt::Display,
+    fs::{metadata, File},
+    io::{self, Read, Write},
+    mem::size_of,
+    path::Path,
+};
+
+use serde_derive::Serialize;
+
+use crate::{
+    data::{TblMeta, UInt},
+    gendata::{check_digest, TblReader, DIGEST_LEN},
+    output::{DumpRecord, Format},
+    query::TblCursor,
+    shell::{path2str, TblFamily, TblTy},
+};
+
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TblHeader {
+    pub path: String,
+    /// `None` if the path is not laid out as in the catalog
+    pub table: Option<TblFamily>,
+    pub id: Option<u32>,
+    pub len: u64,
+    pub keysize: u32,
+    pub valsize: u16,
+    pub valcols: u16,
+    /// bytes of a row
+    pub unit_len: u32,
+    /// bytes the header accounts for
+    pub bytes: u64,
+    pub file_bytes: u64,
+    pub first_key: Option<String>,
+    pub last_key: Option<String>,
+    /// rows match the stored digest, `None` without one
+    pub digest_ok: Option<bool>,
+}
+
+/// Rows to dump, bounds inclusive, keys only used without rows
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DumpRange {
+    pub from_row: Option<u64>,
+    pub to_row: Option<u64>,
+    pub from_key: Option<u32>,
+    pub to_key: Option<u32>,
+}
+
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_meta(path: &Path) -> Result<TblMeta, io::Error> {
+    let mut buf = [0; size_of::<TblMeta>()];
+    File::open(path)?.read_exact(&mut buf).map_err(|_| {
+        invalid_data(format!("{}: shorter than a table header", path.display()))
+    })?;
+
+    let meta = unsafe { TblMeta::from_raw(buf.as_mut_ptr()) };
+
+    // `UInt` only has 4 and 8 byte widths
+    if ![4, 8].contains(&meta.keysize) || ![4, 8].contains(&meta.valsize) {
+        return Err(invalid_data(format!(
+            "{}: not a table (keysize {}, valsize {})",
+            path.display(),
+            meta.keysize,
+            meta.valsize
+        )));
+    }
+
+    if meta.checked_tbl_bytes().is_none() {
+        return Err(invalid_data(format!(
+            "{}: corrupt header, {} rows of {} bytes overflow",
+            path.display(),
+            meta.len,
+            meta.unit_len()
+        )));
+    }
+
+    Ok(meta)
+}
+
+
+impl TblHeader {
+    /// The file holds exactly the rows the header claims (and maybe their
+    /// digest), an interrupted write leaves fewer
+    pub fn is_consistent(&self) -> bool {
+        self.file_bytes == self.bytes || self.file_bytes == self.bytes + DIGEST_LEN
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.is_consistent() && self.digest_ok != Some(false)
+    }
+}
+
+impl Display for TblHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}:", self.path)?;
+
+        match (self.table, self.id) {
+            (Some(table), Some(id)) => writeln!(f, "  table     {:?} {}", table, id)?,
+            _ => writeln!(f, "  table     -")?,
+        }
+        writeln!(f, "  len       {}", self.len)?;
+        writeln!(f, "  keysize   {}", self.keysize)?;
+        writeln!(f, "  valsize   {}", self.valsize)?;
+        writeln!(f, "  valcols   {}", self.valcols)?;
+        writeln!(f, "  unit_len  {}", self.unit_len)?;
+        writeln!(
+            f,
+            "  bytes     {} (file {}{})",
+            self.bytes,
+            self.file_bytes,
+            if self.is_consistent() { "" } else { ", MISMATCH" }
+        )?;
+        writeln!(
+            f,
+            "  keys      {} ..= {}",
+            self.first_key.as_deref().unwrap_or("-"),
+            self.last_key.as_deref().unwrap_or("-")
+        )?;
+        writeln!(
+            f,
+            "  digest    {}",
+            match self.digest_ok {
+                Some(true) => "ok",
+                Some(false) => "MISMATCH",
+                None => "-",
+            }
+        )
+    }
+}
+
+
+/// Header of the table file at `path`, keys are read and the digest checked
+/// only if the file holds every row
+pub fn inspect(path: &Path) -> Result<TblHeader, io::Error> {
+    let meta = read_meta(path)?;
+    let ty = TblTy::of_path(path);
+    let file_bytes = metadata(path)?.len();
+
+    let mut header = TblHeader {
+        path: path2str(path),
+        table: ty.map(|ty| ty.family()),
+        id: ty.map(|ty| ty.id()),
+        len: meta.len,
+        keysize: meta.keysize,
+        valsize: meta.valsize,
+        valcols: meta.valcols,
+        unit_len: meta.unit_len(),
+        bytes: meta.tbl_bytes(),
+        file_bytes,
+        first_key: None,
+        last_key: None,
+        digest_ok: None,
+    };
+
+    if header.is_consistent() {
+        header.digest_ok = check_digest(path, meta).unwrap_or(Some(false));
+    }
+
+    if meta.len > 0 && header.bytes <= file_bytes {
+        let mut cursor = TblCursor::new(File::open(path)?, meta);
+        let key_str = |key: UInt| format!("{:08x}", key.widen());
+
+        header.first_key = Some(key_str(cursor.key_at(0)?));
+        header.last_key = Some(key_str(cursor.key_at(meta.len - 1)?));
+    }
+
+    Ok(header)
+}
+
+/// Rows of the table at `path` in `range`, number written
+pub fn dump<W: Write>(
+    w: &mut W,
+    fmt: Format,
+    path: &Path,
+    range: DumpRange,
+) -> Result<u64, io::Error> {
+    let meta = read_meta(path)?;
+
+    if meta.tbl_bytes() > metadata(path)?.len() {
+        return Err(invalid_data(format!(
+            "{}: header claims {} rows, file is truncated",
+            path.display(),
+            meta.len
+        )));
+    }
+
+    let start = match (range.from_row, range.from_key) {
+        (Some(row), _) => row,
+        (None, Some(key)) => TblCursor::new(File::open(path)?, meta)
+            .lower_bound(UInt::U32(key), 0, meta.len)?,
+        (None, None) => 0,
+    };
+
+    let mut reader = TblReader::open(meta, path)?;
+    reader.seek_row(start)?;
+
+    fmt.write_header::<_, DumpRecord>(w)?;
+
+    let mut row = start;
+    let mut cnt = 0;
+
+    while let Some(item) = reader.try_read_item()? {
+        if range.to_row.is_some_and(|to| row > to) {
+            break;
+        }
+
+        let key = item.0.widen();
+        if range.from_row.is_none()
+            && range.to_key.is_some_and(|to| key > to as u64)
+        {
+            break;
+        }
+
+        let rec = DumpRecord {
+            row,
+            key: format!("{:08x}", key),
+            vals: reader.vals().into_iter().map(|x| x.widen()).collect(),
+        };
+        fmt.write_record(w, &rec)?;
+
+        row += 1;
+        cnt += 1;
+    }
+
+    Ok(cnt)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        fs::{create_dir_all, remove_dir_all, File},
+        io::Write,
+    };
+
+    use m6coll::ToLeBytes;
+
+    use super::{dump, inspect, DumpRange};
+    use crate::{data::TblMeta, output::Format, shell::TblFamily};
+
+    #[test]
+    fn test_inspect_dump() {
+        let dir = temp_dir().join(format!("hh_inspect_{}", std::process::id()));
+        let path = dir.join("data1").join("db.bin");
+        create_dir_all(path.parent().unwrap()).unwrap();
+
+        let meta = TblMeta {
+            len: 5,
+            keysize: 4,
+            valsize: 4,
+            valcols: 2,
+        };
+        let mut f = File::create(&path).unwrap();
+        f.write_all(&meta.to_le_bytes()).unwrap();
+        for (key, orig, uid) in [(1u32, 9u32, 10u32), (3, 9, 11), (3, 8, 12), (7, 7, 13), (9, 6, 14)] {
+            for x in [key, orig, uid] {
+                f.write_all(&x.to_le_bytes()).unwrap();
+            }
+        }
+        drop(f);
+
+        let header = inspect(&path).unwrap();
+        assert_eq!(header.table, Some(TblFamily::Normal));
+        assert_eq!(header.id, Some(1));
+        assert_eq!((header.unit_len, header.bytes), (12, 76));
+        assert!(header.is_consistent());
+        assert_eq!(header.digest_ok, None);
+        assert_eq!(header.last_key.as_deref(), Some("00000009"));
+
+        let mut buf = vec![];
+        let range = DumpRange {
+            from_key: Some(2),
+            to_key: Some(7),
+            ..Default::default()
+        };
+        assert_eq!(dump(&mut buf, Format::Csv, &path, range).unwrap(), 3);
+
+        let range = DumpRange {
+            from_row: Some(4),
+            to_row: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(dump(&mut buf, Format::Plain, &path, range).unwrap(), 1);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "row,key,vals\n\
+             1,00000003,9;11\n\
+             2,00000003,8;12\n\
+             3,00000007,7;13\n\
+             4 00000009 6;14\n"
+        );
+
+        // headers the row readers can't handle
+        for bad in [
+            TblMeta { keysize: 2, ..meta },
+            TblMeta { valsize: 16, ..meta },
+            TblMeta { len: 1 << 62, ..meta },
+        ] {
+            let mut f = File::create(&path).unwrap();
+            f.write_all(&bad.to_le_bytes()).unwrap();
+            drop(f);
+
+            assert!(inspect(&path).is_err());
+            assert!(dump(&mut vec![], Format::Csv, &path, DumpRange::default()).is_err());
+        }
+
+        // a header claiming more rows than written
+        let mut f = File::create(&path).unwrap();
+        f.write_all(&TblMeta { len: 100, ..meta }.to_le_bytes()).unwrap();
+        drop(f);
+
+        assert!(!inspect(&path).unwrap().is_ok());
+        assert!(dump(&mut vec![], Format::Csv, &path, DumpRange::default()).is_err());
+
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,8 +1,21 @@
-
 pub mod gendata;
 pub mod query;
 pub mod shell;
 pub mod data;
+pub mod eval;
+pub mod hash;
+pub mod search;
+pub mod stats;
+pub mod output;
+pub mod catalog;
+pub mod rank;
+pub mod overrides;
+pub mod inspect;
+pub mod export;
+pub mod import;
+pub mod compact;
+pub mod diff;
 
-use shell::*;
+// for `run!`
+pub use shell::runit;
 
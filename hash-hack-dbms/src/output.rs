@@ -0,0 +1,1019 @@
+//! Output Format
+//!
+//! `plain` is for humans, `csv`/`tsv` print a header line then one row per
+//! record, `json` prints one object per record (JSON Lines). Hashes are
+//! 8-digit lowercase hex, missing values are empty (csv/tsv) or `null` (json).
+//!
+//! Schemas:
+//!
+//! - bili2 `hash,uid,table,resolver,orig,also,error,coverage`:
+//!   one record per candidate uid, `table` is `Override`/`Normal`/`Dup`/
+//!   `Group`/`Import`/`Resolve`, `resolver` and `orig` (the hash before resolving)
+//!   are set for `Resolve`, `also` the later tables finding the uid too (`;`
+//!   separated, `Group;Resolve:Rehash:0d9e2cc6`).
+//!   A hash without candidates is one record with an empty `uid` and the
+//!   uid ranges searched in `coverage` (`1..=102400000 204800001..=...`),
+//!   an input which is not a hash is one record with `error` set.
+//! - bili2 `--rank` `hash,uid,score,table,resolver,orig,signals,coverage`:
+//!   one record per candidate, highest score first, `signals` is `;`
+//!   separated (`active:+2;Resolve:-1`), a hash without candidates is one
+//!   record with an empty `uid` and `coverage` set as for bili2
+//! - bili2 `--explain` `hash,table,id,resolver,path,quick_fail,seeks,bytes,io_bytes,micros,rows`:
+//!   one record per table probed, written to stderr, `hash` is every hash
+//!   looked up together (space separated), `bytes` is what the cursor read
+//!   and `io_bytes` what was read from the file under its buffer
+//! - hash `uid,hash`: one record per uid
+//! - check `hash,uid,computed,direct,resolvers`: `resolvers` taking
+//!   `computed` (the hash of `uid`) to `hash`, `;` separated
+//! - locate `uid,hash,path,row,run_row,run_uid`: one record per row of the
+//!   collision run of `hash` (`run_uid == uid` is the row of the uid), `path`
+//!   is empty if no table covers the uid
+//! - export `hash,uid,table,id,resolver,orig`: one record per table row,
+//!   `id` is the table in its family
+//! - diff `table,resolver,hash,kind,a,b`: one record per key differing between the
+//!   databases (`resolver` set for `Resolve`, each resolver's tables are
+//!   compared apart), `kind` is `only_a`/`only_b`/`differ`, `a`/`b` are the rows
+//!   of the key only on that side (`;` separated, value columns `/`
+//!   separated), the summary goes to stderr
+//! - config `table,id,path,items,bytes`: one record per table
+//! - inspect `path,table,id,len,keysize,valsize,valcols,unit_len,bytes,file_bytes,first_key,last_key,digest_ok`:
+//!   one record per table, `bytes` is the size the header accounts for
+//!   (the stored digest takes 16 more), `table`/`id` are empty off the
+//!   catalog layout, `digest_ok` is empty for a table without a digest
+//! - dump `row,key,vals`: one record per row, `vals` are the raw value
+//!   columns (`;` separated, uid last, a group member is `size << 32 | uid`)
+//! - override list `hash,uid,verdict,time,note`: `time` in unix seconds
+//! - group `uid,hash,collide`: one record per colliding uid
+//! - stats `kind,key,count,collided,uids`: `kind` is `total` (`key` is
+//!   `items`/`distinct_hashes`/`collisions`), `preimages` (`key` is `0`..`3+`,
+//!   `count` the hashes), `histogram` (`key` preimages per hash, `count` the
+//!   hashes), `table` (`key` the path, `count` its items, `collided` those
+//!   sharing their hash) or `top` (`key` the hash, `uids` space separated),
+//!   `json` is the report as one object
+//! - eval `source,labeled,hit,ambiguous,miss,unlabeled_found,unlabeled`
+//! - search `hits,pairs,poly,init,refin,refout,xorout,template,salt,depth,fmt`
+//!
+
+use std::io::{self, Write};
+
+use clap::ArgEnum;
+use serde::Serialize;
+use serde_derive::Serialize;
+
+use crate::{
+    catalog::{Coverage, Location},
+    data::{DBMeta2, UInt},
+    diff::DiffKind,
+    eval::SourceReport,
+    gendata::CollisionResolver,
+    inspect::TblHeader,
+    hash::{hash_bili2, HashSpec, PairCheck},
+    overrides::{Override, Verdict},
+    query::{Candidate, Explain, TblProbe},
+    rank::Scored,
+    stats::DBStats,
+    shell::{path2str, TblFamily},
+};
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ArgEnum)]
+pub enum Format {
+    Plain,
+    Csv,
+    Tsv,
+    Json,
+}
+
+/// Row of a csv/tsv output, or an object of json lines
+pub trait Record: Serialize {
+    const HEADER: &'static [&'static str];
+
+    fn fields(&self) -> Vec<String>;
+}
+
+
+#[derive(Serialize)]
+pub struct CandidateRecord {
+    pub hash: String,
+    pub uid: Option<u32>,
+    pub table: Option<TblFamily>,
+    pub resolver: Option<String>,
+    pub orig: Option<String>,
+    pub also: Option<String>,
+    pub error: Option<String>,
+    pub coverage: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ScoredRecord {
+    pub hash: String,
+    pub uid: Option<u32>,
+    pub score: Option<f64>,
+    pub table: Option<TblFamily>,
+    pub resolver: Option<String>,
+    pub orig: Option<String>,
+    pub signals: Vec<String>,
+    pub coverage: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ProbeRecord<'a> {
+    pub hash: String,
+    #[serde(flatten)]
+    pub probe: &'a TblProbe,
+}
+
+#[derive(Serialize)]
+pub struct OverrideRecord {
+    pub hash: String,
+    pub uid: u32,
+    pub verdict: Verdict,
+    pub time: u64,
+    pub note: String,
+}
+
+#[derive(Serialize)]
+pub struct HashRecord {
+    pub uid: u32,
+    pub hash: String,
+}
+
+#[derive(Serialize)]
+pub struct CheckRecord {
+    pub hash: String,
+    pub uid: u32,
+    pub computed: String,
+    pub direct: bool,
+    pub resolvers: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct LocateRecord {
+    pub uid: u32,
+    pub hash: String,
+    pub path: Option<String>,
+    pub row: Option<u64>,
+    pub run_row: Option<u64>,
+    pub run_uid: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct TblRecord {
+    pub table: TblFamily,
+    pub id: u32,
+    pub path: String,
+    pub items: u64,
+    pub bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct ExportRecord {
+    pub hash: String,
+    pub uid: u32,
+    pub table: TblFamily,
+    pub id: u32,
+    pub resolver: Option<String>,
+    pub orig: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DiffRecord {
+    pub table: TblFamily,
+    pub resolver: Option<String>,
+    pub hash: String,
+    pub kind: &'static str,
+    pub a: Vec<Vec<u64>>,
+    pub b: Vec<Vec<u64>>,
+}
+
+#[derive(Serialize)]
+pub struct DumpRecord {
+    pub row: u64,
+    pub key: String,
+    pub vals: Vec<u64>,
+}
+
+#[derive(Serialize)]
+pub struct CollideRecord {
+    pub uid: u32,
+    pub hash: String,
+    pub collide: u32,
+}
+
+#[derive(Serialize)]
+pub struct StatsRecord {
+    pub kind: &'static str,
+    pub key: String,
+    pub count: u64,
+    pub collided: Option<u64>,
+    pub uids: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SpecRecord {
+    pub hits: usize,
+    pub pairs: usize,
+    pub poly: String,
+    pub init: String,
+    pub refin: bool,
+    pub refout: bool,
+    pub xorout: String,
+    pub template: String,
+    pub salt: String,
+    pub depth: u32,
+    pub fmt: String,
+}
+
+
+fn opt_field<T: ToString>(x: &Option<T>) -> String {
+    x.as_ref().map(|x| x.to_string()).unwrap_or_default()
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+
+impl Format {
+    pub fn write_header<W: Write + ?Sized, T: Record>(
+        &self,
+        w: &mut W,
+    ) -> Result<(), io::Error> {
+        match self {
+            Format::Csv => writeln!(w, "{}", T::HEADER.join(",")),
+            Format::Tsv => writeln!(w, "{}", T::HEADER.join("\t")),
+            Format::Plain | Format::Json => Ok(()),
+        }
+    }
+
+    pub fn write_record<W: Write + ?Sized, T: Record>(
+        &self,
+        w: &mut W,
+        rec: &T,
+    ) -> Result<(), io::Error> {
+        match self {
+            Format::Plain => writeln!(w, "{}", rec.fields().join(" ")),
+            Format::Csv => {
+                let fields: Vec<String> =
+                    rec.fields().iter().map(|x| csv_escape(x)).collect();
+                writeln!(w, "{}", fields.join(","))
+            }
+            Format::Tsv => {
+                let fields: Vec<String> = rec
+                    .fields()
+                    .iter()
+                    .map(|x| x.replace(['\t', '\n'], " "))
+                    .collect();
+                writeln!(w, "{}", fields.join("\t"))
+            }
+            Format::Json => writeln!(w, "{}", serde_json::to_string(rec)?),
+        }
+    }
+
+    pub fn write_records<W: Write, T: Record>(
+        &self,
+        w: &mut W,
+        recs: &[T],
+    ) -> Result<(), io::Error> {
+        self.write_header::<W, T>(w)?;
+
+        for rec in recs.iter() {
+            self.write_record(w, rec)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whole value as one json line
+    pub fn write_json<W: Write, T: Serialize>(
+        w: &mut W,
+        value: &T,
+    ) -> Result<(), io::Error> {
+        writeln!(w, "{}", serde_json::to_string(value)?)
+    }
+
+    /// Candidates of a hash, a single line for `plain`
+    pub fn write_candidates<W: Write>(
+        &self,
+        w: &mut W,
+        hash: u32,
+        cands: &[Candidate],
+        coverage: &Coverage,
+    ) -> Result<(), io::Error> {
+        if *self == Format::Plain {
+            if cands.is_empty() {
+                return writeln!(w, "{:08x}: - ({})", hash, coverage);
+            }
+
+            let uids: Vec<String> =
+                cands.iter().map(|cand| cand.uid.to_string()).collect();
+            return writeln!(w, "{:08x}: {}", hash, uids.join(" "));
+        }
+
+        for rec in CandidateRecord::of(hash, cands, coverage) {
+            self.write_record(w, &rec)?;
+        }
+
+        Ok(())
+    }
+
+    /// Ranked candidates of a hash, a single line for `plain`
+    pub fn write_scored<W: Write>(
+        &self,
+        w: &mut W,
+        hash: u32,
+        scored: &[Scored],
+        coverage: &Coverage,
+    ) -> Result<(), io::Error> {
+        if *self == Format::Plain {
+            if scored.is_empty() {
+                return writeln!(w, "{:08x}: - ({})", hash, coverage);
+            }
+
+            let uids: Vec<String> = scored
+                .iter()
+                .map(|x| format!("{}({})", x.candidate.uid, x.score))
+                .collect();
+            return writeln!(w, "{:08x}: {}", hash, uids.join(" "));
+        }
+
+        for rec in ScoredRecord::of(hash, scored, coverage) {
+            self.write_record(w, &rec)?;
+        }
+
+        Ok(())
+    }
+
+    /// How the tables were probed for `hashes` (looked up together)
+    pub fn write_explain<W: Write + ?Sized>(
+        &self,
+        w: &mut W,
+        hashes: &[u32],
+        explain: &Explain,
+    ) -> Result<(), io::Error> {
+        let hashes: Vec<String> =
+            hashes.iter().map(|hash| format!("{:08x}", hash)).collect();
+        let hashes = hashes.join(" ");
+
+        if *self == Format::Plain {
+            writeln!(w, "explain {}:", hashes)?;
+            return write!(w, "{}", explain);
+        }
+
+        for probe in explain.probes.iter() {
+            let rec = ProbeRecord {
+                hash: hashes.clone(),
+                probe,
+            };
+            self.write_record(w, &rec)?;
+        }
+
+        Ok(())
+    }
+
+    /// Input that is not a hash, still written so the output stays aligned
+    pub fn write_error<W: Write>(
+        &self,
+        w: &mut W,
+        input: &str,
+        error: &str,
+    ) -> Result<(), io::Error> {
+        if *self == Format::Plain {
+            return writeln!(w, "{}: {}", input, error);
+        }
+
+        let rec = CandidateRecord {
+            hash: input.to_string(),
+            uid: None,
+            table: None,
+            resolver: None,
+            orig: None,
+            also: None,
+            error: Some(error.to_string()),
+            coverage: None,
+        };
+
+        self.write_record(w, &rec)
+    }
+}
+
+
+impl CandidateRecord {
+    pub fn of(hash: u32, cands: &[Candidate], coverage: &Coverage) -> Vec<Self> {
+        if cands.is_empty() {
+            return vec![Self {
+                hash: format!("{:08x}", hash),
+                uid: None,
+                table: None,
+                resolver: None,
+                orig: None,
+                also: None,
+                error: None,
+                coverage: Some(coverage.covered_str()),
+            }];
+        }
+
+        cands
+            .iter()
+            .map(|cand| Self {
+                hash: format!("{:08x}", cand.hash),
+                uid: Some(cand.uid),
+                table: Some(cand.table),
+                resolver: cand.resolver.clone(),
+                orig: cand.orig.map(|orig| format!("{:08x}", orig)),
+                also: (!cand.also.is_empty()).then(|| {
+                    let also: Vec<String> = cand.also.iter().map(|x| x.to_string()).collect();
+                    also.join(";")
+                }),
+                error: None,
+                coverage: None,
+            })
+            .collect()
+    }
+}
+
+impl Record for CandidateRecord {
+    const HEADER: &'static [&'static str] =
+        &["hash", "uid", "table", "resolver", "orig", "also", "error", "coverage"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.hash.clone(),
+            opt_field(&self.uid),
+            self.table.map(|x| format!("{:?}", x)).unwrap_or_default(),
+            opt_field(&self.resolver),
+            opt_field(&self.orig),
+            opt_field(&self.also),
+            opt_field(&self.error),
+            opt_field(&self.coverage),
+        ]
+    }
+}
+
+
+impl ScoredRecord {
+    pub fn of(hash: u32, scored: &[Scored], coverage: &Coverage) -> Vec<Self> {
+        if scored.is_empty() {
+            return vec![Self {
+                hash: format!("{:08x}", hash),
+                uid: None,
+                score: None,
+                table: None,
+                resolver: None,
+                orig: None,
+                signals: vec![],
+                coverage: Some(coverage.covered_str()),
+            }];
+        }
+
+        scored
+            .iter()
+            .map(|x| Self {
+                hash: format!("{:08x}", x.candidate.hash),
+                uid: Some(x.candidate.uid),
+                score: Some(x.score),
+                table: Some(x.candidate.table),
+                resolver: x.candidate.resolver.clone(),
+                orig: x.candidate.orig.map(|orig| format!("{:08x}", orig)),
+                signals: x.signals.clone(),
+                coverage: None,
+            })
+            .collect()
+    }
+}
+
+impl Record for ScoredRecord {
+    const HEADER: &'static [&'static str] =
+        &["hash", "uid", "score", "table", "resolver", "orig", "signals", "coverage"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.hash.clone(),
+            opt_field(&self.uid),
+            opt_field(&self.score),
+            self.table.map(|x| format!("{:?}", x)).unwrap_or_default(),
+            opt_field(&self.resolver),
+            opt_field(&self.orig),
+            self.signals.join(";"),
+            opt_field(&self.coverage),
+        ]
+    }
+}
+
+
+impl Record for ProbeRecord<'_> {
+    const HEADER: &'static [&'static str] = &[
+        "hash", "table", "id", "resolver", "path", "quick_fail", "seeks",
+        "bytes", "io_bytes", "micros", "rows",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.hash.clone(),
+            format!("{:?}", self.probe.table),
+            self.probe.id.to_string(),
+            opt_field(&self.probe.resolver),
+            self.probe.path.clone(),
+            self.probe.quick_fail.to_string(),
+            self.probe.seeks.to_string(),
+            self.probe.bytes.to_string(),
+            self.probe.io_bytes.to_string(),
+            self.probe.micros.to_string(),
+            self.probe.rows.to_string(),
+        ]
+    }
+}
+
+
+impl Record for HashRecord {
+    const HEADER: &'static [&'static str] = &["uid", "hash"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![self.uid.to_string(), self.hash.clone()]
+    }
+}
+
+
+impl CheckRecord {
+    pub fn of(check: &PairCheck) -> Self {
+        Self {
+            hash: format!("{:08x}", check.hash),
+            uid: check.uid,
+            computed: format!("{:08x}", check.computed),
+            direct: check.direct,
+            resolvers: check.resolvers.clone(),
+        }
+    }
+}
+
+impl Record for CheckRecord {
+    const HEADER: &'static [&'static str] =
+        &["hash", "uid", "computed", "direct", "resolvers"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.hash.clone(),
+            self.uid.to_string(),
+            self.computed.clone(),
+            self.direct.to_string(),
+            self.resolvers.join(";"),
+        ]
+    }
+}
+
+
+impl LocateRecord {
+    pub fn of(uid: u32, loc: &Option<Location>) -> Vec<Self> {
+        let loc = match loc {
+            Some(loc) => loc,
+            None => {
+                return vec![Self {
+                    uid,
+                    hash: format!("{:08x}", hash_bili2(uid)),
+                    path: None,
+                    row: None,
+                    run_row: None,
+                    run_uid: None,
+                }]
+            }
+        };
+
+        let rec = |run: Option<&(u64, u32)>| Self {
+            uid,
+            hash: format!("{:08x}", loc.hash),
+            path: Some(loc.path.clone()),
+            row: loc.row,
+            run_row: run.map(|(row, _)| *row),
+            run_uid: run.map(|(_, uid)| *uid),
+        };
+
+        if loc.run.is_empty() {
+            return vec![rec(None)];
+        }
+
+        loc.run.iter().map(|run| rec(Some(run))).collect()
+    }
+}
+
+impl Record for LocateRecord {
+    const HEADER: &'static [&'static str] =
+        &["uid", "hash", "path", "row", "run_row", "run_uid"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.uid.to_string(),
+            self.hash.clone(),
+            opt_field(&self.path),
+            opt_field(&self.row),
+            opt_field(&self.run_row),
+            opt_field(&self.run_uid),
+        ]
+    }
+}
+
+
+impl OverrideRecord {
+    pub fn of(ov: &Override) -> Self {
+        Self {
+            hash: format!("{:08x}", ov.hash),
+            uid: ov.uid,
+            verdict: ov.verdict,
+            time: ov.time,
+            note: ov.note.clone(),
+        }
+    }
+}
+
+impl Record for OverrideRecord {
+    const HEADER: &'static [&'static str] =
+        &["hash", "uid", "verdict", "time", "note"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.hash.clone(),
+            self.uid.to_string(),
+            format!("{:?}", self.verdict),
+            self.time.to_string(),
+            self.note.clone(),
+        ]
+    }
+}
+
+
+impl TblRecord {
+    pub(crate) fn of(dbmeta: &DBMeta2) -> Vec<Self> {
+        dbmeta
+            .0
+            .iter()
+            .map(|(ty, meta)| Self {
+                table: ty.family(),
+                id: ty.id(),
+                path: path2str(&ty.pathbuf()),
+                items: meta.len,
+                bytes: meta.tbl_bytes(),
+            })
+            .collect()
+    }
+}
+
+impl Record for TblRecord {
+    const HEADER: &'static [&'static str] =
+        &["table", "id", "path", "items", "bytes"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            format!("{:?}", self.table),
+            self.id.to_string(),
+            self.path.clone(),
+            self.items.to_string(),
+            self.bytes.to_string(),
+        ]
+    }
+}
+
+
+impl ExportRecord {
+    pub fn of(cand: &Candidate, id: u32) -> Self {
+        Self {
+            hash: format!("{:08x}", cand.hash),
+            uid: cand.uid,
+            table: cand.table,
+            id,
+            resolver: cand.resolver.clone(),
+            orig: cand.orig.map(|x| format!("{:08x}", x)),
+        }
+    }
+}
+
+impl Record for ExportRecord {
+    const HEADER: &'static [&'static str] =
+        &["hash", "uid", "table", "id", "resolver", "orig"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.hash.clone(),
+            self.uid.to_string(),
+            format!("{:?}", self.table),
+            self.id.to_string(),
+            opt_field(&self.resolver),
+            opt_field(&self.orig),
+        ]
+    }
+}
+
+
+impl DiffRecord {
+    pub fn of(
+        (table, resolver): (TblFamily, Option<CollisionResolver>),
+        key: UInt,
+        kind: DiffKind,
+        a: &[Vec<UInt>],
+        b: &[Vec<UInt>],
+    ) -> Self {
+        let rows = |rows: &[Vec<UInt>]| -> Vec<Vec<u64>> {
+            rows.iter()
+                .map(|vals| vals.iter().map(|x| x.widen()).collect())
+                .collect()
+        };
+
+        Self {
+            table,
+            resolver: resolver.map(|x| x.to_string()),
+            hash: format!("{:08x}", key.widen()),
+            kind: match kind {
+                DiffKind::OnlyA => "only_a",
+                DiffKind::OnlyB => "only_b",
+                DiffKind::Differ => "differ",
+            },
+            a: rows(a),
+            b: rows(b),
+        }
+    }
+
+    /// `Normal 1388875d: -86825 +7`, `Resolve:Rehash 0d9e2cc6: ...`
+    pub fn plain(&self) -> String {
+        let mut res = match &self.resolver {
+            Some(resolver) => format!("{:?}:{} {}:", self.table, resolver, self.hash),
+            None => format!("{:?} {}:", self.table, self.hash),
+        };
+
+        for (sign, rows) in [('-', &self.a), ('+', &self.b)] {
+            for row in rows.iter() {
+                res.push_str(&format!(" {}{}", sign, row_str(row)));
+            }
+        }
+
+        res
+    }
+}
+
+fn row_str(row: &[u64]) -> String {
+    let cols: Vec<String> = row.iter().map(|x| x.to_string()).collect();
+
+    cols.join("/")
+}
+
+fn rows_str(rows: &[Vec<u64>]) -> String {
+    let rows: Vec<String> = rows.iter().map(|row| row_str(row)).collect();
+
+    rows.join(";")
+}
+
+impl Record for DiffRecord {
+    const HEADER: &'static [&'static str] = &["table", "resolver", "hash", "kind", "a", "b"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            format!("{:?}", self.table),
+            opt_field(&self.resolver),
+            self.hash.clone(),
+            self.kind.to_string(),
+            rows_str(&self.a),
+            rows_str(&self.b),
+        ]
+    }
+}
+
+
+impl Record for TblHeader {
+    const HEADER: &'static [&'static str] = &[
+        "path", "table", "id", "len", "keysize", "valsize", "valcols",
+        "unit_len", "bytes", "file_bytes", "first_key", "last_key", "digest_ok",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.path.clone(),
+            self.table.map(|x| format!("{:?}", x)).unwrap_or_default(),
+            opt_field(&self.id),
+            self.len.to_string(),
+            self.keysize.to_string(),
+            self.valsize.to_string(),
+            self.valcols.to_string(),
+            self.unit_len.to_string(),
+            self.bytes.to_string(),
+            self.file_bytes.to_string(),
+            opt_field(&self.first_key),
+            opt_field(&self.last_key),
+            opt_field(&self.digest_ok),
+        ]
+    }
+}
+
+impl Record for DumpRecord {
+    const HEADER: &'static [&'static str] = &["row", "key", "vals"];
+
+    fn fields(&self) -> Vec<String> {
+        let vals: Vec<String> = self.vals.iter().map(|x| x.to_string()).collect();
+
+        vec![self.row.to_string(), self.key.clone(), vals.join(";")]
+    }
+}
+
+
+impl Record for CollideRecord {
+    const HEADER: &'static [&'static str] = &["uid", "hash", "collide"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.uid.to_string(),
+            self.hash.clone(),
+            self.collide.to_string(),
+        ]
+    }
+}
+
+
+impl StatsRecord {
+    fn new(kind: &'static str, key: String, count: u64) -> Self {
+        Self {
+            kind,
+            key,
+            count,
+            collided: None,
+            uids: None,
+        }
+    }
+
+    /// Every part of the report, in the order of the plain one
+    pub fn of(stats: &DBStats) -> Vec<Self> {
+        let mut res = vec![
+            Self::new("total", "items".to_string(), stats.total_items),
+            Self::new("total", "distinct_hashes".to_string(), stats.distinct_hashes),
+            Self::new("total", "collisions".to_string(), stats.collisions),
+        ];
+
+        for (i, cnt) in stats.preimages.iter().enumerate() {
+            let key = if i == 3 { "3+".to_string() } else { i.to_string() };
+            res.push(Self::new("preimages", key, *cnt));
+        }
+        for (n, cnt) in stats.histogram.iter() {
+            res.push(Self::new("histogram", n.to_string(), *cnt));
+        }
+        for tbl in stats.tables.iter() {
+            res.push(Self {
+                collided: Some(tbl.collided),
+                ..Self::new("table", tbl.path.clone(), tbl.items)
+            });
+        }
+        for hash in stats.top.iter() {
+            let uids: Vec<String> = hash.uids.iter().map(|uid| uid.to_string()).collect();
+
+            res.push(Self {
+                uids: Some(uids.join(" ")),
+                ..Self::new("top", format!("{:08x}", hash.hash), hash.uids.len() as u64)
+            });
+        }
+
+        res
+    }
+}
+
+impl Record for StatsRecord {
+    const HEADER: &'static [&'static str] = &["kind", "key", "count", "collided", "uids"];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.kind.to_string(),
+            self.key.clone(),
+            self.count.to_string(),
+            opt_field(&self.collided),
+            opt_field(&self.uids),
+        ]
+    }
+}
+
+
+impl Record for SourceReport {
+    const HEADER: &'static [&'static str] = &[
+        "source",
+        "labeled",
+        "hit",
+        "ambiguous",
+        "miss",
+        "unlabeled_found",
+        "unlabeled",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.name.clone(),
+            self.labeled().to_string(),
+            self.hit.to_string(),
+            self.ambiguous.to_string(),
+            self.miss.to_string(),
+            self.unlabeled_found.to_string(),
+            self.unlabeled.to_string(),
+        ]
+    }
+}
+
+
+impl SpecRecord {
+    pub fn of(hits: usize, pairs: usize, spec: &HashSpec) -> Self {
+        Self {
+            hits,
+            pairs,
+            poly: format!("{:08x}", spec.params.poly),
+            init: format!("{:08x}", spec.params.init),
+            refin: spec.params.refin,
+            refout: spec.params.refout,
+            xorout: format!("{:08x}", spec.params.xorout),
+            template: spec.template.clone(),
+            salt: spec.salt.clone(),
+            depth: spec.depth,
+            fmt: spec.fmt.to_string(),
+        }
+    }
+}
+
+impl Record for SpecRecord {
+    const HEADER: &'static [&'static str] = &[
+        "hits", "pairs", "poly", "init", "refin", "refout", "xorout",
+        "template", "salt", "depth", "fmt",
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.hits.to_string(),
+            self.pairs.to_string(),
+            self.poly.clone(),
+            self.init.clone(),
+            self.refin.to_string(),
+            self.refout.to_string(),
+            self.xorout.clone(),
+            self.template.clone(),
+            self.salt.clone(),
+            self.depth.to_string(),
+            self.fmt.clone(),
+        ]
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::Format;
+    use crate::{
+        catalog::Coverage,
+        query::{Candidate, Provenance},
+        shell::TblFamily,
+    };
+
+    #[test]
+    fn test_write_candidates() {
+        let cands = vec![
+            Candidate {
+                hash: 0x55f5defa,
+                uid: 6487381,
+                table: TblFamily::Normal,
+                resolver: None,
+                orig: None,
+                also: vec![Provenance {
+                    table: TblFamily::Resolve,
+                    resolver: Some("Rehash".to_string()),
+                    orig: Some(0x0d9e2cc6),
+                }],
+            },
+            Candidate {
+                hash: 0x55f5defa,
+                uid: 1,
+                table: TblFamily::Resolve,
+                resolver: Some("Rehash".to_string()),
+                orig: Some(0x0d9e2cc6),
+                also: vec![],
+            },
+        ];
+
+        let coverage = Coverage {
+            covered: vec![(1, 102400000)],
+            gaps: vec![(102400001, u32::MAX)],
+        };
+
+        let mut buf = vec![];
+        Format::Plain.write_candidates(&mut buf, 0xd9e2cc6, &[], &coverage).unwrap();
+        Format::Plain.write_candidates(&mut buf, 0x55f5defa, &cands, &coverage).unwrap();
+        Format::Csv.write_candidates(&mut buf, 0x55f5defa, &cands, &coverage).unwrap();
+        Format::Tsv.write_candidates(&mut buf, 0xd9e2cc6, &[], &coverage).unwrap();
+        Format::Tsv.write_scored(&mut buf, 0xd9e2cc6, &[], &coverage).unwrap();
+        Format::Json.write_candidates(&mut buf, 0x55f5defa, &cands[..1], &coverage).unwrap();
+        Format::Json.write_error(&mut buf, "xyz", "invalid hash").unwrap();
+
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "0d9e2cc6: - (no preimage in uids 1..=102_400_000; ranges above not generated)\n\
+             55f5defa: 6487381 1\n\
+             55f5defa,6487381,Normal,,,Resolve:Rehash:0d9e2cc6,,\n\
+             55f5defa,1,Resolve,Rehash,0d9e2cc6,,,\n\
+             0d9e2cc6\t\t\t\t\t\t\t1..=102400000\n\
+             0d9e2cc6\t\t\t\t\t\t\t1..=102400000\n\
+             {\"hash\":\"55f5defa\",\"uid\":6487381,\"table\":\"Normal\",\"resolver\":null,\"orig\":null,\"also\":\"Resolve:Rehash:0d9e2cc6\",\"error\":null,\"coverage\":null}\n\
+             {\"hash\":\"xyz\",\"uid\":null,\"table\":null,\"resolver\":null,\"orig\":null,\"also\":null,\"error\":\"invalid hash\",\"coverage\":null}\n"
+        );
+    }
+}
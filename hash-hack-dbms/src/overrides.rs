@@ -0,0 +1,311 @@
+//! Curated Overrides
+//!
+//! Hash -> uid pairs confirmed or rejected by hand, kept in
+//! `data_override/overrides.tsv` (`hash uid verdict time note`, tab
+//! separated, `time` in unix seconds) and merged into query results:
+//! a confirmed uid comes first as an `Override` candidate when the
+//! `override` family is asked for, a rejected one is always dropped.
+//!
+
+use std::{
+    fs::{create_dir_all, rename, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::ArgEnum;
+use serde_derive::Serialize;
+
+use crate::{
+    eval::Truth,
+    gendata::sync_parent,
+    hash::parse_hash,
+    query::Candidate,
+    shell::{tbl_override_dir, tbl_override_path, TblFamily, CWD},
+};
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ArgEnum)]
+pub enum Verdict {
+    Confirmed,
+    Rejected,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Override {
+    pub hash: u32,
+    pub uid: u32,
+    pub verdict: Verdict,
+    /// unix seconds
+    pub time: u64,
+    pub note: String,
+}
+
+/// One override per (hash, uid), sorted by them
+#[derive(Debug, Default)]
+pub struct Overrides(pub Vec<Override>);
+
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+
+impl Override {
+    pub fn new(hash: u32, uid: u32, verdict: Verdict, note: &str) -> Self {
+        Self {
+            hash,
+            uid,
+            verdict,
+            time: now_secs(),
+            note: note.replace(['\t', '\n', '\r'], " "),
+        }
+    }
+
+    /// The `Override` candidate of a confirmed pair
+    pub fn candidate(&self) -> Candidate {
+        Candidate {
+            hash: self.hash,
+            uid: self.uid,
+            table: TblFamily::Override,
+            resolver: None,
+            orig: None,
+            also: vec![],
+        }
+    }
+
+    fn parse_line(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(5, '\t');
+
+        Some(Self {
+            hash: parse_hash(fields.next()?)?,
+            uid: fields.next()?.parse().ok()?,
+            verdict: Verdict::from_str(fields.next()?, true).ok()?,
+            time: fields.next()?.parse().ok()?,
+            note: fields.next().unwrap_or_default().to_string(),
+        })
+    }
+}
+
+
+impl Overrides {
+    /// Empty if there is none yet
+    pub fn load() -> Result<Self, io::Error> {
+        Self::load_in(Path::new(CWD))
+    }
+
+    /// Overrides of the database under `root`
+    pub fn load_in(root: &Path) -> Result<Self, io::Error> {
+        let path = root.join(tbl_override_path());
+
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn read<R: BufRead>(reader: R) -> Result<Self, io::Error> {
+        let mut res = Self::default();
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let ov = Override::parse_line(&line).ok_or_else(|| {
+                invalid_data(format!("overrides line {}: {:?}", i + 1, line))
+            })?;
+            res.add(ov);
+        }
+
+        Ok(res)
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<(), io::Error> {
+        for ov in self.0.iter() {
+            writeln!(
+                w,
+                "{:08x}\t{}\t{}\t{}\t{}",
+                ov.hash,
+                ov.uid,
+                ov.verdict.to_possible_value().unwrap().get_name(),
+                ov.time,
+                ov.note
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Written at a `.part` path then renamed, a crash leaves the old file
+    pub fn save(&self) -> Result<(), io::Error> {
+        create_dir_all(tbl_override_dir())?;
+
+        let path = tbl_override_path();
+        let part = path.with_extension("tsv.part");
+
+        let mut w = BufWriter::new(File::create(&part)?);
+        self.write(&mut w)?;
+        w.into_inner()?.sync_all()?;
+
+        rename(&part, &path)?;
+        sync_parent(&path)
+    }
+
+    /// Replace the override of the same (hash, uid)
+    pub fn add(&mut self, ov: Override) {
+        match self.0.binary_search_by_key(&(ov.hash, ov.uid), |x| (x.hash, x.uid)) {
+            Ok(i) => self.0[i] = ov,
+            Err(i) => self.0.insert(i, ov),
+        }
+    }
+
+    /// Overrides of `hash` (only of `uid` if some), number removed
+    pub fn remove(&mut self, hash: u32, uid: Option<u32>) -> usize {
+        let len = self.0.len();
+
+        self.0
+            .retain(|x| !(x.hash == hash && uid.is_none_or(|uid| x.uid == uid)));
+
+        len - self.0.len()
+    }
+
+    pub fn of(&self, hash: u32) -> &[Override] {
+        let lo = self.0.partition_point(|x| x.hash < hash);
+        let hi = self.0.partition_point(|x| x.hash <= hash);
+
+        &self.0[lo..hi]
+    }
+
+    /// Confirm the labeled pairs of a `hashid,uid` csv (`bullet.csv`),
+    /// number added
+    pub fn import(&mut self, truths: &[Truth], note: &str) -> usize {
+        let mut cnt = 0;
+
+        for truth in truths.iter() {
+            if let Some(uid) = truth.uid {
+                self.add(Override::new(truth.hash, uid, Verdict::Confirmed, note));
+                cnt += 1;
+            }
+        }
+
+        cnt
+    }
+
+    /// Drop rejected candidates of `hash`, with `confirm` put confirmed ones
+    /// first (with the tables finding them too)
+    pub fn apply(&self, hash: u32, cands: &mut Vec<Candidate>, confirm: bool) {
+        let ovs = self.of(hash);
+
+        if ovs.is_empty() {
+            return;
+        }
+
+        let verdict = |uid| ovs.iter().find(|ov| ov.uid == uid).map(|ov| ov.verdict);
+
+        cands.retain(|cand| verdict(cand.uid) != Some(Verdict::Rejected));
+        if !confirm {
+            return;
+        }
+
+        let (overridden, rest): (Vec<Candidate>, Vec<Candidate>) = cands
+            .drain(..)
+            .partition(|cand| verdict(cand.uid).is_some());
+
+        let confirmed = ovs
+            .iter()
+            .filter(|ov| ov.verdict == Verdict::Confirmed)
+            .map(|ov| {
+                let mut cand = ov.candidate();
+                for found in overridden.iter().filter(|x| x.uid == ov.uid) {
+                    cand.merge(found.clone());
+                }
+
+                cand
+            });
+
+        cands.extend(confirmed);
+        cands.extend(rest);
+    }
+}
+
+
+/// `hhq override import` source, see `eval::load_truth`
+pub fn import_csv(path: &Path) -> Result<usize, io::Error> {
+    let truths = crate::eval::load_truth(path)?;
+    let mut ovs = Overrides::load()?;
+
+    let cnt = ovs.import(&truths, &format!("import {}", path.display()));
+    ovs.save()?;
+
+    Ok(cnt)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Override, Overrides, Verdict};
+    use crate::{query::Candidate, shell::TblFamily};
+
+    fn cand(uid: u32) -> Candidate {
+        Candidate {
+            hash: 0x1388875d,
+            uid,
+            table: TblFamily::Normal,
+            resolver: None,
+            orig: None,
+            also: vec![],
+        }
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut ovs = Overrides::default();
+        ovs.add(Override::new(0x1388875d, 86825, Verdict::Rejected, "bot"));
+        ovs.add(Override::new(0x1388875d, 7, Verdict::Confirmed, "a\tnote"));
+        ovs.add(Override::new(0x0d9e2cc6, 1, Verdict::Confirmed, ""));
+        ovs.add(Override::new(0x1388875d, 7, Verdict::Confirmed, "seen"));
+
+        let mut buf = vec![];
+        ovs.write(&mut buf).unwrap();
+        let ovs = Overrides::read(&buf[..]).unwrap();
+
+        assert_eq!(ovs.0.len(), 3);
+        assert_eq!(ovs.of(0x1388875d)[0].note, "seen");
+
+        let mut cands = vec![cand(86825), cand(14740604), cand(7)];
+        ovs.apply(0x1388875d, &mut cands, false);
+
+        let uids: Vec<u32> = cands.iter().map(|x| x.uid).collect();
+        assert_eq!(uids, vec![14740604, 7]);
+
+        let mut cands = vec![cand(86825), cand(14740604), cand(7)];
+        ovs.apply(0x1388875d, &mut cands, true);
+
+        let uids: Vec<(u32, TblFamily)> =
+            cands.iter().map(|x| (x.uid, x.table)).collect();
+        assert_eq!(
+            uids,
+            vec![(7, TblFamily::Override), (14740604, TblFamily::Normal)]
+        );
+        assert_eq!(cands[0].found_in(TblFamily::Normal), Some(cand(7).provenance()));
+
+        let mut ovs = ovs;
+        assert_eq!(ovs.remove(0x1388875d, Some(7)), 1);
+        assert_eq!(ovs.remove(0x1388875d, None), 1);
+        assert_eq!(ovs.0.len(), 1);
+        assert!(Overrides::read("1388875d\tx\tconfirmed\t0\t".as_bytes()).is_err());
+    }
+}
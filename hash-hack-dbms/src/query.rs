@@ -1,223 +1,951 @@
 use std::{
-    cmp::{min, Ordering},
-    fs::{read_dir, File},
-    io::{self, Read, Seek, SeekFrom},
+    cmp::Ordering,
+    fmt::Display,
+    fs::{metadata, read_dir, File},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
     mem::size_of,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        Mutex,
+    },
+    thread::{self, available_parallelism},
+    time::{Duration, Instant},
 };
 
 use m6coll::Entry;
 use regex::Regex;
+use serde_derive::Serialize;
 use sorted_vec::SortedVec;
 
 use crate::{
-    data::{DBMeta2, TblMeta, UInt},
-    gendata::TblReader,
+    catalog::{Catalog, Coverage, QueryPlan},
+    data::{DBMeta2, GroupMember, TblMeta, UInt},
+    gendata::{CountRead, TblReader, DIGEST_LEN},
+    hash::{hash_bili2, parse_hash, strip_hex_prefix},
+    output::{Format, TblRecord},
 };
 use crate::{gendata::CollisionResolver, shell::*};
 
 
-pub(crate) fn query_db(
-    dbmeta: &DBMeta2,
-    key: UInt,
-) -> Result<Vec<UInt>, io::Error> {
-    let mut res = vec![];
+/// A uid the hash may come from, and the table that says so
+#[derive(Clone, Debug, Serialize)]
+pub struct Candidate {
+    pub hash: u32,
+    pub uid: u32,
+    pub table: TblFamily,
+    pub resolver: Option<String>,
+    /// hash before resolving
+    pub orig: Option<u32>,
+    /// later steps of the plan finding the uid too
+    pub also: Vec<Provenance>,
+}
 
-    for (ty, meta) in dbmeta.0.iter() {
-        let mut reader = TblReader::new(*meta, *ty).into_reader();
+/// A step of the plan finding a candidate
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Provenance {
+    pub table: TblFamily,
+    pub resolver: Option<String>,
+    pub orig: Option<u32>,
+}
+
+/// Candidates of a hash, and the uid ranges they were searched in
+#[derive(Debug, Serialize)]
+pub struct Lookup {
+    pub hash: u32,
+    pub candidates: Vec<Candidate>,
+    pub coverage: Coverage,
+    /// what no candidates means, e.g. `no preimage in uids 1..=102_400_000;
+    /// ranges above not generated`
+    pub not_found: Option<String>,
+}
 
-        let unit_len = meta.keysize + meta.valsize;
-        let meta_off = size_of::<TblMeta>() as u64;
+#[derive(Debug, Serialize)]
+pub struct ResolveChain {
+    pub hash: u32,
+    pub orig: Option<u32>,
+    pub uid: u32,
+}
 
-        let mut key_cache = vec![0u8; meta.keysize as usize];
-        let mut quick_cache = vec![0u8; 10 * 2 * unit_len as usize];
+impl Candidate {
+    pub fn provenance(&self) -> Provenance {
+        Provenance {
+            table: self.table,
+            resolver: self.resolver.clone(),
+            orig: self.orig,
+        }
+    }
 
-        // quick failed
-        reader.seek(SeekFrom::End(0 - unit_len as i64))?;
-        reader.read_exact(&mut key_cache[..])?;
-        let local_key = UInt::from_slice(&key_cache[..]);
-        if local_key < key {
-            continue;
+    /// First step of `family` finding the uid
+    pub fn found_in(&self, family: TblFamily) -> Option<Provenance> {
+        if self.table == family {
+            return Some(self.provenance());
+        }
+
+        self.also.iter().find(|x| x.table == family).cloned()
+    }
+
+    /// Keep the provenances of `other`, the same uid found again
+    pub fn merge(&mut self, other: Candidate) {
+        for x in std::iter::once(other.provenance()).chain(other.also) {
+            if x != self.provenance() && !self.also.contains(&x) {
+                self.also.push(x);
+            }
+        }
+    }
+}
+
+/// `Group`, `Resolve:Rehash:0d9e2cc6`
+impl Display for Provenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.table)?;
+
+        if let Some(resolver) = self.resolver.as_ref() {
+            write!(f, ":{}", resolver)?;
+        }
+        if let Some(orig) = self.orig {
+            write!(f, ":{:08x}", orig)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<&Candidate> for ResolveChain {
+    fn from(cand: &Candidate) -> Self {
+        Self {
+            hash: cand.hash,
+            orig: cand.orig,
+            uid: cand.uid,
+        }
+    }
+}
+
+impl Display for ResolveChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.orig {
+            Some(orig) => {
+                write!(f, "{:08x} -> {:08x} -> {}", self.hash, orig, self.uid)
+            }
+            None => write!(f, "{:08x} -> ? -> {}", self.hash, self.uid),
+        }
+    }
+}
+
+
+/// What a `TblCursor` did, for explain
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ProbeStats {
+    pub(crate) quick_fail: bool,
+    pub(crate) seeks: u64,
+    /// bytes the cursor read, mostly out of the buffer
+    pub(crate) bytes: u64,
+    /// bytes read from the file under the buffer
+    pub(crate) io_bytes: u64,
+    pub(crate) rows: u64,
+}
+
+/// Bytes that reached the file under a reader
+pub(crate) trait IoBytes {
+    fn io_bytes(&self) -> u64;
+}
+
+impl<R> IoBytes for BufReader<CountRead<R>> {
+    fn io_bytes(&self) -> u64 {
+        self.get_ref().bytes
+    }
+}
+
+/// Keys in `lo..=hi` with `key & mask == value`, from a hex prefix
+/// (`c4ff7a`), a mask with `?` for unknown digits (`c4ff??c1`) or bounds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyRange {
+    pub lo: u32,
+    pub hi: u32,
+    pub mask: u32,
+    pub value: u32,
+}
+
+/// Probe of a table by a query
+#[derive(Debug, Serialize)]
+pub struct TblProbe {
+    pub table: TblFamily,
+    pub id: u32,
+    pub resolver: Option<String>,
+    pub path: String,
+    /// the key range check skipped the table
+    pub quick_fail: bool,
+    pub seeks: u64,
+    /// bytes the cursor read, mostly out of the buffer
+    pub bytes: u64,
+    /// bytes read from the file, what the disk (or page cache) served
+    pub io_bytes: u64,
+    pub micros: u64,
+    /// rows matched
+    pub rows: u64,
+}
+
+/// Tables probed by a query, in probe order
+#[derive(Debug, Default, Serialize)]
+pub struct Explain {
+    pub probes: Vec<TblProbe>,
+}
+
+
+/// Positioned reads over a table laid out as `TblMeta` + rows
+pub(crate) struct TblCursor<R> {
+    reader: R,
+    meta: TblMeta,
+    row: Vec<u8>,
+    pub(crate) stats: ProbeStats,
+}
+
+impl<R: Read + Seek> TblCursor<R> {
+    pub(crate) fn new(reader: R, meta: TblMeta) -> Self {
+        Self {
+            reader,
+            meta,
+            row: vec![0; meta.unit_len() as usize],
+            stats: ProbeStats::default(),
         }
+    }
+
+    pub(crate) fn seek_row(&mut self, pos: u64) -> Result<(), io::Error> {
+        let off = size_of::<TblMeta>() as u64 + pos * self.meta.unit_len() as u64;
+
+        self.reader.seek(SeekFrom::Start(off))?;
+        self.stats.seeks += 1;
+
+        Ok(())
+    }
+
+    pub(crate) fn key_at(&mut self, pos: u64) -> Result<UInt, io::Error> {
+        self.seek_row(pos)?;
+
+        let keysize = self.meta.keysize as usize;
+        self.reader.read_exact(&mut self.row[..keysize])?;
+        self.stats.bytes += keysize as u64;
+
+        Ok(UInt::from_slice(&self.row[..keysize]))
+    }
 
-        // SEEK PLAN
-        let mut l = 0;
-        let mut h = meta.len; // [l, h)
+    pub(crate) fn meta(&self) -> TblMeta {
+        self.meta
+    }
+
+    /// Key and value columns of the row at the reader position
+    pub(crate) fn read_row(&mut self) -> Result<(UInt, Vec<UInt>), io::Error> {
+        self.reader.read_exact(&mut self.row[..])?;
+        self.stats.bytes += self.row.len() as u64;
+
+        let key = UInt::from_slice(&self.row[..self.meta.keysize as usize]);
+
+        Ok((key, self.meta.vals_from_slice(&self.row[..])))
+    }
 
+    /// First pos in [l, h) whose key >= `key`, or h
+    pub(crate) fn lower_bound(
+        &mut self,
+        key: UInt,
+        mut l: u64,
+        mut h: u64,
+    ) -> Result<u64, io::Error> {
         while l < h {
             let pivot = (h + l) / 2;
-            reader
-                .seek(SeekFrom::Start(meta_off + pivot * unit_len as u64))?;
 
-            reader.read_exact(&mut key_cache[..])?;
-            let local_key = UInt::from_slice(&key_cache[..]);
+            match self.key_at(pivot)?.cmp(&key) {
+                Ordering::Less => l = pivot + 1,
+                _ => h = pivot,
+            }
+        }
 
-            match key.cmp(&local_key) {
-                Ordering::Less => {
-                    h = pivot;
-                }
-                Ordering::Equal => {
-                    // read +/- 10 slice
-
-                    let half =
-                        quick_cache.capacity() as u64 / unit_len as u64 / 2;
-
-                    let el = pivot - min(pivot, half);
-                    let eh = min(pivot + half, meta.len);
-                    let cache_len = (eh - el) as usize;
-
-                    reader.seek(SeekFrom::Start(
-                        meta_off + el * unit_len as u64,
-                    ))?;
-                    reader.read_exact(
-                        &mut quick_cache[..cache_len * unit_len as usize],
-                    )?;
-
-                    for i in 0..cache_len {
-                        let base = i * unit_len as usize;
-
-                        let local_key = UInt::from_slice(
-                            &quick_cache[base..base + meta.keysize as usize],
-                        );
-                        let local_val = UInt::from_slice(
-                            &quick_cache[base + meta.keysize as usize
-                                ..base + unit_len as usize],
-                        );
-
-                        if local_key == key {
-                            res.push(local_val);
-                        }
+        Ok(l)
+    }
+
+    /// `lower_bound` from `lo` to the end, probing lo + 1, 2, 4 ... first
+    pub(crate) fn gallop(
+        &mut self,
+        key: UInt,
+        lo: u64,
+    ) -> Result<u64, io::Error> {
+        let len = self.meta.len;
+
+        if lo >= len || self.key_at(lo)? >= key {
+            return Ok(lo);
+        }
+
+        let mut prev = lo;
+        let mut step = 1;
+
+        loop {
+            let probe = lo + step;
+
+            if probe >= len {
+                return self.lower_bound(key, prev + 1, len);
+            }
+            if self.key_at(probe)? >= key {
+                return self.lower_bound(key, prev + 1, probe);
+            }
+
+            prev = probe;
+            step *= 2;
+        }
+    }
+
+    /// Value columns of the run of `key` starting at `pos`, and the pos after
+    pub(crate) fn scan_eq(
+        &mut self,
+        key: UInt,
+        mut pos: u64,
+    ) -> Result<(Vec<Vec<UInt>>, u64), io::Error> {
+        let mut res = vec![];
+
+        self.seek_row(pos)?;
+
+        while pos < self.meta.len {
+            self.reader.read_exact(&mut self.row[..])?;
+            self.stats.bytes += self.row.len() as u64;
+
+            let local_key =
+                UInt::from_slice(&self.row[..self.meta.keysize as usize]);
+            if local_key != key {
+                break;
+            }
+
+            res.push(self.meta.vals_from_slice(&self.row[..]));
+            pos += 1;
+        }
+
+        Ok((res, pos))
+    }
+
+    /// Single pass over the table for `keys` (sorted, deduped),
+    /// push matched rows into `res` by `idx`
+    pub(crate) fn query_sorted(
+        &mut self,
+        keys: &[(UInt, usize)],
+        res: &mut [Vec<Vec<UInt>>],
+    ) -> Result<(), io::Error> {
+        let len = self.meta.len;
+
+        // quick failed
+        if len == 0
+            || keys.is_empty()
+            || self.key_at(len - 1)? < keys[0].0
+            || self.key_at(0)? > keys[keys.len() - 1].0
+        {
+            self.stats.quick_fail = true;
+            return Ok(());
+        }
+
+        let mut pos = 0;
+
+        for (key, idx) in keys.iter() {
+            pos = self.gallop(*key, pos)?;
+
+            if pos == len {
+                break;
+            }
+
+            let (rows, nxt_pos) = self.scan_eq(*key, pos)?;
+            self.stats.rows += rows.len() as u64;
+            res[*idx].extend(rows);
+            pos = nxt_pos;
+        }
+
+        Ok(())
+    }
+}
+
+
+impl KeyRange {
+    pub fn between(lo: u32, hi: u32) -> Self {
+        Self {
+            lo,
+            hi,
+            mask: 0,
+            value: 0,
+        }
+    }
+
+    /// Up to 8 hex digits (`0x` allowed), unknown trailing ones
+    pub fn prefix(s: &str) -> Option<Self> {
+        let s = strip_hex_prefix(s.trim());
+
+        if s.is_empty() || s.len() > 8 {
+            return None;
+        }
+
+        Self::mask(&format!("{:?<8}", s))
+    }
+
+    /// 8 hex digits or `?` (`0x` allowed)
+    pub fn mask(s: &str) -> Option<Self> {
+        let s = strip_hex_prefix(s.trim());
+
+        if s.len() != 8 {
+            return None;
+        }
+
+        let mut mask = 0;
+        let mut value = 0;
+
+        for c in s.chars() {
+            mask <<= 4;
+            value <<= 4;
+
+            if c != '?' {
+                mask |= 0xf;
+                value |= c.to_digit(16)?;
+            }
+        }
+
+        Some(Self {
+            lo: value,
+            hi: value | !mask,
+            mask,
+            value,
+        })
+    }
+
+    pub fn contains(&self, key: u32) -> bool {
+        self.lo <= key && key <= self.hi && key & self.mask == self.value
+    }
+}
+
+impl FromStr for KeyRange {
+    type Err = String;
+
+    /// `lo,hi` (hex, optionally in `[]`), a mask with `?`, or a prefix
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("{:?} is not a hash prefix, mask or lo,hi", s);
+        let trimmed = s.trim().trim_start_matches('[').trim_end_matches(']');
+
+        if let Some((lo, hi)) = trimmed.split_once(',') {
+            let lo = parse_hash(lo).ok_or_else(err)?;
+            let hi = parse_hash(hi).ok_or_else(err)?;
+
+            if lo > hi {
+                return Err(err());
+            }
+            return Ok(Self::between(lo, hi));
+        }
+
+        if trimmed.contains('?') {
+            return Self::mask(trimmed).ok_or_else(err);
+        }
+
+        Self::prefix(trimmed).ok_or_else(err)
+    }
+}
+
+impl Display for KeyRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{:08x},{:08x}]", self.lo, self.hi)?;
+
+        if self.mask != 0 {
+            write!(f, " & {:08x} == {:08x}", self.mask, self.value)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Value columns of the matched rows, for each key
+pub(crate) type KeyRows = Vec<Vec<Vec<UInt>>>;
+
+/// Workers for a query by default, tables are mostly seek bound so it is
+/// not worth more than a few
+pub fn default_threads() -> usize {
+    available_parallelism().map(|n| n.get()).unwrap_or(1).min(8)
+}
+
+/// `query_sorted` over tables `0..n` (opened by `open`) on up to `threads`
+/// workers, the rows of each table in table order whatever finishes first
+pub(crate) fn probe_tables<R, F>(
+    n: usize,
+    open: F,
+    keys: &[(UInt, usize)],
+    nkeys: usize,
+    threads: usize,
+) -> Result<Vec<(KeyRows, ProbeStats, Duration)>, io::Error>
+where
+    R: Read + Seek + IoBytes,
+    F: Fn(usize) -> Result<TblCursor<R>, io::Error> + Sync,
+{
+    let probe = |i: usize| {
+        let start = Instant::now();
+        let mut cursor = open(i)?;
+        let mut rows = vec![vec![]; nkeys];
+
+        cursor.query_sorted(keys, &mut rows)?;
+        cursor.stats.io_bytes = cursor.reader.io_bytes();
+
+        Ok::<_, io::Error>((rows, cursor.stats, start.elapsed()))
+    };
+
+    let threads = threads.clamp(1, n.max(1));
+    let mut tbl_res = Vec::with_capacity(n);
+
+    if threads == 1 {
+        for i in 0..n {
+            tbl_res.push(probe(i)?);
+        }
+    } else {
+        let next = AtomicUsize::new(0);
+        let slots = Mutex::new((0..n).map(|_| None).collect::<Vec<_>>());
+
+        thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| loop {
+                    let i = next.fetch_add(1, AtomicOrdering::Relaxed);
+                    if i >= n {
+                        break;
                     }
 
-                    break;
-                }
-                Ordering::Greater => {
-                    l = pivot + 1;
-                }
+                    let x = probe(i);
+                    slots.lock().unwrap()[i] = Some(x);
+                });
             }
+        });
+
+        for x in slots.into_inner().unwrap() {
+            tbl_res.push(x.unwrap()?);
+        }
+    }
+
+    Ok(tbl_res)
+}
+
+
+/// Rows for each key (in input order) over the tables under `root`, each
+/// walked once on up to `threads` workers, pushing a probe per table into
+/// `explain` if any
+pub(crate) fn query_db_many_rows_explain(
+    root: &Path,
+    dbmeta: &DBMeta2,
+    keys: &[UInt],
+    threads: usize,
+    explain: Option<&mut Explain>,
+) -> Result<Vec<Vec<Vec<UInt>>>, io::Error> {
+    let mut res = vec![vec![]; keys.len()];
+
+    for rows in query_tables_explain(root, &dbmeta.0, keys, threads, explain)? {
+        for (acc, rows) in res.iter_mut().zip(rows) {
+            acc.extend(rows);
         }
     }
 
     Ok(res)
 }
 
+/// Rows for each key (in input order) of each of `tbls` (in `tbls` order),
+/// the tables may be of different families and are probed by the same
+/// workers
+pub(crate) fn query_tables_explain(
+    root: &Path,
+    tbls: &[(TblTy, TblMeta)],
+    keys: &[UInt],
+    threads: usize,
+    mut explain: Option<&mut Explain>,
+) -> Result<Vec<KeyRows>, io::Error> {
+    let mut sorted: Vec<(UInt, usize)> =
+        keys.iter().cloned().zip(0..keys.len()).collect();
+    sorted.sort_unstable();
+    sorted.dedup_by_key(|(key, _)| *key);
 
-pub fn query_bili2(id: u32) -> Result<Vec<u32>, io::Error> {
-    let dbmeta = load_dbmeta();
-    let raw_res = query_db(&dbmeta, UInt::U32(id))?;
+    let tbl_res = probe_tables(
+        tbls.len(),
+        |i| {
+            let (ty, meta) = tbls[i];
+            Ok(TblCursor::new(TblReader::open(meta, &ty.path_in(root))?.into_reader(), meta))
+        },
+        &sorted,
+        keys.len(),
+        threads,
+    )?;
+
+    let mut res = Vec::with_capacity(tbls.len());
+
+    for ((ty, _), (mut rows, stats, elapsed)) in tbls.iter().zip(tbl_res) {
+        if let Some(explain) = explain.as_deref_mut() {
+            explain.probes.push(TblProbe::of(ty, stats, elapsed));
+        }
+
+        // fill duplicated inputs
+        for i in 0..keys.len() {
+            if rows[i].is_empty() {
+                if let Ok(j) = sorted.binary_search_by_key(&keys[i], |(key, _)| *key) {
+                    if sorted[j].1 != i {
+                        rows[i] = rows[sorted[j].1].clone();
+                    }
+                }
+            }
+        }
+
+        res.push(rows);
+    }
 
-    Ok(raw_res.into_iter().map(|uint| uint.into_u32()).collect())
+    Ok(res)
+}
+
+impl TblProbe {
+    pub(crate) fn of(ty: &TblTy, stats: ProbeStats, elapsed: Duration) -> Self {
+        Self {
+            table: ty.family(),
+            id: ty.id(),
+            resolver: match ty {
+                TblTy::Resolve(_, resolve) => Some(resolve.to_string()),
+                _ => None,
+            },
+            path: path2str(&ty.pathbuf()),
+            quick_fail: stats.quick_fail,
+            seeks: stats.seeks,
+            bytes: stats.bytes,
+            io_bytes: stats.io_bytes,
+            micros: elapsed.as_micros() as u64,
+            rows: stats.rows,
+        }
+    }
 }
 
-pub fn query_collision_rehash_resolve(id: u32) -> Result<Vec<u32>, io::Error> {
-    let resolve = CollisionResolver::Rehash;
+impl Explain {
+    pub fn total_micros(&self) -> u64 {
+        self.probes.iter().map(|probe| probe.micros).sum()
+    }
+}
 
-    let dbmeta = resolve.load_dbmeta();
+impl Display for Explain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for probe in self.probes.iter() {
+            if probe.quick_fail {
+                writeln!(f, "{}: quick failed", probe.path)?;
+                continue;
+            }
 
-    let raw_res = query_db(&dbmeta, UInt::U32(id))?;
+            writeln!(
+                f,
+                "{}: {} seeks, {} bytes ({} from the file), {}us, {} rows",
+                probe.path,
+                probe.seeks,
+                probe.bytes,
+                probe.io_bytes,
+                probe.micros,
+                probe.rows
+            )?;
+        }
 
-    Ok(raw_res.into_iter().map(|uint| uint.into_u32()).collect())
+        writeln!(
+            f,
+            "{} tables probed, {}us",
+            self.probes.len(),
+            self.total_micros()
+        )
+    }
 }
 
 
-pub fn load_tblmeta(id: u32) -> TblMeta {
-    let path = tbl_path(id);
+/// Uids of the hash in the normal tables
+pub fn query_bili2(id: u32) -> Result<Vec<u32>, io::Error> {
+    let plan = QueryPlan::default().restrict(&[TblFamily::Normal]);
+    let cands = lookup_candidates_many(&[id], &plan)?.pop().unwrap();
+
+    Ok(cands.into_iter().map(|cand| cand.uid).collect())
+}
+
+/// Candidates of each hash (in input order) following `plan`
+pub fn lookup_candidates_many(
+    ids: &[u32],
+    plan: &QueryPlan,
+) -> Result<Vec<Vec<Candidate>>, io::Error> {
+    Catalog::load().query(plan, ids)
+}
 
-    let mut file = File::open(path).unwrap();
-    let mut buf = [0; size_of::<TblMeta>()];
+/// Candidates of the hash following `plan`, saying what was searched if none
+pub fn lookup_bili2(id: u32, plan: &QueryPlan) -> Result<Lookup, io::Error> {
+    let catalog = Catalog::load();
+    let candidates = catalog.query(plan, &[id])?.pop().unwrap();
+    let coverage = catalog.coverage();
 
-    file.read_exact(&mut buf).unwrap();
+    Ok(Lookup {
+        hash: id,
+        not_found: candidates.is_empty().then(|| coverage.to_string()),
+        candidates,
+        coverage,
+    })
+}
 
-    unsafe { TblMeta::from_raw(buf.as_mut_ptr()) }
+/// `lookup_candidates_many`, and how each table was probed
+pub fn explain_candidates_many(
+    ids: &[u32],
+    plan: &QueryPlan,
+) -> Result<(Vec<Vec<Candidate>>, Explain), io::Error> {
+    Catalog::load().query_explain(plan, ids)
 }
 
-pub(crate) fn load_dbmeta() -> DBMeta2 {
-    let paths = read_dir("./").unwrap();
-    let datareg = Regex::new("data([0-9]+)").unwrap();
-    let mut coll = SortedVec::new();
+/// Answer a hash per input line following `plan`, flushing each line so it
+/// works in long-running pipes, with `explain` the probes of each line go to it.
+/// Every line gets an answer, a blank one an error record, so the output
+/// lines up with the input.
+pub fn query_stream_bili2<R: BufRead, W: Write>(
+    catalog: &Catalog,
+    reader: R,
+    writer: &mut W,
+    fmt: Format,
+    plan: &QueryPlan,
+    mut explain: Option<&mut dyn Write>,
+) -> Result<(), io::Error> {
+    let coverage = catalog.coverage();
 
-    for path in paths {
-        let dir_entry = path.unwrap();
-        let name = path2str(&dir_entry.path());
+    for line in reader.lines() {
+        let line = line?;
+        let input = line.trim();
 
-        if let Some(cap) = datareg.captures(name.as_str()) {
-            let id =
-                u32::from_str_radix(cap.get(1).unwrap().as_str(), 10).unwrap();
-            let meta = load_tblmeta(id);
-            coll.insert(Entry(id, meta));
+        if input.is_empty() {
+            fmt.write_error(writer, input, "empty line")?;
+        } else if let Some(id) = parse_hash(input) {
+            let (cands, probes) = catalog.query_explain(plan, &[id])?;
+
+            fmt.write_candidates(writer, id, &cands[0], &coverage)?;
+
+            if let Some(w) = explain.as_mut() {
+                fmt.write_explain(w, &[id], &probes)?;
+                w.flush()?;
+            }
+        } else {
+            fmt.write_error(writer, input, "invalid hash")?;
         }
+
+        writer.flush()?;
     }
 
-    let vec = coll
+    Ok(())
+}
+
+/// Every uid of the collision group of the hash, empty if no collision
+pub fn query_collision_group(
+    catalog: &Catalog,
+    id: u32,
+) -> Result<Vec<GroupMember>, io::Error> {
+    let rows = query_db_many_rows_explain(
+        &catalog.root,
+        &catalog.group,
+        &[UInt::U32(id)],
+        1,
+        None,
+    )?;
+
+    Ok(rows[0]
         .iter()
-        .map(|Entry(id, meta)| (TblTy::Normal(*id), *meta))
-        .collect();
+        .map(|vals| GroupMember::from_uint(*vals.last().unwrap()))
+        .collect())
+}
 
-    DBMeta2(vec)
+/// Uids sharing the midHash of `uid`
+pub fn query_colliding_uids(catalog: &Catalog, uid: u32) -> Result<Vec<u32>, io::Error> {
+    let group = query_collision_group(catalog, hash_bili2(uid))?;
+
+    Ok(group
+        .into_iter()
+        .map(|member| member.uid)
+        .filter(|x| *x != uid)
+        .collect())
 }
 
-pub(crate) fn load_dup_dbmeta() -> DBMeta2 {
-    let paths = read_dir(tbl_dup_dir()).unwrap();
-    let datareg = Regex::new("([0-9]+)").unwrap();
+
+pub fn load_tblmeta(id: u32) -> TblMeta {
+    TblTy::Normal(id).load_meta()
+}
+
+impl TblTy {
+    pub(crate) fn load_meta(&self) -> TblMeta {
+        self.load_meta_in(Path::new(CWD)).unwrap()
+    }
+
+    pub(crate) fn load_meta_in(&self, root: &Path) -> Result<TblMeta, io::Error> {
+        let mut file = File::open(self.path_in(root))?;
+        let mut buf = [0; size_of::<TblMeta>()];
+
+        file.read_exact(&mut buf)?;
+
+        Ok(unsafe { TblMeta::from_raw(buf.as_mut_ptr()) })
+    }
+}
+
+/// Scan `root/dir` for tables whose name matches `pat`, a missing dir is an
+/// empty family
+fn load_family_dbmeta<F>(root: &Path, dir: &Path, pat: &str, ty_of: F) -> DBMeta2
+where
+    F: Fn(u32) -> TblTy,
+{
     let mut coll = SortedVec::new();
 
+    let paths = if let Ok(paths) = read_dir(root.join(dir)) {
+        paths
+    } else {
+        return DBMeta2(vec![]);
+    };
+    let datareg = Regex::new(pat).unwrap();
+
     for path in paths {
         let dir_entry = path.unwrap();
-        let name = path2str(&dir_entry.path());
+        let name = dir_entry.file_name().to_string_lossy().to_string();
 
         if let Some(cap) = datareg.captures(name.as_str()) {
-            let id =
-                u32::from_str_radix(cap.get(1).unwrap().as_str(), 10).unwrap();
-            let meta = load_tblmeta(id);
+            let id = match cap.get(1).unwrap().as_str().parse::<u32>() {
+                Ok(id) => id,
+                Err(err) => {
+                    let path = root.join(dir).join(&name);
+                    eprintln!("skip {}: table id {}", path2str(&path), err);
+                    continue;
+                }
+            };
+
+            let path = ty_of(id).path_in(root);
+
+            // a bundle dir still being generated has no table yet
+            if !path.exists() {
+                continue;
+            }
+
+            let meta = match ty_of(id).load_meta_in(root) {
+                Ok(meta) => meta,
+                Err(err) => {
+                    eprintln!("skip {}: {}", path2str(&path), err);
+                    continue;
+                }
+            };
+
+            // written in place by an older version and cut short, or a
+            // corrupt header
+            let bytes = metadata(&path).map(|x| x.len()).unwrap_or_default();
+            let tbl_bytes = match meta.checked_tbl_bytes() {
+                Some(tbl_bytes) => tbl_bytes,
+                None => {
+                    eprintln!(
+                        "skip {}: header claims {} rows, overflows",
+                        path2str(&path),
+                        meta.len
+                    );
+                    continue;
+                }
+            };
+            if bytes != tbl_bytes && bytes != tbl_bytes + DIGEST_LEN {
+                eprintln!(
+                    "skip {}: header claims {} bytes, file has {}",
+                    path2str(&path),
+                    tbl_bytes,
+                    bytes
+                );
+                continue;
+            }
+
             coll.insert(Entry(id, meta));
         }
     }
 
     let vec = coll
         .iter()
-        .map(|Entry(id, meta)| (TblTy::Dup(*id), *meta))
+        .map(|Entry(id, meta)| (ty_of(*id), *meta))
         .collect();
 
     DBMeta2(vec)
 }
 
+/// Tables of `family` under `root` (`CWD` for the working dir), overrides
+/// are not tables
+pub(crate) fn load_family_in(root: &Path, family: TblFamily) -> DBMeta2 {
+    match family {
+        TblFamily::Normal => {
+            load_family_dbmeta(root, Path::new("./"), "^data([0-9]+)$", TblTy::Normal)
+        }
+        TblFamily::Dup => load_family_dbmeta(
+            root,
+            &tbl_dup_dir(),
+            "^db_dup_([0-9]+).bin$",
+            TblTy::Dup,
+        ),
+        TblFamily::Group => load_family_dbmeta(
+            root,
+            &tbl_group_dir(),
+            "^db_group_([0-9]+).bin$",
+            TblTy::Group,
+        ),
+        TblFamily::Import => load_family_dbmeta(
+            root,
+            &tbl_import_dir(),
+            "^db_import_([0-9]+).bin$",
+            TblTy::Import,
+        ),
+        TblFamily::Resolve => DBMeta2(
+            CollisionResolver::ALL
+                .iter()
+                .flat_map(|resolve| resolve.load_dbmeta_in(root).0)
+                .collect(),
+        ),
+        TblFamily::Override => DBMeta2(vec![]),
+    }
+}
+
+pub(crate) fn load_dbmeta() -> DBMeta2 {
+    load_family_in(Path::new(CWD), TblFamily::Normal)
+}
+
+pub(crate) fn load_dup_dbmeta() -> DBMeta2 {
+    load_family_in(Path::new(CWD), TblFamily::Dup)
+}
+
+pub(crate) fn load_group_dbmeta() -> DBMeta2 {
+    load_family_in(Path::new(CWD), TblFamily::Group)
+}
+
+pub(crate) fn load_import_dbmeta() -> DBMeta2 {
+    load_family_in(Path::new(CWD), TblFamily::Import)
+}
+
 impl CollisionResolver {
     pub(crate) fn load_dbmeta(&self) -> DBMeta2 {
-        match self {
-            CollisionResolver::Rehash => {
-                let paths = read_dir("data_cr_rehash").unwrap();
-                let datareg = Regex::new("([0-9]+)").unwrap();
-                let mut coll = SortedVec::new();
-
-                for path in paths {
-                    let dir_entry = path.unwrap();
-                    let name = path2str(&dir_entry.path());
-
-                    if let Some(cap) = datareg.captures(name.as_str()) {
-                        let id = u32::from_str_radix(
-                            cap.get(1).unwrap().as_str(),
-                            10,
-                        )
-                        .unwrap();
-                        let meta = load_tblmeta(id);
-                        coll.insert(Entry(id, meta));
-                    }
-                }
+        self.load_dbmeta_in(Path::new(CWD))
+    }
 
-                let vec = coll
-                    .iter()
-                    .map(|Entry(id, meta)| {
-                        (TblTy::Resolve(*id, CollisionResolver::Rehash), *meta)
-                    })
-                    .collect();
+    pub(crate) fn load_dbmeta_in(&self, root: &Path) -> DBMeta2 {
+        let resolve = *self;
 
-                DBMeta2(vec)
-            }
+        match self {
+            CollisionResolver::Rehash => load_family_dbmeta(
+                root,
+                Path::new("data_cr_rehash"),
+                "^db_cr_rehash_([0-9]+).bin$",
+                move |id| TblTy::Resolve(id, resolve),
+            ),
         }
     }
 }
 
 
 
+/// Every table family
+pub(crate) fn load_all_dbmeta() -> DBMeta2 {
+    let mut vec = load_dbmeta().0;
+
+    vec.extend(load_dup_dbmeta().0);
+    vec.extend(load_group_dbmeta().0);
+    vec.extend(load_import_dbmeta().0);
+
+    for resolve in CollisionResolver::ALL {
+        vec.extend(resolve.load_dbmeta().0);
+    }
+
+    DBMeta2(vec)
+}
+
+/// `config` in a machine readable format
+pub fn write_dbmeta<W: Write>(w: &mut W, fmt: Format) -> Result<(), io::Error> {
+    fmt.write_records(w, &TblRecord::of(&load_all_dbmeta()))
+}
+
 pub fn print_dbmeta() {
     let dbmeta = load_dbmeta();
     println!("{}", dbmeta);
@@ -228,4 +956,211 @@ pub fn print_dbmeta() {
         println!("Dup DB:");
         println!("{}", db_dup_meta)
     }
+
+    let db_group_meta = load_group_dbmeta();
+
+    if !db_group_meta.0.is_empty() {
+        println!("Group DB:");
+        println!("{}", db_group_meta)
+    }
+
+    let db_import_meta = load_import_dbmeta();
+
+    if !db_import_meta.0.is_empty() {
+        println!("Import DB:");
+        println!("{}", db_import_meta)
+    }
+
+    for resolve in CollisionResolver::ALL {
+        let db_resolve_meta = resolve.load_dbmeta();
+
+        if !db_resolve_meta.0.is_empty() {
+            println!("Resolve({}) DB:", resolve);
+            println!("{}", db_resolve_meta)
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use m6coll::ToLeBytes;
+
+    use super::{load_family_in, probe_tables, KeyRange, TblCursor};
+    use crate::{
+        data::{TblMeta, UInt},
+        gendata::CountRead,
+        hash::hash_bili2,
+        shell::{TblFamily, TblTy},
+    };
+
+    fn tbl_meta(len: usize) -> TblMeta {
+        TblMeta {
+            len: len as u64,
+            keysize: 4,
+            valsize: 4,
+            valcols: 0,
+        }
+    }
+
+    fn tbl_bytes(rows: &[(u32, u32)]) -> Vec<u8> {
+        let mut raw = tbl_meta(rows.len()).to_le_bytes().to_vec();
+
+        for (k, v) in rows.iter() {
+            raw.extend_from_slice(&k.to_le_bytes());
+            raw.extend_from_slice(&v.to_le_bytes());
+        }
+
+        raw
+    }
+
+    fn mem_tbl(rows: &[(u32, u32)]) -> TblCursor<BufReader<CountRead<Cursor<Vec<u8>>>>> {
+        let reader = BufReader::new(CountRead::new(Cursor::new(tbl_bytes(rows))));
+
+        TblCursor::new(reader, tbl_meta(rows.len()))
+    }
+
+    /// Rows of uid range `i`, sorted by hash
+    fn range_rows(i: u32, n: u32) -> Vec<(u32, u32)> {
+        let mut rows: Vec<(u32, u32)> =
+            (i * n + 1..=(i + 1) * n).map(|uid| (hash_bili2(uid), uid)).collect();
+        rows.sort_unstable();
+        rows
+    }
+
+    fn sorted_keys(keys: &[u32]) -> Vec<(UInt, usize)> {
+        let mut sorted: Vec<(UInt, usize)> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (UInt::U32(*k), i))
+            .collect();
+        sorted.sort_unstable();
+        sorted
+    }
+
+    #[test]
+    fn test_query_sorted() {
+        // a long run (> the old +/- 10 window) and sparse keys
+        let mut rows: Vec<(u32, u32)> = (0..100).map(|i| (i * 2, i)).collect();
+        rows.extend((0..30).map(|i| (500, 1000 + i)));
+        rows.push((900, 7));
+
+        let mut cursor = mem_tbl(&rows);
+        let keys: Vec<(UInt, usize)> = [1, 4, 198, 500, 900, 901]
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (UInt::U32(*k), i))
+            .collect();
+        let mut res = vec![vec![]; keys.len()];
+
+        cursor.query_sorted(&keys, &mut res).unwrap();
+
+        let uids: Vec<Vec<u32>> = res
+            .into_iter()
+            .map(|rows| rows.into_iter().map(|mut vals| vals.pop().unwrap().into_u32()).collect())
+            .collect();
+
+        assert!(uids[0].is_empty());
+        assert_eq!(uids[1], vec![2]);
+        assert_eq!(uids[2], vec![99]);
+        assert_eq!(uids[3], (1000..1030).collect::<Vec<u32>>());
+        assert_eq!(uids[4], vec![7]);
+        assert!(uids[5].is_empty());
+        assert!(!cursor.stats.quick_fail);
+        assert_eq!(cursor.stats.rows, 33);
+        assert!(cursor.stats.seeks > 0 && cursor.stats.bytes > 0);
+
+        let mut cursor = mem_tbl(&rows);
+        cursor
+            .query_sorted(&keys[5..], &mut vec![vec![]; keys.len()])
+            .unwrap();
+        assert!(cursor.stats.quick_fail);
+        assert_eq!(cursor.stats.rows, 0);
+
+        assert_eq!(cursor.gallop(UInt::U32(500), 0).unwrap(), 100);
+        assert_eq!(cursor.gallop(UInt::U32(1000), 3).unwrap(), 131);
+    }
+
+    #[test]
+    fn test_key_range() {
+        let range: KeyRange = "c4ff7a".parse().unwrap();
+        assert_eq!((range.lo, range.hi), (0xc4ff7a00, 0xc4ff7aff));
+        assert!(range.contains(0xc4ff7ac1) && !range.contains(0xc4ff7bc1));
+
+        let range: KeyRange = "c4ff??c1".parse().unwrap();
+        assert_eq!((range.lo, range.hi), (0xc4ff00c1, 0xc4ffffc1));
+        assert!(range.contains(0xc4ff7ac1) && !range.contains(0xc4ff7ac2));
+
+        let range: KeyRange = "[10,0x2f]".parse().unwrap();
+        assert_eq!(range, KeyRange::between(0x10, 0x2f));
+        assert!(range.contains(0x2f) && !range.contains(0x30));
+
+        assert_eq!("0xc4ff7a".parse::<KeyRange>(), "c4ff7a".parse());
+        assert_eq!("0Xc4ff??c1".parse::<KeyRange>(), "c4ff??c1".parse());
+        assert!("0x".parse::<KeyRange>().is_err());
+        assert!("c4ff7ac1ff".parse::<KeyRange>().is_err());
+        assert!("c4ff??".parse::<KeyRange>().is_err());
+        assert!("[2f,10]".parse::<KeyRange>().is_err());
+        assert!("xyz".parse::<KeyRange>().is_err());
+    }
+
+    #[test]
+    fn test_probe_tables() {
+        let tables: Vec<Vec<(u32, u32)>> =
+            (0..5).map(|i| range_rows(i, 2000)).collect();
+        let mut keys: Vec<u32> = (1..=10000).step_by(37).map(hash_bili2).collect();
+        keys.push(0);
+        let sorted = sorted_keys(&keys);
+
+        let open = |i: usize| Ok(mem_tbl(&tables[i]));
+        let seq = probe_tables(tables.len(), open, &sorted, keys.len(), 1).unwrap();
+        let par = probe_tables(tables.len(), open, &sorted, keys.len(), 3).unwrap();
+
+        assert_eq!(par.len(), tables.len());
+
+        for (i, uid) in (1..=10000).step_by(37).enumerate() {
+            // table `(uid - 1) / 2000` holds it, in both runs
+            let tbl = (uid as usize - 1) / 2000;
+
+            assert_eq!(seq[tbl].0[i], vec![vec![UInt::U32(uid)]]);
+            assert_eq!(par[tbl].0[i], seq[tbl].0[i]);
+        }
+        for (x, y) in seq.iter().zip(par.iter()) {
+            assert_eq!(x.0, y.0);
+            assert!(x.0[keys.len() - 1].is_empty());
+
+            let (x, y) = (x.1, y.1);
+            assert_eq!((x.seeks, x.rows, x.io_bytes), (y.seeks, y.rows, y.io_bytes));
+            // every seek drops the buffer, so more comes from the file than is used
+            assert!(x.io_bytes >= x.bytes && x.io_bytes <= 8 + x.seeks * 8192);
+        }
+    }
+
+    #[test]
+    fn test_load_skips_bad_tables() {
+        let root = std::env::temp_dir().join(format!("hh_load_skip_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+
+        let write = |dir: &str, raw: &[u8]| {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+            std::fs::write(root.join(dir).join("db.bin"), raw).unwrap();
+        };
+        write("data1", &tbl_bytes(&range_rows(0, 10)));
+        // shorter than the header
+        write("data2", &[0; 5]);
+        // row count overflows the byte length
+        write("data3", &tbl_meta(1 << 62).to_le_bytes());
+        // id overflows u32
+        write("data99999999999", &tbl_bytes(&range_rows(0, 10)));
+
+        let dbmeta = load_family_in(&root, TblFamily::Normal);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(dbmeta.0.len(), 1);
+        assert!(matches!(dbmeta.0[0].0, TblTy::Normal(1)));
+        assert_eq!(dbmeta.0[0].1.len, 10);
+    }
 }
@@ -0,0 +1,325 @@
+//! Candidate Ranking
+//!
+//! Order the candidates of a hash by how plausible each uid is, the score is
+//! the sum of the weights of every signal the candidate has:
+//! uid range priors, allow/deny lists, a known-active uid set and where the
+//! candidate comes from (table family / resolver).
+//!
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+    str::FromStr,
+};
+
+use clap::ArgEnum;
+use serde_derive::Serialize;
+
+use crate::{gendata::CollisionResolver, query::Candidate, shell::TblFamily};
+
+
+/// `weight` for uids in `lo..=hi`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UidPrior {
+    pub lo: u32,
+    pub hi: u32,
+    pub weight: f64,
+}
+
+/// A signal weight overriding the default one, `name:weight` where name is
+/// `allow`, `deny`, `active`, a table family (`resolve`) or a resolver
+/// (`rehash`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Weight {
+    Allow(f64),
+    Deny(f64),
+    Active(f64),
+    Family(TblFamily, f64),
+    Resolver(CollisionResolver, f64),
+}
+
+pub struct RankConfig {
+    pub priors: Vec<UidPrior>,
+    pub allow: HashSet<u32>,
+    pub deny: HashSet<u32>,
+    pub active: HashSet<u32>,
+    pub allow_weight: f64,
+    pub deny_weight: f64,
+    pub active_weight: f64,
+    /// provenance, a resolver weight replaces the `Resolve` family one
+    pub family_weights: Vec<(TblFamily, f64)>,
+    pub resolver_weights: Vec<(CollisionResolver, f64)>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Scored {
+    #[serde(flatten)]
+    pub candidate: Candidate,
+    pub score: f64,
+    /// signals contributing to `score`, e.g. `active:+2`
+    pub signals: Vec<String>,
+}
+
+
+impl FromStr for UidPrior {
+    type Err = String;
+
+    /// `lo..=hi:weight`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("{:?} is not lo..=hi:weight", s);
+
+        let (range, weight) = s.split_once(':').ok_or_else(err)?;
+        let (lo, hi) = range.split_once("..=").ok_or_else(err)?;
+
+        let prior = Self {
+            lo: lo.trim().parse().map_err(|_| err())?,
+            hi: hi.trim().parse().map_err(|_| err())?,
+            weight: weight.trim().parse().map_err(|_| err())?,
+        };
+
+        if prior.lo > prior.hi {
+            return Err(err());
+        }
+
+        Ok(prior)
+    }
+}
+
+
+impl FromStr for Weight {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("{:?} is not name:weight", s);
+
+        let (name, weight) = s.rsplit_once(':').ok_or_else(err)?;
+        let name = name.trim().to_ascii_lowercase();
+        let weight: f64 = weight.trim().parse().map_err(|_| err())?;
+
+        let resolver = CollisionResolver::ALL
+            .into_iter()
+            .find(|x| x.to_string().to_ascii_lowercase() == name);
+
+        match name.as_str() {
+            "allow" => Ok(Self::Allow(weight)),
+            "deny" => Ok(Self::Deny(weight)),
+            "active" => Ok(Self::Active(weight)),
+            _ => {
+                if let Some(resolver) = resolver {
+                    return Ok(Self::Resolver(resolver, weight));
+                }
+
+                TblFamily::from_str(&name, true)
+                    .map(|family| Self::Family(family, weight))
+                    .map_err(|_| format!("{:?}: unknown signal {:?}", s, name))
+            }
+        }
+    }
+}
+
+
+impl Default for RankConfig {
+    /// Every table is trusted the same, a resolved candidate relies on a
+    /// guess of how the collision was resolved, a confirmed one on a human
+    fn default() -> Self {
+        Self {
+            priors: vec![],
+            allow: HashSet::new(),
+            deny: HashSet::new(),
+            active: HashSet::new(),
+            allow_weight: 10.0,
+            deny_weight: -10.0,
+            active_weight: 2.0,
+            family_weights: vec![
+                (TblFamily::Override, 10.0),
+                (TblFamily::Normal, 0.0),
+                (TblFamily::Dup, 0.0),
+                (TblFamily::Group, 0.0),
+                (TblFamily::Import, 0.0),
+                (TblFamily::Resolve, -1.0),
+            ],
+            resolver_weights: vec![],
+        }
+    }
+}
+
+impl RankConfig {
+    /// Replace the weight of a signal
+    pub fn set_weight(&mut self, weight: Weight) {
+        match weight {
+            Weight::Allow(x) => self.allow_weight = x,
+            Weight::Deny(x) => self.deny_weight = x,
+            Weight::Active(x) => self.active_weight = x,
+            Weight::Family(family, x) => {
+                self.family_weights.retain(|(y, _)| *y != family);
+                self.family_weights.push((family, x));
+            }
+            Weight::Resolver(resolver, x) => {
+                self.resolver_weights.retain(|(y, _)| *y != resolver);
+                self.resolver_weights.push((resolver, x));
+            }
+        }
+    }
+
+    fn provenance(&self, cand: &Candidate) -> f64 {
+        let resolver_weight = self.resolver_weights.iter().find(|(x, _)| {
+            cand.resolver.as_deref() == Some(x.to_string().as_str())
+        });
+
+        if let Some((_, weight)) = resolver_weight {
+            return *weight;
+        }
+
+        self.family_weights
+            .iter()
+            .find(|(family, _)| *family == cand.table)
+            .map(|(_, weight)| *weight)
+            .unwrap_or_default()
+    }
+
+    pub fn score(&self, cand: Candidate) -> Scored {
+        let mut score = 0.0;
+        let mut signals = vec![];
+        let mut add = |name: String, weight: f64| {
+            if weight != 0.0 {
+                score += weight;
+                signals.push(format!("{}:{:+}", name, weight));
+            }
+        };
+
+        add(format!("{:?}", cand.table), self.provenance(&cand));
+
+        for prior in self.priors.iter() {
+            if prior.lo <= cand.uid && cand.uid <= prior.hi {
+                add(format!("{}..={}", prior.lo, prior.hi), prior.weight);
+            }
+        }
+
+        if self.allow.contains(&cand.uid) {
+            add("allow".to_string(), self.allow_weight);
+        }
+        if self.deny.contains(&cand.uid) {
+            add("deny".to_string(), self.deny_weight);
+        }
+        if self.active.contains(&cand.uid) {
+            add("active".to_string(), self.active_weight);
+        }
+
+        Scored {
+            candidate: cand,
+            score,
+            signals,
+        }
+    }
+}
+
+
+/// Candidates of a hash, highest score first, ties kept in query order
+pub fn rank(cands: Vec<Candidate>, cfg: &RankConfig) -> Vec<Scored> {
+    let mut res: Vec<Scored> =
+        cands.into_iter().map(|cand| cfg.score(cand)).collect();
+
+    res.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    res
+}
+
+/// One uid per line, blank lines and `#` comments skipped
+pub fn load_uid_set(path: &Path) -> Result<HashSet<u32>, io::Error> {
+    let mut res = HashSet::new();
+
+    let file = File::open(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let uid = line.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {:?} is not a uid", path.display(), line),
+            )
+        })?;
+        res.insert(uid);
+    }
+
+    Ok(res)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::{rank, RankConfig, UidPrior, Weight};
+    use crate::gendata::CollisionResolver;
+    use crate::{query::Candidate, shell::TblFamily};
+
+    fn cand(uid: u32, table: TblFamily) -> Candidate {
+        Candidate {
+            hash: 0x1388875d,
+            uid,
+            table,
+            resolver: None,
+            orig: None,
+            also: vec![],
+        }
+    }
+
+    #[test]
+    fn test_rank() {
+        let prior: UidPrior = "1..=100000000:1.5".parse().unwrap();
+        assert_eq!(prior.hi, 100000000);
+        assert!("9..=1:1".parse::<UidPrior>().is_err());
+        assert!("1-9:1".parse::<UidPrior>().is_err());
+
+        let mut cfg = RankConfig {
+            priors: vec![prior],
+            ..Default::default()
+        };
+        cfg.active.insert(14740604);
+        cfg.deny.insert(3);
+
+        let ranked = rank(
+            vec![
+                cand(86825, TblFamily::Normal),
+                cand(14740604, TblFamily::Normal),
+                cand(3, TblFamily::Normal),
+                cand(7, TblFamily::Resolve),
+            ],
+            &cfg,
+        );
+        let uids: Vec<u32> = ranked.iter().map(|x| x.candidate.uid).collect();
+
+        assert_eq!(uids, vec![14740604, 86825, 7, 3]);
+        assert_eq!(ranked[0].score, 3.5);
+        assert_eq!(ranked[0].signals, vec!["1..=100000000:+1.5", "active:+2"]);
+        assert_eq!(ranked[2].score, 0.5);
+        assert_eq!(ranked[3].score, -8.5);
+
+        let weight = |s: &str| s.parse::<Weight>().unwrap();
+        assert_eq!(weight("Resolve:-3"), Weight::Family(TblFamily::Resolve, -3.0));
+        assert_eq!(weight("rehash:-2"), Weight::Resolver(CollisionResolver::Rehash, -2.0));
+        assert!("resolved:1".parse::<Weight>().is_err());
+        assert!("active".parse::<Weight>().is_err());
+
+        cfg.set_weight(weight("active:5"));
+        cfg.set_weight(weight("deny:-1"));
+        cfg.set_weight(weight("resolve:-3"));
+
+        let mut resolved = cand(7, TblFamily::Resolve);
+        resolved.resolver = Some("Rehash".to_string());
+        assert_eq!(cfg.score(resolved.clone()).score, -1.5);
+        assert_eq!(cfg.score(cand(3, TblFamily::Normal)).score, 0.5);
+
+        cfg.set_weight(weight("rehash:-2"));
+        assert_eq!(cfg.score(resolved).score, -0.5);
+        assert_eq!(cfg.score(cand(14740604, TblFamily::Normal)).score, 6.5);
+    }
+}
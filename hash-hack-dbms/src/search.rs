@@ -0,0 +1,316 @@
+//! Hash Parameter Search
+//!
+//! Explore CRC polynomial/init/xorout/reflection, re-hash depth, salts and
+//! string templates, report the parameterisations reproducing known pairs.
+//! Init and xorout are tried from their lists (all ones and zero by default),
+//! input and output reflection independently.
+//!
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fmt::Display,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use m6coll::Entry;
+
+use crate::hash::{Crc32, CrcParams, HashSpec, RehashFmt};
+
+
+pub struct SearchSpace {
+    pub polys: Vec<u32>,
+    pub inits: Vec<u32>,
+    pub xorouts: Vec<u32>,
+    pub templates: Vec<String>,
+    pub salts: Vec<String>,
+    pub max_depth: u32,
+}
+
+pub struct SearchReport {
+    pub tried: u64,
+    pub pairs: usize,
+    /// reproduce every known pair
+    pub matched: Vec<HashSpec>,
+    /// (hits, spec), most hits first
+    pub best: Vec<(usize, HashSpec)>,
+}
+
+
+impl Default for SearchSpace {
+    fn default() -> Self {
+        Self {
+            polys: CrcParams::POLYS.iter().map(|(_, poly)| *poly).collect(),
+            inits: vec![0xFFFF_FFFF, 0],
+            xorouts: vec![0xFFFF_FFFF, 0],
+            templates: vec!["{uid}".to_string()],
+            salts: vec![],
+            max_depth: 2,
+        }
+    }
+}
+
+impl SearchSpace {
+    /// poly x init x refin x refout x xorout
+    pub fn crc_params(&self) -> Vec<CrcParams> {
+        let mut res = vec![];
+
+        for poly in self.polys.iter() {
+            for init in self.inits.iter() {
+                for refin in [true, false] {
+                    for refout in [true, false] {
+                        for xorout in self.xorouts.iter() {
+                            res.push(CrcParams {
+                                poly: *poly,
+                                init: *init,
+                                refin,
+                                refout,
+                                xorout: *xorout,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        res
+    }
+
+    /// A `{salt}` template needs salts, it would add no hypothesis
+    pub fn check(&self) -> Result<(), io::Error> {
+        if self.salts.is_empty() {
+            if let Some(tpl) = self.templates.iter().find(|tpl| tpl.contains("{salt}")) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("template {:?} needs salts (--wordlist)", tpl),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn salts_of(&self, template: &str) -> Vec<String> {
+        if template.contains("{salt}") {
+            self.salts.clone()
+        } else {
+            vec![String::new()]
+        }
+    }
+
+    /// Number of hypotheses
+    pub fn size(&self) -> u64 {
+        let depths = 1 + 2 * (self.max_depth.max(1) as u64 - 1);
+        let strs: u64 = self
+            .templates
+            .iter()
+            .map(|tpl| self.salts_of(tpl).len() as u64)
+            .sum();
+
+        self.crc_params().len() as u64 * strs * depths
+    }
+}
+
+
+/// One salt per line, blank lines skipped
+pub fn load_wordlist(path: &Path) -> Result<Vec<String>, io::Error> {
+    let mut res = vec![];
+
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let word = line.trim();
+
+        if !word.is_empty() {
+            res.push(word.to_string());
+        }
+    }
+
+    Ok(res)
+}
+
+
+/// Score every hypothesis in `space` against known `(hash, uid)` pairs
+pub fn search(
+    space: &SearchSpace,
+    pairs: &[(u32, u32)],
+    top: usize,
+) -> Result<SearchReport, io::Error> {
+    space.check()?;
+
+    let max_depth = space.max_depth.max(1) as usize;
+    let mut tried = 0;
+    let mut matched = vec![];
+    let mut best = BinaryHeap::new();
+
+    let all_params = space.crc_params();
+    let pb = ProgressBar::new(all_params.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_spinner()
+            .template("search {spinner:.green} [{elapsed_precise}] {pos}/{len} crc"),
+    );
+
+    for params in all_params {
+        let crc = Crc32::new(params);
+
+        for template in space.templates.iter() {
+            for salt in space.salts_of(template) {
+                let strs: Vec<String> = pairs
+                    .iter()
+                    .map(|(_, uid)| HashSpec::render(template, &salt, *uid))
+                    .collect();
+
+                for fmt in [RehashFmt::Dec, RehashFmt::Hex] {
+                    let mut hits = vec![0; max_depth];
+
+                    for ((hash, _), s) in pairs.iter().zip(strs.iter()) {
+                        let mut hashval = crc.checksum(s.as_bytes());
+
+                        for hit in hits.iter_mut() {
+                            if hashval == *hash {
+                                *hit += 1;
+                            }
+                            hashval = crc.checksum(fmt.render(hashval).as_bytes());
+                        }
+                    }
+
+                    for (i, hit) in hits.into_iter().enumerate() {
+                        // depth 1 never re-hashes, count it once
+                        if i == 0 && fmt == RehashFmt::Hex {
+                            continue;
+                        }
+                        tried += 1;
+
+                        if hit == 0 {
+                            continue;
+                        }
+
+                        let spec = HashSpec {
+                            params,
+                            template: template.clone(),
+                            salt: salt.clone(),
+                            depth: i as u32 + 1,
+                            fmt,
+                        };
+
+                        if hit == pairs.len() {
+                            matched.push(spec.clone());
+                        }
+
+                        best.push(Reverse(Entry(hit, spec)));
+                        if best.len() > top {
+                            best.pop();
+                        }
+                    }
+                }
+            }
+        }
+
+        pb.inc(1);
+    }
+    pb.finish_and_clear();
+
+    let mut best: Vec<(usize, HashSpec)> = best
+        .into_iter()
+        .map(|Reverse(Entry(hit, spec))| (hit, spec))
+        .collect();
+    best.sort_by_key(|(hit, _)| Reverse(*hit));
+
+    Ok(SearchReport {
+        tried,
+        pairs: pairs.len(),
+        matched,
+        best,
+    })
+}
+
+
+impl Display for SearchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "tried {} hypotheses against {} known pairs",
+            self.tried, self.pairs
+        )?;
+
+        writeln!(f, "reproduce all pairs: {}", self.matched.len())?;
+        for spec in self.matched.iter() {
+            writeln!(f, "  {}", spec)?;
+        }
+
+        writeln!(f, "best partial:")?;
+        for (hit, spec) in self.best.iter() {
+            writeln!(f, "  {}/{} {}", hit, self.pairs, spec)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::{search, SearchSpace};
+    use crate::hash::{hash_bili2, rehash_bili2, CrcParams, HashSpec};
+
+    #[test]
+    fn test_search() {
+        let pairs = vec![(0x55f5defa, 6487381), (hash_bili2(12), 12)];
+        let space = SearchSpace::default();
+        let report = search(&space, &pairs, 5).unwrap();
+
+        assert_eq!(report.tried, space.size());
+        assert_eq!(report.matched.len(), 1);
+        assert_eq!(report.matched[0].params, CrcParams::CRC32);
+        assert_eq!(report.matched[0].depth, 1);
+        assert_eq!(report.best[0].0, 2);
+
+        // refout apart from refin, an init from the list
+        let params = CrcParams {
+            refout: false,
+            init: 0x1234_5678,
+            ..CrcParams::CRC32
+        };
+        let spec = HashSpec { params, ..HashSpec::bili2() };
+        let pairs = vec![(spec.hash(7), 7), (spec.hash(8), 8)];
+        let space = SearchSpace {
+            inits: vec![0x1234_5678],
+            ..Default::default()
+        };
+        let report = search(&space, &pairs, 5).unwrap();
+
+        assert_eq!(report.matched.len(), 1);
+        assert_eq!(report.matched[0].params, params);
+    }
+
+    #[test]
+    fn test_search_salt_depth() {
+        let pairs = vec![(rehash_bili2(hash_bili2(6487381)), 6487381)];
+        let space = SearchSpace {
+            templates: vec!["{uid}".to_string(), "{salt}{uid}".to_string()],
+            salts: vec!["bili".to_string()],
+            ..Default::default()
+        };
+        let report = search(&space, &pairs, 5).unwrap();
+        let spec = &report.matched[0];
+
+        assert_eq!(report.matched.len(), 1);
+        assert_eq!(spec.depth, 2);
+        assert_eq!(spec.hash(6487381), pairs[0].0);
+
+        let mut salted = HashSpec::bili2();
+        salted.template = "{salt}{uid}".to_string();
+        salted.salt = "bili".to_string();
+        let pairs = vec![(salted.hash(7), 7), (salted.hash(8), 8)];
+        let report = search(&space, &pairs, 5).unwrap();
+
+        assert_eq!(report.matched.len(), 1);
+        assert_eq!(report.matched[0].salt, "bili");
+
+        let unsalted = SearchSpace { salts: vec![], ..space };
+        assert!(search(&unsalted, &pairs, 5).is_err());
+    }
+}
@@ -1,11 +1,12 @@
 use std::{
-    fs::File,
+    fs::{create_dir_all, File},
     io::{self, BufWriter},
     path::{Path, PathBuf},
     process::{ExitStatus, Stdio},
 };
 
-use clap::Command;
+use clap::{ArgEnum, Command};
+use serde_derive::Serialize;
 use clap_complete::{generate, Shell};
 use shellexpand::tilde;
 
@@ -13,7 +14,7 @@ use crate::gendata::CollisionResolver;
 
 
 /// Shell Tool
-pub fn runit<'a>(args: &str) -> Result<ExitStatus, io::Error> {
+pub fn runit(args: &str) -> Result<ExitStatus, io::Error> {
     std::process::Command::new("sh")
         .arg("-c")
         .arg(args)
@@ -52,8 +53,11 @@ macro_rules! path {
 }
 
 
-pub const BUNDLE_NAME: &'static str = "db.bin";
-pub const BUNDLE_DUP_NAME: &'static str = "db_dup.bin";
+pub const BUNDLE_NAME: &str = "db.bin";
+pub const BUNDLE_DUP_NAME: &str = "db_dup.bin";
+
+/// Root of the tables of the working dir, paths under it stay relative
+pub(crate) const CWD: &str = "";
 
 
 #[inline]
@@ -76,20 +80,42 @@ pub fn tbl_dup_path(id: u32) -> PathBuf {
 }
 
 
+#[inline]
+pub fn tbl_group_dir() -> PathBuf {
+    path!("data_group")
+}
+#[inline]
+pub fn tbl_group_path(id: u32) -> PathBuf {
+    tbl_group_dir().join(path!("db_group_{}.bin", id))
+}
+
+
+#[inline]
+pub fn tbl_import_dir() -> PathBuf {
+    path!("data_import")
+}
+#[inline]
+pub fn tbl_import_path(id: u32) -> PathBuf {
+    tbl_import_dir().join(path!("db_import_{}.bin", id))
+}
+
+
+#[inline]
+pub fn tbl_override_dir() -> PathBuf {
+    path!("data_override")
+}
+#[inline]
+pub fn tbl_override_path() -> PathBuf {
+    tbl_override_dir().join("overrides.tsv")
+}
+
+
 #[inline]
 pub fn path2str(p: &Path) -> String {
     p.as_os_str().to_string_lossy().to_string()
 }
 
 impl CollisionResolver {
-    fn init(&self) {
-        match self {
-            CollisionResolver::Rehash => {
-                run!("mkdir -p {}", "data_cr_rehash").unwrap();
-            }
-        }
-    }
-
     fn pathbuf(&self, id: u32) -> PathBuf {
         match self {
             CollisionResolver::Rehash => path!("data_cr_rehash")
@@ -100,24 +126,37 @@ impl CollisionResolver {
 
 
 
+/// Kind of table, as shown to users
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ArgEnum)]
+pub enum TblFamily {
+    Normal,
+    Dup,
+    Resolve,
+    Group,
+    /// pairs imported from other sources (`hhgd import`)
+    Import,
+    /// curated pairs (`overrides`)
+    Override,
+}
+
 #[derive(Clone, Copy)]
 pub(crate) enum TblTy {
     Normal(u32),
     Dup(u32),
     Resolve(u32, CollisionResolver),
+    Group(u32),
+    Import(u32),
 }
 
 impl TblTy {
-    pub fn init(&self) {
-        match self {
-            Self::Normal(id) => {
-                run!("mkdir -p {}", path2str(&tbl_dir(*id))).unwrap();
-            }
-            Self::Dup(_) => {
-                run!("mkdir -p {}", path2str(&tbl_dup_dir())).unwrap();
-            }
-            Self::Resolve(_id, resolv) => resolv.init(),
-        }
+    /// Create the dir of the table under `root`
+    pub(crate) fn init_in(&self, root: &Path) -> Result<(), io::Error> {
+        create_dir_all(self.path_in(root).parent().unwrap())
+    }
+
+    /// Path of the table under `root` (`CWD` for the working dir)
+    pub(crate) fn path_in(&self, root: &Path) -> PathBuf {
+        root.join(self.pathbuf())
     }
 
     pub fn pathbuf(&self) -> PathBuf {
@@ -125,6 +164,50 @@ impl TblTy {
             Self::Normal(id) => tbl_path(*id),
             Self::Dup(id) => tbl_dup_path(*id),
             Self::Resolve(id, resolv) => resolv.pathbuf(*id),
+            Self::Group(id) => tbl_group_path(*id),
+            Self::Import(id) => tbl_import_path(*id),
+        }
+    }
+
+    pub fn family(&self) -> TblFamily {
+        match self {
+            Self::Normal(_) => TblFamily::Normal,
+            Self::Dup(_) => TblFamily::Dup,
+            Self::Resolve(..) => TblFamily::Resolve,
+            Self::Group(_) => TblFamily::Group,
+            Self::Import(_) => TblFamily::Import,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            Self::Normal(id)
+            | Self::Dup(id)
+            | Self::Resolve(id, _)
+            | Self::Group(id)
+            | Self::Import(id) => *id,
+        }
+    }
+
+    /// Table of a catalog path (`data0/db.bin`, `data_dup/db_dup_0.bin` ..)
+    pub fn of_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let dir = path.parent()?.file_name()?.to_str()?;
+
+        let id_of = |prefix: &str| -> Option<u32> {
+            name.strip_prefix(prefix)?.strip_suffix(".bin")?.parse().ok()
+        };
+
+        match dir {
+            "data_dup" => id_of("db_dup_").map(Self::Dup),
+            "data_group" => id_of("db_group_").map(Self::Group),
+            "data_import" => id_of("db_import_").map(Self::Import),
+            "data_cr_rehash" => id_of("db_cr_rehash_")
+                .map(|id| Self::Resolve(id, CollisionResolver::Rehash)),
+            _ if name == BUNDLE_NAME => {
+                dir.strip_prefix("data")?.parse().ok().map(Self::Normal)
+            }
+            _ => None,
         }
     }
 
@@ -135,6 +218,8 @@ impl TblTy {
             Self::Resolve(id, resolv) => {
                 Self::Resolve(*id + 1, *resolv)
             }
+            Self::Group(id) => Self::Group(*id + 1),
+            Self::Import(id) => Self::Import(*id + 1),
         }
     }
 }
@@ -164,11 +249,26 @@ pub fn gen_completions(gen: Shell, cmd: &mut Command) {
 
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
+
+    use super::{TblFamily, TblTy};
 
     #[test]
     fn test_dec_macro() {
-        use crate::run;
-
         run!("echo {} {} {}", 1, 2, 3).unwrap();
     }
+
+    #[test]
+    fn test_of_path() {
+        let of = |s: &str| {
+            TblTy::of_path(Path::new(s)).map(|ty| (ty.family(), ty.id()))
+        };
+
+        assert_eq!(of("data12/db.bin"), Some((TblFamily::Normal, 12)));
+        assert_eq!(of("/db/data_dup/db_dup_3.bin"), Some((TblFamily::Dup, 3)));
+        assert_eq!(of("./data_cr_rehash/db_cr_rehash_0.bin"), Some((TblFamily::Resolve, 0)));
+        assert_eq!(of("data_group/db_group_x.bin"), None);
+        assert_eq!(of("tmp/db.bin"), None);
+        assert_eq!(of("db.bin"), None);
+    }
 }
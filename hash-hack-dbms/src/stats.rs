@@ -0,0 +1,230 @@
+//! Collision Statistics
+//!
+//! Streaming over the sorted normal tables (k-way merge), count preimages
+//! per hash.
+//!
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap},
+    fmt::Display,
+    io,
+    path::Path,
+};
+
+use m6coll::Entry;
+use serde_derive::Serialize;
+
+use crate::{
+    catalog::Catalog,
+    data::DBMeta2,
+    gendata::MergeReader,
+    shell::path2str,
+};
+
+
+#[derive(Serialize)]
+pub struct TblStats {
+    pub path: String,
+    pub items: u64,
+    /// items whose hash has more than one preimage
+    pub collided: u64,
+}
+
+#[derive(Serialize)]
+pub struct HashStats {
+    pub hash: u32,
+    pub uids: Vec<u32>,
+}
+
+#[derive(Serialize)]
+pub struct DBStats {
+    pub total_items: u64,
+    pub distinct_hashes: u64,
+    /// items beyond the first preimage of each hash
+    pub collisions: u64,
+    /// hashes with 0, 1, 2 and 3+ preimages
+    pub preimages: [u64; 4],
+    /// preimages per hash -> hashes
+    pub histogram: BTreeMap<usize, u64>,
+    pub tables: Vec<TblStats>,
+    /// most collided hashes
+    pub top: Vec<HashStats>,
+}
+
+
+impl DBStats {
+    /// Tables of `dbmeta` under `root`
+    pub(crate) fn collect(root: &Path, dbmeta: &DBMeta2, top: usize) -> Result<Self, io::Error> {
+        let mut tables: Vec<TblStats> = dbmeta
+            .0
+            .iter()
+            .map(|(ty, meta)| TblStats {
+                path: path2str(&ty.pathbuf()),
+                items: meta.len,
+                collided: 0,
+            })
+            .collect();
+
+        let mut histogram = BTreeMap::new();
+        let mut heap = BinaryHeap::new();
+
+        let mut merger = MergeReader::open(root, dbmeta)?;
+
+        while let Some((key, run)) = merger.read_run()? {
+            *histogram.entry(run.len()).or_insert(0) += 1;
+
+            if run.len() < 2 {
+                continue;
+            }
+
+            for (_, i) in run.iter() {
+                tables[*i].collided += 1;
+            }
+
+            if heap.len() < top
+                || heap
+                    .peek()
+                    .is_some_and(|Reverse(Entry(n, _))| *n < run.len())
+            {
+                let uids = run.iter().map(|(val, _)| val.into_u32()).collect();
+
+                heap.push(Reverse(Entry(run.len(), (key.into_u32(), uids))));
+                if heap.len() > top {
+                    heap.pop();
+                }
+            }
+        }
+
+        let distinct_hashes = histogram.values().sum();
+        let total_items = dbmeta.total_items();
+
+        let mut preimages = [(u32::MAX as u64 + 1) - distinct_hashes, 0, 0, 0];
+        for (n, cnt) in histogram.iter() {
+            preimages[(*n).min(3)] += cnt;
+        }
+
+        let mut top: Vec<(usize, HashStats)> = heap
+            .into_iter()
+            .map(|Reverse(Entry(n, (hash, uids)))| (n, HashStats { hash, uids }))
+            .collect();
+        top.sort_by_key(|(n, stats)| (Reverse(*n), stats.hash));
+
+        Ok(Self {
+            total_items,
+            distinct_hashes,
+            collisions: total_items - distinct_hashes,
+            preimages,
+            histogram,
+            tables,
+            top: top.into_iter().map(|(_, stats)| stats).collect(),
+        })
+    }
+}
+
+
+impl Display for DBStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} items, {} distinct hashes, {} collisions",
+            self.total_items, self.distinct_hashes, self.collisions
+        )?;
+
+        writeln!(f, "hashes by preimages:")?;
+        for (i, cnt) in self.preimages.iter().enumerate() {
+            let label = if i == 3 { "3+".to_string() } else { i.to_string() };
+            writeln!(f, "{:>4}: {}", label, cnt)?;
+        }
+
+        writeln!(f, "histogram:")?;
+        for (n, cnt) in self.histogram.iter() {
+            writeln!(f, "{:>4}: {}", n, cnt)?;
+        }
+
+        writeln!(f, "tables:")?;
+        for tbl in self.tables.iter() {
+            writeln!(
+                f,
+                "{}: {} items, {} collided",
+                tbl.path, tbl.items, tbl.collided
+            )?;
+        }
+
+        writeln!(f, "most collided:")?;
+        for stats in self.top.iter() {
+            writeln!(f, "{:08x}: {:?}", stats.hash, stats.uids)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// `DBStats::collect` over the normal tables of `catalog`
+pub fn stats_bili2(catalog: &Catalog, top: usize) -> Result<DBStats, io::Error> {
+    DBStats::collect(&catalog.root, &catalog.normal, top)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, env::temp_dir, fs::remove_dir_all};
+
+    use super::DBStats;
+    use crate::{
+        data::UInt,
+        gendata::{DBWriter, BILI2_HASHUID_TBL_META},
+        query::load_family_in,
+        shell::{TblFamily, TblTy},
+    };
+
+    #[test]
+    fn test_collect() {
+        let root = temp_dir().join(format!("hh_stats_{}", std::process::id()));
+        let tbls: [&[(u32, u32)]; 2] = [
+            &[(5, 1), (7, 2), (9, 3)],
+            &[(5, 104), (6, 105), (9, 101), (9, 107)],
+        ];
+
+        let mut db = DBWriter::init_in(&root, BILI2_HASHUID_TBL_META, TblTy::Normal(0));
+        for rows in tbls {
+            let mut w = db.nxt_tbl_writer().unwrap();
+            for (key, uid) in rows {
+                w.write_row(UInt::U32(*key), &[UInt::U32(*uid)]).unwrap();
+            }
+            w.finish().unwrap();
+        }
+
+        let dbmeta = load_family_in(&root, TblFamily::Normal);
+        let stats = DBStats::collect(&root, &dbmeta, 1).unwrap();
+
+        assert_eq!(
+            (stats.total_items, stats.distinct_hashes, stats.collisions),
+            (7, 4, 3)
+        );
+        assert_eq!(stats.histogram, BTreeMap::from([(1, 2), (2, 1), (3, 1)]));
+        assert_eq!(stats.preimages, [(1 << 32) - 4, 2, 1, 1]);
+
+        let tables: Vec<(&str, u64, u64)> = stats
+            .tables
+            .iter()
+            .map(|tbl| (tbl.path.as_str(), tbl.items, tbl.collided))
+            .collect();
+        assert_eq!(tables, vec![("data0/db.bin", 3, 2), ("data1/db.bin", 4, 3)]);
+
+        let top = |n| {
+            DBStats::collect(&root, &dbmeta, n)
+                .unwrap()
+                .top
+                .into_iter()
+                .map(|h| (h.hash, h.uids))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(top(1), vec![(9, vec![3, 101, 107])]);
+        assert_eq!(top(3), vec![(9, vec![3, 101, 107]), (5, vec![1, 104])]);
+        assert!(top(0).is_empty());
+
+        remove_dir_all(root).unwrap();
+    }
+}
@@ -8,7 +8,11 @@ use tracing_subscriber::{
     fmt::format::FmtSpan
 };
 
-use hash_hack_dbms::query::query_bili2;
+use hash_hack_dbms::{
+    catalog::QueryPlan,
+    hash::parse_hash,
+    query::{lookup_bili2, query_bili2},
+};
 
 use clap:: Parser;
 
@@ -77,8 +81,42 @@ async fn main() {
             })
     );
 
+    /* Bili2 candidates of every table family, `not_found` says which uid
+     * ranges were searched when there are none */
+    let bili2_lookup
+    = warp::path("bili2")
+        .and(
+            warp::path!("lookup" / String)
+            .and(warp::get())
+            .map(|raw: String| {
+                let id = match parse_hash(&raw) {
+                    Some(id) => id,
+                    None => {
+                        return warp::reply::json(&ErrorMessage {
+                            code: StatusCode::BAD_REQUEST.as_u16(),
+                            message: "hex u32 required!".to_string()
+                        })
+                    },
+                };
+
+                match lookup_bili2(id, &QueryPlan::default()) {
+                    Ok(res) => {
+                        warp::reply::json(&res)
+                    },
+                    Err(_) => {
+                        tracing::error!("[Lookup] Read DB Failed");
+
+                        warp::reply::json(&ErrorMessage {
+                            code: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                            message: "read db failed".to_string()
+                        })
+                    }
+                }
+            })
+    );
+
     let routers
-    = bili2;
+    = bili2.or(bili2_lookup);
 
     let addr = SocketAddr::from(([127, 0, 0, 1], port));
 